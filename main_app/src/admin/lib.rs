@@ -0,0 +1,120 @@
+//管理员对用户的操作，删用户要把文件、回收站、历史版本占的blob都放掉
+use crate::auth::lib::revoke_sessions;
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::{File, FileType, TrashItem, User, UserRole};
+use crate::file::share::lib::revoke_user_links;
+use crate::file::storage_backend::blob_storage::{self, BLOB_STORAGE_TYPE};
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::file::version::lib::version_collection;
+use crate::libs::ApiError;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use rocket::futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+fn db_error<E>(_: E) -> ApiError {
+    ApiError::InternalServerError("Database error".to_string().into())
+}
+
+//返回给管理员看的，不带密码
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub _id: ObjectId,
+    pub username: String,
+    pub nickname: String,
+    pub root_id: ObjectId,
+    pub role: UserRole,
+    pub disabled: bool,
+    pub provider: Option<String>,
+    pub quota: Option<u64>,
+    pub used_bytes: i64,
+}
+
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            _id: user._id,
+            username: user.username,
+            nickname: user.nickname,
+            root_id: user.root_id,
+            role: user.role,
+            disabled: user.disabled,
+            provider: user.provider,
+            quota: user.quota,
+            used_bytes: user.used_bytes,
+        }
+    }
+}
+
+pub fn users(mongo: &MongoDb) -> mongodb::Collection<User> {
+    mongo.database.collection::<User>("users")
+}
+
+pub async fn get_user(id: &ObjectId, mongo: &MongoDb) -> Result<User, ApiError> {
+    match users(mongo).find_one(doc! { "_id": id }).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(ApiError::NotFound("User not found".to_string().into())),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+async fn release_files(collection: &str, owner: &ObjectId, mongo: &MongoDb, factory: &StorageFactory) -> Result<(), ApiError> {
+    let collection = mongo.database.collection::<File>(collection);
+    let files: Vec<File> = collection
+        .find(doc! { "owner": owner, "type": "File" })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    for file in files {
+        if file.type_ == FileType::File && file.storage_type == BLOB_STORAGE_TYPE {
+            blob_storage::release(mongo, factory, &file.sha256).await?;
+        }
+    }
+    collection.delete_many(doc! { "owner": owner }).await.map_err(db_error)?;
+    Ok(())
+}
+
+pub async fn delete_user(
+    user: &User,
+    system_root: &ObjectId,
+    mongo: &MongoDb,
+    redis: &Redis,
+    factory: &StorageFactory,
+) -> Result<(), ApiError> {
+    revoke_sessions(&user._id, None, mongo, redis).await.map_err(db_error)?;
+    revoke_user_links(&user._id, mongo, redis).await?;
+    release_files("files", &user._id, mongo, factory).await?;
+    release_files("trashed_files", &user._id, mongo, factory).await?;
+    let versions = version_collection(mongo)
+        .find(doc! { "owner": user._id })
+        .await
+        .map_err(db_error)?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(db_error)?;
+    for version in versions {
+        if version.storage_type == BLOB_STORAGE_TYPE {
+            blob_storage::release(mongo, factory, &version.sha256).await?;
+        }
+    }
+    version_collection(mongo)
+        .delete_many(doc! { "owner": user._id })
+        .await
+        .map_err(db_error)?;
+    mongo
+        .database
+        .collection::<TrashItem>("trash")
+        .delete_many(doc! { "owner": user._id })
+        .await
+        .map_err(db_error)?;
+    mongo
+        .database
+        .collection::<File>("files")
+        .update_one(doc! { "_id": system_root }, doc! { "$pull": { "children": user.root_id } })
+        .await
+        .map_err(db_error)?;
+    users(mongo).delete_one(doc! { "_id": user._id }).await.map_err(db_error)?;
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use super::lib::{delete_user, get_user, users, UserInfo};
+use crate::auth::guard::AdminUser;
+use crate::auth::lib::{create_user, hash_password, revoke_sessions};
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::UserRole;
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::libs::{parse_object_id, ApiError};
+use crate::MyConfig;
+use mongodb::bson::doc;
+use rocket::futures::TryStreamExt;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUserRequest {
+    pub username: String,
+    pub password: String,
+    pub nickname: String,
+    #[serde(default)]
+    pub role: UserRole,
+    #[serde(default)]
+    pub quota: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisabledRequest {
+    pub disabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QuotaRequest {
+    pub quota: Option<u64>, //null表示用配置里的默认值
+}
+
+fn db_error<E>(_: E) -> ApiError {
+    ApiError::InternalServerError("Database error".to_string().into())
+}
+
+#[get("/users")]
+pub async fn list_users(
+    _admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<Vec<UserInfo>>, ApiError> {
+    let list: Vec<_> = users(mongo)
+        .find(doc! {})
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    Ok(Json(list.into_iter().map(UserInfo::from).collect()))
+}
+
+#[post("/users", data = "<request>")]
+pub async fn add_user(
+    request: Json<CreateUserRequest>,
+    _admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<UserInfo>, ApiError> {
+    let request = request.into_inner();
+    if request.username.is_empty() || request.password.is_empty() {
+        return Err(ApiError::BadRequest("Username and password are required".to_string().into()));
+    }
+    if users(mongo).find_one(doc! { "username": &request.username }).await.map_err(db_error)?.is_some() {
+        return Err(ApiError::BadRequest("Username already exists".to_string().into()));
+    }
+    let mut user = create_user(
+        &request.username,
+        &request.password,
+        &request.nickname,
+        request.role,
+        mongo,
+        &config.system_root_id,
+    )
+    .await
+    .map_err(db_error)?;
+    if request.quota.is_some() {
+        users(mongo)
+            .update_one(doc! { "_id": user._id }, doc! { "$set": { "quota": request.quota.map(|q| q as i64) } })
+            .await
+            .map_err(db_error)?;
+        user.quota = request.quota;
+    }
+    Ok(Json(user.into()))
+}
+
+//停用的同时踢掉所有登录
+#[put("/users/<id>/disabled", data = "<request>")]
+pub async fn set_disabled(
+    id: &str,
+    request: Json<DisabledRequest>,
+    admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<status::NoContent, ApiError> {
+    let user = get_user(&parse_object_id(id)?, mongo).await?;
+    if user._id == admin.0.uuid {
+        return Err(ApiError::BadRequest("Cannot disable yourself".to_string().into()));
+    }
+    users(mongo)
+        .update_one(doc! { "_id": user._id }, doc! { "$set": { "disabled": request.disabled } })
+        .await
+        .map_err(db_error)?;
+    if request.disabled {
+        revoke_sessions(&user._id, None, mongo, redis).await.map_err(db_error)?;
+    }
+    Ok(status::NoContent)
+}
+
+#[post("/users/<id>/password", data = "<request>")]
+pub async fn reset_password(
+    id: &str,
+    request: Json<PasswordRequest>,
+    _admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<status::NoContent, ApiError> {
+    if request.password.is_empty() {
+        return Err(ApiError::BadRequest("Password is required".to_string().into()));
+    }
+    let user = get_user(&parse_object_id(id)?, mongo).await?;
+    if user.provider.is_some() {
+        return Err(ApiError::BadRequest("Password is managed by an external provider".to_string().into()));
+    }
+    users(mongo)
+        .update_one(doc! { "_id": user._id }, doc! { "$set": { "password": hash_password(&request.password) } })
+        .await
+        .map_err(db_error)?;
+    revoke_sessions(&user._id, None, mongo, redis).await.map_err(db_error)?;
+    Ok(status::NoContent)
+}
+
+#[delete("/users/<id>/sessions")]
+pub async fn force_logout(
+    id: &str,
+    _admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<status::NoContent, ApiError> {
+    let user = get_user(&parse_object_id(id)?, mongo).await?;
+    revoke_sessions(&user._id, None, mongo, redis).await.map_err(db_error)?;
+    Ok(status::NoContent)
+}
+
+//用户手机丢了、恢复码也没了的时候用
+#[delete("/users/<id>/totp")]
+pub async fn reset_totp(
+    id: &str,
+    _admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<status::NoContent, ApiError> {
+    let user = get_user(&parse_object_id(id)?, mongo).await?;
+    users(mongo)
+        .update_one(doc! { "_id": user._id }, doc! { "$unset": { "totp": "" } })
+        .await
+        .map_err(db_error)?;
+    Ok(status::NoContent)
+}
+
+#[put("/users/<id>/quota", data = "<request>")]
+pub async fn set_quota(
+    id: &str,
+    request: Json<QuotaRequest>,
+    _admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<status::NoContent, ApiError> {
+    let user = get_user(&parse_object_id(id)?, mongo).await?;
+    users(mongo)
+        .update_one(doc! { "_id": user._id }, doc! { "$set": { "quota": request.quota.map(|q| q as i64) } })
+        .await
+        .map_err(db_error)?;
+    Ok(status::NoContent)
+}
+
+#[delete("/users/<id>")]
+pub async fn remove_user(
+    id: &str,
+    admin: AdminUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<status::NoContent, ApiError> {
+    let user = get_user(&parse_object_id(id)?, mongo).await?;
+    if user._id == admin.0.uuid {
+        return Err(ApiError::BadRequest("Cannot delete yourself".to_string().into()));
+    }
+    let factory = storage_factory.lock().await.clone();
+    delete_user(&user, &config.system_root_id, mongo, redis, &factory).await?;
+    Ok(status::NoContent)
+}
@@ -1,3 +1,7 @@
 pub mod guard;
 pub mod routes;
-pub mod lib;
\ No newline at end of file
+pub mod ldap;
+pub mod lib;
+pub mod provider;
+pub mod oidc;
+pub mod totp;
\ No newline at end of file
@@ -1,21 +1,56 @@
-use std::str::FromStr;
-
-use super::lib::authenticate_jwt;
+use super::lib::{authenticate_token, key_allows};
 use crate::db::connect::{MongoDb, Redis};
-use crate::db::models::User;
+use crate::db::models::{AccessScope, LoginedDevice, User, UserRole};
+use crate::rate_limit::guard::RetryAfter;
+use crate::rate_limit::lib::check_api_rate;
 use crate::MyConfig;
-use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Outcome};
 use rocket::Request;
 
+#[derive(Clone)]
 pub struct AuthenticatedUser {
     pub uuid: ObjectId,
     pub username: String,
     pub nickname: String,
     pub token: Option<String>,
-    pub root_id: ObjectId,
+    pub root_id: ObjectId, //限定了文件夹的access key这里是那个文件夹
+    pub role: UserRole,
+    pub scopes: Option<Vec<AccessScope>>, //None是不限，只有access key会有
+    pub folder: Option<ObjectId>,
+}
+
+impl AuthenticatedUser {
+    pub fn from_user(user: User, token: Option<String>) -> Self {
+        Self {
+            uuid: user._id,
+            username: user.username,
+            nickname: user.nickname,
+            token,
+            root_id: user.root_id,
+            role: user.role,
+            scopes: None,
+            folder: None,
+        }
+    }
+
+    //套上access key的限制
+    pub fn restrict(self, device: &LoginedDevice) -> Self {
+        Self {
+            root_id: device.folder.unwrap_or(self.root_id),
+            scopes: device.scopes.clone(),
+            folder: device.folder,
+            ..self
+        }
+    }
+
+    pub fn has_scope(&self, scope: AccessScope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => true,
+        }
+    }
 }
 
 #[rocket::async_trait]
@@ -34,30 +69,34 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
             return Outcome::Error((Status::Unauthorized, ()));
         }
         let token = &auth_header[7..];
-        //先匹配access_key
-        if redis.exists(token).await {
-            let db = mongo.database.collection::<User>("users");
-            let user = db
-                .find_one(doc! { "_id": ObjectId::from_str(redis.get::<String, String>(token.to_string()).await.as_str()).unwrap() })
-                .await;
-            match user {
-                Ok(Some(user)) => {
-                        return Outcome::Success(AuthenticatedUser {
-                            uuid: user._id,
-                            username: user.username,
-                            nickname: user.nickname,
-                            token: Some(token.to_owned()),
-                            root_id: user.root_id,
-                        })
-                    }
-                Ok(None) => return Outcome::Error((Status::Unauthorized, ())),
-                _ => return Outcome::Error((Status::Unauthorized, ())),
-            }
+        let user = match authenticate_token(token, &config.jwt_secret, mongo, redis).await {
+            Ok(user) if key_allows(&user, request.method().as_str(), request.uri().path().as_str()) => user,
+            Ok(_) => return Outcome::Error((Status::Forbidden, ())),
+            Err(_) => return Outcome::Error((Status::Unauthorized, ())),
+        };
+        //jwt按jti算，access key按key算
+        let rate_key = user.token.clone().unwrap_or_else(|| user.uuid.to_hex());
+        if let Err(retry_after) = check_api_rate(redis, config, &rate_key).await {
+            request.local_cache(|| RetryAfter(retry_after));
+            return Outcome::Error((Status::TooManyRequests, ()));
         }
+        Outcome::Success(user)
+    }
+}
 
-        match authenticate_jwt(token, &config.jwt_secret, mongo, redis).await {
-            Ok(user) => return Outcome::Success(user),
-            Err(_) => return Outcome::Error((Status::Unauthorized, ())),
+//管理员接口用，不是管理员直接403
+pub struct AdminUser(pub AuthenticatedUser);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match AuthenticatedUser::from_request(request).await {
+            Outcome::Success(user) if user.role == UserRole::Admin => Outcome::Success(AdminUser(user)),
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, ())),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(f) => Outcome::Forward(f),
         }
     }
 }
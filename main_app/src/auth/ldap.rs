@@ -0,0 +1,429 @@
+//LDAP登录：先用服务账号按用户名搜出DN，再用用户的密码bind
+use super::lib::create_user;
+use super::provider::{AuthProvider, AuthResult};
+use crate::db::connect::MongoDb;
+use crate::db::models::{User, UserRole};
+use crate::MyConfig;
+use async_trait::async_trait;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, SearchOptions};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use std::error::Error;
+use std::time::Duration;
+
+pub const PROVIDER_NAME: &str = "ldap";
+
+type LdapResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+//连接和每个请求的超时，服务器不响应时不至于把登录请求一直挂着
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+//按用户名只该搜出一条，多于这个数就不用再收了
+const SEARCH_SIZE_LIMIT: usize = 2;
+
+fn first_attr<'a>(entry: &'a SearchEntry, name: &str) -> Option<&'a str> {
+    entry
+        .attrs
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(|value| value.as_str())
+}
+
+pub struct LdapProvider {
+    url: String,
+    bind_dn: String,
+    bind_password: String,
+    base_dn: String,
+    user_attr: String,
+    admin_group: String,
+    group_member_attr: String,
+    system_root_id: ObjectId,
+    timeout: Duration,
+}
+
+impl LdapProvider {
+    pub fn new(config: &MyConfig) -> Self {
+        Self {
+            url: config.ldap_url.clone(),
+            bind_dn: config.ldap_bind_dn.clone(),
+            bind_password: config.ldap_bind_password.clone(),
+            base_dn: config.ldap_base_dn.clone(),
+            user_attr: config.ldap_user_attr.clone(),
+            admin_group: config.ldap_admin_group.clone(),
+            group_member_attr: config.ldap_group_member_attr.clone(),
+            system_root_id: config.system_root_id,
+            timeout: IO_TIMEOUT,
+        }
+    }
+
+    async fn connect(&self) -> LdapResult<Ldap> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (connection, ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(connection);
+        Ok(ldap)
+    }
+
+    //密码不对(invalidCredentials)返回false，其他错误码直接报错
+    async fn bind(&self, ldap: &mut Ldap, dn: &str, password: &str) -> LdapResult<bool> {
+        match ldap.with_timeout(self.timeout).simple_bind(dn, password).await?.rc {
+            0 => Ok(true),
+            49 => Ok(false),
+            rc => Err(format!("LDAP: bind failed with result code {}", rc).into()),
+        }
+    }
+
+    async fn service_bind(&self, ldap: &mut Ldap) -> LdapResult<()> {
+        if !self.bind(ldap, &self.bind_dn, &self.bind_password).await? {
+            return Err("LDAP: service account bind failed".into());
+        }
+        Ok(())
+    }
+
+    //结果多于SEARCH_SIZE_LIMIT条的当成没搜到，服务器不理sizeLimit也不会一直收下去
+    async fn search(
+        &self,
+        ldap: &mut Ldap,
+        base: &str,
+        scope: Scope,
+        attribute: &str,
+        value: &str,
+        attributes: &[&str],
+    ) -> LdapResult<Vec<SearchEntry>> {
+        let filter = format!("({}={})", attribute, ldap_escape(value));
+        let mut stream = ldap
+            .with_timeout(self.timeout)
+            .with_search_options(SearchOptions::new().sizelimit(SEARCH_SIZE_LIMIT as i32))
+            .streaming_search(base, scope, &filter, attributes.to_vec())
+            .await?;
+        let mut entries = Vec::new();
+        while let Some(entry) = stream.next().await? {
+            entries.push(SearchEntry::construct(entry));
+            if entries.len() > SEARCH_SIZE_LIMIT {
+                let id = stream.ldap_handle().last_id();
+                let _ = stream.ldap_handle().abandon(id).await;
+                return Ok(Vec::new());
+            }
+        }
+        match stream.finish().await.rc {
+            0 => Ok(entries),
+            //sizeLimitExceeded / noSuchObject
+            4 | 32 => Ok(Vec::new()),
+            rc => Err(format!("LDAP: search failed with result code {}", rc).into()),
+        }
+    }
+
+    //group是groupOfNames这种，member里存的是用户DN
+    async fn is_admin(&self, ldap: &mut Ldap, dn: &str) -> LdapResult<bool> {
+        if self.admin_group.is_empty() {
+            return Ok(false);
+        }
+        let found = self
+            .search(ldap, &self.admin_group, Scope::Base, &self.group_member_attr, dn, &["1.1"])
+            .await?;
+        Ok(!found.is_empty())
+    }
+
+    //找到用户并验证密码，用户不存在返回None
+    async fn lookup(&self, name: &str, password: &str) -> LdapResult<Option<(SearchEntry, UserRole)>> {
+        //空密码的bind在LDAP里是匿名登录，会直接成功
+        if password.is_empty() {
+            return Err("Invalid password".into());
+        }
+        let mut ldap = self.connect().await?;
+        let result = self.lookup_with(&mut ldap, name, password).await;
+        let _ = ldap.with_timeout(self.timeout).unbind().await;
+        result
+    }
+
+    async fn lookup_with(
+        &self,
+        ldap: &mut Ldap,
+        name: &str,
+        password: &str,
+    ) -> LdapResult<Option<(SearchEntry, UserRole)>> {
+        self.service_bind(ldap).await?;
+        let mut entries = self
+            .search(ldap, &self.base_dn, Scope::Subtree, &self.user_attr, name, &["cn", "displayName", "mail"])
+            .await?;
+        if entries.len() != 1 {
+            return Ok(None);
+        }
+        let entry = entries.remove(0);
+        if !self.bind(ldap, &entry.dn, password).await? {
+            return Err("Invalid password".into());
+        }
+        self.service_bind(ldap).await?;
+        let role = if self.is_admin(ldap, &entry.dn).await? {
+            UserRole::Admin
+        } else {
+            UserRole::Normal
+        };
+        Ok(Some((entry, role)))
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LdapProvider {
+    async fn authenticate(&self, name: &str, password: &str, mongo: &MongoDb) -> AuthResult {
+        let users = mongo.database.collection::<User>("users");
+        let existing = users.find_one(doc! { "username": name }).await?;
+        if matches!(&existing, Some(user) if user.provider.as_deref() != Some(PROVIDER_NAME)) {
+            return Ok(None);
+        }
+        let Some((entry, role)) = self.lookup(name, password).await? else {
+            return Ok(None);
+        };
+        let user = match existing {
+            Some(mut user) => {
+                //每次登录按组同步一下角色
+                if !self.admin_group.is_empty() && user.role != role {
+                    users
+                        .update_one(doc! { "_id": user._id }, doc! { "$set": { "role": mongodb::bson::to_bson(&role)? } })
+                        .await?;
+                    user.role = role;
+                }
+                user
+            }
+            None => {
+                let nickname = first_attr(&entry, "displayName")
+                    .or(first_attr(&entry, "cn"))
+                    .unwrap_or(name)
+                    .to_string();
+                //密码随机生成，只能走LDAP登录
+                let password = uuid::Uuid::new_v4().to_string();
+                let mut user = create_user(name, &password, &nickname, role, mongo, &self.system_root_id)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                users
+                    .update_one(doc! { "_id": user._id }, doc! { "$set": { "provider": PROVIDER_NAME } })
+                    .await?;
+                user.provider = Some(PROVIDER_NAME.to_string());
+                user
+            }
+        };
+        Ok(Some(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use ldap3::asn1::{
+        parse_tag, write, ASNTag, Enumerated, Integer, OctetString, Sequence, Set, StructureTag, Tag, TagClass,
+    };
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const BIND_REQUEST: u64 = 0;
+    const BIND_RESPONSE: u64 = 1;
+    const UNBIND_REQUEST: u64 = 2;
+    const SEARCH_REQUEST: u64 = 3;
+    const SEARCH_RESULT_ENTRY: u64 = 4;
+    const SEARCH_RESULT_DONE: u64 = 5;
+
+    fn provider(url: String, timeout: Duration) -> LdapProvider {
+        LdapProvider {
+            url,
+            bind_dn: "cn=admin,dc=example,dc=org".to_string(),
+            bind_password: "adminpassword".to_string(),
+            base_dn: "ou=users,dc=example,dc=org".to_string(),
+            user_attr: "uid".to_string(),
+            admin_group: "cn=rcadmins,ou=groups,dc=example,dc=org".to_string(),
+            group_member_attr: "member".to_string(),
+            system_root_id: ObjectId::new(),
+            timeout,
+        }
+    }
+
+    fn octet(value: &str) -> Tag {
+        Tag::OctetString(OctetString { inner: value.as_bytes().to_vec(), ..Default::default() })
+    }
+
+    fn response(id: u64, rc: i64) -> Tag {
+        Tag::Sequence(Sequence {
+            class: TagClass::Application,
+            id,
+            inner: vec![Tag::Enumerated(Enumerated { inner: rc, ..Default::default() }), octet(""), octet("")],
+        })
+    }
+
+    fn entry(dn: &str, attribute: &str, value: &str) -> Tag {
+        let values = Tag::Set(Set { inner: vec![octet(value)], ..Default::default() });
+        let attribute = Tag::Sequence(Sequence { inner: vec![octet(attribute), values], ..Default::default() });
+        Tag::Sequence(Sequence {
+            class: TagClass::Application,
+            id: SEARCH_RESULT_ENTRY,
+            inner: vec![octet(dn), Tag::Sequence(Sequence { inner: vec![attribute], ..Default::default() })],
+        })
+    }
+
+    fn text(tag: StructureTag) -> String {
+        String::from_utf8(tag.expect_primitive().unwrap()).unwrap()
+    }
+
+    //bind请求里的DN和密码
+    fn bind_args(op: StructureTag) -> (String, String) {
+        let mut fields = op.expect_constructed().unwrap().into_iter().skip(1);
+        (text(fields.next().unwrap()), text(fields.next().unwrap()))
+    }
+
+    //假的LDAP服务器，handler按操作类型返回应答，返回地址和收到的连接数
+    async fn mock_server<F>(handler: F) -> (String, Arc<AtomicUsize>)
+    where
+        F: Fn(u64, StructureTag) -> Vec<Tag> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        let handler = Arc::new(handler);
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                rocket::tokio::spawn(serve(stream, handler.clone()));
+            }
+        });
+        (url, accepted)
+    }
+
+    async fn serve<F>(mut stream: TcpStream, handler: Arc<F>)
+    where
+        F: Fn(u64, StructureTag) -> Vec<Tag>,
+    {
+        let mut buffer = Vec::new();
+        loop {
+            let (message, used) = match parse_tag(&buffer) {
+                Ok((rest, message)) => (message, buffer.len() - rest.len()),
+                Err(_) => {
+                    let mut chunk = [0u8; 4096];
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                    }
+                    continue;
+                }
+            };
+            buffer.drain(..used);
+            let mut fields = message.expect_constructed().unwrap().into_iter();
+            let id = fields
+                .next()
+                .unwrap()
+                .expect_primitive()
+                .unwrap()
+                .iter()
+                .fold(0i64, |acc, byte| (acc << 8) | *byte as i64);
+            let op = fields.next().unwrap();
+            if op.id == UNBIND_REQUEST {
+                return;
+            }
+            for reply in handler(op.id, op) {
+                let envelope = Tag::Sequence(Sequence {
+                    inner: vec![Tag::Integer(Integer { inner: id, ..Default::default() }), reply],
+                    ..Default::default()
+                });
+                let mut out = BytesMut::new();
+                write::encode_into(&mut out, envelope.into_structure()).unwrap();
+                if stream.write_all(&out).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    //alice/alicepassword，是管理员组成员
+    fn directory(op_id: u64, op: StructureTag) -> Vec<Tag> {
+        match op_id {
+            BIND_REQUEST => {
+                let ok = match bind_args(op) {
+                    (dn, password) if dn == "cn=admin,dc=example,dc=org" => password == "adminpassword",
+                    (dn, password) if dn == "uid=alice,ou=users,dc=example,dc=org" => password == "alicepassword",
+                    _ => false,
+                };
+                vec![response(BIND_RESPONSE, if ok { 0 } else { 49 })]
+            }
+            SEARCH_REQUEST => {
+                let base = text(op.expect_constructed().unwrap().remove(0));
+                if base.starts_with("cn=rcadmins") {
+                    vec![entry(&base, "cn", "rcadmins"), response(SEARCH_RESULT_DONE, 0)]
+                } else {
+                    vec![
+                        entry("uid=alice,ou=users,dc=example,dc=org", "cn", "Alice"),
+                        response(SEARCH_RESULT_DONE, 0),
+                    ]
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    #[rocket::async_test]
+    async fn binds_user_and_maps_admin_group() {
+        let (url, _) = mock_server(directory).await;
+        let provider = provider(url, IO_TIMEOUT);
+        let (entry, role) = provider.lookup("alice", "alicepassword").await.unwrap().unwrap();
+        assert_eq!(entry.dn, "uid=alice,ou=users,dc=example,dc=org");
+        assert_eq!(first_attr(&entry, "CN"), Some("Alice"));
+        assert_eq!(role, UserRole::Admin);
+        assert!(provider.lookup("alice", "wrong").await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_empty_password_without_connecting() {
+        let (url, accepted) = mock_server(directory).await;
+        let provider = provider(url, IO_TIMEOUT);
+        assert!(provider.lookup("alice", "").await.is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+    }
+
+    #[rocket::async_test]
+    async fn ignores_servers_past_size_limit() {
+        let (url, _) = mock_server(|op_id, _| match op_id {
+            BIND_REQUEST => vec![response(BIND_RESPONSE, 0)],
+            SEARCH_REQUEST => {
+                let mut replies: Vec<Tag> = (0..SEARCH_SIZE_LIMIT + 3)
+                    .map(|i| entry(&format!("uid=user{},ou=users,dc=example,dc=org", i), "cn", "user"))
+                    .collect();
+                replies.push(response(SEARCH_RESULT_DONE, 0));
+                replies
+            }
+            _ => vec![],
+        })
+        .await;
+        let provider = provider(url, IO_TIMEOUT);
+        assert!(provider.lookup("user", "password").await.unwrap().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn times_out_on_silent_server() {
+        let (url, _) = mock_server(|_, _| vec![]).await;
+        let provider = provider(url, Duration::from_millis(200));
+        let started = std::time::Instant::now();
+        assert!(provider.lookup("alice", "alicepassword").await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    //下面几个要先起compose.yaml里的openldap_dev，然后cargo test -- --ignored
+    const TEST_URL: &str = "ldap://localhost:1389";
+
+    #[rocket::async_test]
+    #[ignore = "needs the openldap_dev service from compose.yaml"]
+    async fn openldap_user_bind() {
+        let provider = provider(TEST_URL.to_string(), IO_TIMEOUT);
+        let (entry, role) = provider.lookup("alice", "alicepassword").await.unwrap().unwrap();
+        assert_eq!(entry.dn, "uid=alice,ou=users,dc=example,dc=org");
+        assert_eq!(role, UserRole::Admin);
+        assert!(provider.lookup("alice", "wrong").await.is_err());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the openldap_dev service from compose.yaml"]
+    async fn openldap_missing_user_and_group() {
+        let provider = provider(TEST_URL.to_string(), IO_TIMEOUT);
+        assert!(provider.lookup("nobody", "password").await.unwrap().is_none());
+        let (entry, _) = provider.lookup("bob", "bobpassword").await.unwrap().unwrap();
+        assert_eq!(entry.dn, "uid=bob,ou=users,dc=example,dc=org");
+    }
+}
@@ -1,6 +1,8 @@
 use super::guard::AuthenticatedUser;
+use super::provider::providers;
+use crate::MyConfig;
 use crate::db::connect::{MongoDb, Redis};
-use crate::db::models::{File, User};
+use crate::db::models::{AccessScope, File, LoginedDevice, LoginedDeviceType, User, UserRole};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
@@ -10,6 +12,8 @@ use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header,
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -19,12 +23,20 @@ pub struct Claims {
     pub jti: String,
 }
 
+//refresh token没人用的话登录多久失效，每次刷新往后顺延
+pub const SESSION_DAYS: i64 = 30;
+
 pub fn generate_jwt(user_id: &ObjectId, jwt_secret: &str) -> (String, String) {
+    generate_jwt_with_jti(user_id, &Uuid::new_v4().to_string(), jwt_secret)
+}
+
+//刷新的时候jti不变，登录设备还是同一个
+pub fn generate_jwt_with_jti(user_id: &ObjectId, jti: &str, jwt_secret: &str) -> (String, String) {
     let expiration = Utc::now()
         .checked_add_signed(Duration::hours(4))
         .expect("valid timestamp")
         .timestamp();
-    let jti = Uuid::new_v4().to_string();
+    let jti = jti.to_string();
     let claims = Claims {
         sub: user_id.to_string(),
         exp: expiration,
@@ -51,39 +63,52 @@ pub fn decode_jwt(token: &str, jwt_secret: &str) -> Result<Claims, jsonwebtoken:
     .map(|data| data.claims)
 }
 
+//按配置的provider顺序挨个试
 pub async fn authenticate(
     name: &str,
     password: &str,
-    mongo: &rocket::State<MongoDb>,
+    mongo: &MongoDb,
+    config: &MyConfig,
 ) -> Result<AuthenticatedUser, Box<dyn std::error::Error + Send + Sync>> {
-    let db = &mongo.database;
-    let collection = db.collection::<User>("users");
-    let user = collection
-        .find_one(doc! { "username": name.to_string() })
-        .await?;
-    match user {
-        Some(user) => {
-            let argon2 = Argon2::default();
-            let password_hash = PasswordHash::new(&user.password).unwrap();
-            if argon2
-                .verify_password(password.as_bytes(), &password_hash)
-                .is_ok()
-            {
-                Ok(AuthenticatedUser {
-                    uuid: user._id,
-                    username: user.username,
-                    nickname: user.nickname,
-                    token: None,
-                    root_id: user.root_id,
-                })
-            } else {
-                Err("Invalid password".into())
-            }
-        }
-        None => {
-            Err("User not found".into())
+    for provider in providers(config) {
+        match provider.authenticate(name, password, mongo).await? {
+            Some(user) if user.disabled => return Err("User disabled".into()),
+            Some(user) => return Ok(AuthenticatedUser::from_user(user, None)),
+            None => continue,
         }
     }
+    Err("User not found".into())
+}
+
+//先当access_key查redis，查不到再当jwt解
+//rocket的guard和webdav都用这个
+pub async fn authenticate_token(
+    token: &str,
+    jwt_secret: &str,
+    mongo: &MongoDb,
+    redis: &Redis,
+) -> Result<AuthenticatedUser, Box<dyn std::error::Error>> {
+    let key = LoginedDeviceType::ApiKey.redis_key(token);
+    if redis.exists(&key).await {
+        let user_id = ObjectId::from_str(redis.get::<&str, String>(&key).await.as_str())?;
+        let db = mongo.database.collection::<User>("users");
+        let user = match db.find_one(doc! { "_id": user_id }).await? {
+            Some(user) if user.disabled => return Err("User disabled".into()),
+            Some(user) => AuthenticatedUser::from_user(user, Some(token.to_owned())),
+            None => return Err("User not found".into()),
+        };
+        let device = mongo
+            .database
+            .collection::<LoginedDevice>("logined_devices")
+            .find_one(doc! { "uuid": token })
+            .await?;
+        //只认access key，登录会话的jti不能走这条路
+        return match device {
+            Some(device) if device.type_ == LoginedDeviceType::ApiKey => Ok(user.restrict(&device)),
+            _ => Err("Access key not found".into()),
+        };
+    }
+    authenticate_jwt(token, jwt_secret, mongo, redis).await
 }
 
 pub async fn authenticate_jwt(
@@ -97,25 +122,17 @@ pub async fn authenticate_jwt(
             if claims.exp < Utc::now().timestamp() {
                 return Err("Token expired".into());
             }
-            if !redis.exists(&claims.jti).await {
+            if !redis.exists(LoginedDeviceType::Normal.redis_key(&claims.jti)).await {
                 return Err("Invalid token".into());
             }
             let db = &mongo.database;
             let collection = db.collection::<User>("users");
             let user = collection
-                .find_one(doc! { "uuid": claims.sub })
-                .await
-                .unwrap();
+                .find_one(doc! { "_id": ObjectId::from_str(&claims.sub)? })
+                .await?;
             match user {
-                Some(user) => {
-                    Ok(AuthenticatedUser {
-                        uuid: user._id,
-                        username: user.username,
-                        nickname: user.nickname,
-                        token: claims.jti.to_owned().into(),
-                        root_id: user.root_id,
-                    })
-                }
+                Some(user) if user.disabled => Err("User disabled".into()),
+                Some(user) => Ok(AuthenticatedUser::from_user(user, claims.jti.to_owned().into())),
                 None => Err("User not found".into()),
             }
         }
@@ -123,25 +140,132 @@ pub async fn authenticate_jwt(
     }
 }
 
+//token对应的登录设备，jwt是jti，access key就是key本身
+pub async fn current_device(user: &AuthenticatedUser, mongo: &MongoDb) -> Option<ObjectId> {
+    let token = user.token.as_ref()?;
+    mongo
+        .database
+        .collection::<LoginedDevice>("logined_devices")
+        .find_one(doc! { "uuid": token })
+        .await
+        .ok()?
+        .map(|device| device._id)
+}
+
+pub fn hash_password(password: &str) -> String {
+    let argon2 = Argon2::default();
+    let salt = SaltString::generate(&mut OsRng);
+    argon2.hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+//webdav的方法也在这里
+pub fn scope_for_method(method: &str) -> AccessScope {
+    match method {
+        "GET" | "HEAD" | "OPTIONS" | "PROPFIND" => AccessScope::Read,
+        "DELETE" => AccessScope::Delete,
+        _ => AccessScope::Write,
+    }
+}
+
+//有限制的access key能不能访问这个接口
+//账号相关的（登录设备、密码、access key、管理员）一律不行，免得拿它再签一个不受限的出来
+pub fn key_allows(user: &AuthenticatedUser, method: &str, path: &str) -> bool {
+    if user.scopes.is_none() && user.folder.is_none() {
+        return true;
+    }
+    if path == "/auth/logout" {
+        return true;
+    }
+    if path.starts_with("/auth") || path.starts_with("/admin") || path.starts_with("/file/version/policy") {
+        return false;
+    }
+    //回收站不分文件夹，限定了文件夹的就不让看
+    if user.folder.is_some() && path.starts_with("/trash") {
+        return false;
+    }
+    if path.starts_with("/file/share") {
+        return user.has_scope(AccessScope::Share);
+    }
+    user.has_scope(scope_for_method(method))
+}
+
+//refresh token是"jti.随机串"，库里只存sha256
+pub fn generate_refresh_token(jti: &str) -> (String, String) {
+    let token = format!("{}.{}", jti, Uuid::new_v4().simple());
+    let hash = hash_refresh_token(&token);
+    (token, hash)
+}
+
+pub fn hash_refresh_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+//把这个用户所有的登录和access key都踢掉，except是要留下的那个（当前登录）
+pub async fn revoke_sessions(
+    user_id: &ObjectId,
+    except: Option<&str>,
+    mongo: &MongoDb,
+    redis: &Redis,
+) -> Result<(), mongodb::error::Error> {
+    let collection = mongo.database.collection::<LoginedDevice>("logined_devices");
+    let filter = match except {
+        Some(token) => doc! { "user_uuid": user_id, "uuid": { "$ne": token } },
+        None => doc! { "user_uuid": user_id },
+    };
+    let mut cursor = collection.find(filter.clone()).await?;
+    while cursor.advance().await? {
+        let device = cursor.deserialize_current()?;
+        redis.delete(device.redis_key()).await;
+    }
+    collection.delete_many(filter).await?;
+    Ok(())
+}
+
+//以前的库里没有role，一个管理员都没有的话把admin账号升成管理员
+pub async fn ensure_admin(mongo: &MongoDb) -> Result<(), mongodb::error::Error> {
+    let collection = mongo.database.collection::<User>("users");
+    if collection.find_one(doc! { "role": "Admin" }).await?.is_none() {
+        collection
+            .update_one(doc! { "username": "admin" }, doc! { "$set": { "role": "Admin" } })
+            .await?;
+    }
+    Ok(())
+}
+
 pub async fn create_user(
     name: &str,
     password: &str,
     nickname: &str,
+    role: UserRole,
     mongo: &MongoDb,
     root_id: &ObjectId
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<User, Box<dyn std::error::Error>> {
     let db = &mongo.database;
     let collection = db.collection::<User>("users");
-    let argon2 = Argon2::default();
-    let salt = SaltString::generate(&mut OsRng);
-    let password_hash = argon2.hash_password(password.as_bytes(), &salt).unwrap();
     let user_root_id = ObjectId::new();
     let user = User {
         username: name.to_string(),
         nickname: nickname.to_string(),
-        password: password_hash.to_string(),
+        password: hash_password(password),
         _id: ObjectId::new(),
         root_id: user_root_id,
+        role,
+        disabled: false,
+        provider: None,
+        oidc_subject: None,
+        totp: None,
+        version_policy: None,
+        quota: None,
+        used_bytes: 0,
     };
     collection.insert_one(&user).await?;
     let collection = db.collection::<File>("files");
@@ -155,5 +279,5 @@ pub async fn create_user(
             )
         )
         .await?;
-    Ok(())
+    Ok(user)
 }
@@ -0,0 +1,47 @@
+//登录的时候按配置顺序挨个问，用户名不归它管就返回None交给下一个
+use super::ldap::LdapProvider;
+use super::lib::verify_password;
+use crate::db::connect::MongoDb;
+use crate::db::models::User;
+use crate::MyConfig;
+use async_trait::async_trait;
+use mongodb::bson::doc;
+
+pub type AuthResult = Result<Option<User>, Box<dyn std::error::Error + Send + Sync>>;
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    //Ok(None)表示这个用户不归这里管，Err表示归这里管但是没通过
+    async fn authenticate(&self, name: &str, password: &str, mongo: &MongoDb) -> AuthResult;
+}
+
+//库里存的argon2密码，外部provider建的用户不走这里
+pub struct LocalProvider;
+
+#[async_trait]
+impl AuthProvider for LocalProvider {
+    async fn authenticate(&self, name: &str, password: &str, mongo: &MongoDb) -> AuthResult {
+        let collection = mongo.database.collection::<User>("users");
+        match collection.find_one(doc! { "username": name }).await? {
+            Some(user) if user.provider.is_some() => Ok(None),
+            Some(user) if verify_password(password, &user.password) => Ok(Some(user)),
+            Some(_) => Err("Invalid password".into()),
+            None => Ok(None),
+        }
+    }
+}
+
+//配置里auth_providers是逗号分隔的名字，比如"local,ldap"
+pub fn providers(config: &MyConfig) -> Vec<Box<dyn AuthProvider>> {
+    config
+        .auth_providers
+        .split(',')
+        .filter_map(|name| -> Option<Box<dyn AuthProvider>> {
+            match name.trim() {
+                "local" => Some(Box::new(LocalProvider)),
+                "ldap" if !config.ldap_url.is_empty() => Some(Box::new(LdapProvider::new(config))),
+                _ => None,
+            }
+        })
+        .collect()
+}
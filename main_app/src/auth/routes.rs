@@ -1,15 +1,21 @@
-use super::lib::{authenticate, generate_jwt};
+use super::lib::{
+    authenticate, generate_jwt, generate_refresh_token, hash_password, hash_refresh_token,
+    revoke_sessions, SESSION_DAYS,
+};
+use super::{oidc, totp};
+use crate::rate_limit::lib as rate_limit;
+use crate::rate_limit::guard::ClientIp;
 use super::guard::AuthenticatedUser;
 use crate::db::connect::{MongoDb, Redis};
-use crate::db::models::{LoginedDevice, LoginedDeviceType};
+use crate::db::models::{AccessScope, File, FileType, LoginedDevice, LoginedDeviceType, TotpSettings, User};
 use crate::MyConfig;
-use crate::libs::ApiError;
+use crate::libs::{check_file_permission, mongo_error_check, parse_object_id, ApiError};
 use chrono::Utc;
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 use rocket::form::Form;
 use rocket::futures::TryStreamExt;
-use rocket::response::status;
+use rocket::response::{status, Redirect};
 use rocket::serde::json::Json;
 
 #[derive(FromForm,Debug)]
@@ -26,42 +32,201 @@ pub struct LoginResponse {
     pub nickname: String,
     pub token: String,
     pub expire_at: i64,
+    pub refresh_token: String, //token过期之后拿它去/refresh换新的，用一次就作废
+    pub refresh_expire_at: i64,
+}
+
+//开了两步验证的账号登录时先返回这个，再拿challenge和验证码去/login/totp
+#[derive(serde::Serialize)]
+pub struct TotpChallengeResponse {
+    pub totp_required: bool,
+    pub challenge: String,
+    pub expire_at: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+pub enum LoginResult {
+    Session(LoginResponse),
+    Totp(TotpChallengeResponse),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PendingLogin {
+    user: ObjectId,
+    device_name: String,
+}
+
+async fn start_session(
+    login_user: AuthenticatedUser,
+    device_name: String,
+    mongo: &MongoDb,
+    redis: &Redis,
+    config: &MyConfig,
+) -> LoginResponse {
+    let (token, jti) = generate_jwt(&login_user.uuid, &config.jwt_secret);
+    //redis里存jti，和recover_from_db、logout保持一致
+    let key = LoginedDeviceType::Normal.redis_key(&jti);
+    redis.set(&key, &login_user.uuid.to_string()).await;
+    redis.expire(&key, 4 * 60 * 60).await;
+    let (refresh_token, refresh_hash) = generate_refresh_token(&jti);
+    let expire_at = chrono::Utc::now() + chrono::Duration::days(SESSION_DAYS);
+    let login_device = LoginedDevice {
+        user_uuid: login_user.uuid,
+        uuid: jti,
+        name: device_name,
+        logined_at: chrono::Utc::now(),
+        expire_at,
+        _id: ObjectId::new(),
+        type_: LoginedDeviceType::Normal,
+        refresh_token: Some(refresh_hash),
+        previous_refresh_token: None,
+        scopes: None,
+        folder: None,
+    };
+    let db = mongo.database.collection::<LoginedDevice>("logined_devices");
+    let _ = db.insert_one(login_device).await;
+    LoginResponse {
+        uuid: login_user.uuid.to_string(),
+        username: login_user.username,
+        nickname: login_user.nickname,
+        token,
+        expire_at: chrono::Utc::now().timestamp() + 4 * 60 * 60 - 1,//-1做保险
+        refresh_token,
+        refresh_expire_at: expire_at.timestamp(),
+    }
+}
+
+async fn drop_device(device: &LoginedDevice, mongo: &MongoDb, redis: &Redis) {
+    redis.delete(device.redis_key()).await;
+    let db = mongo.database.collection::<LoginedDevice>("logined_devices");
+    let _ = db.delete_one(doc! { "_id": device._id }).await;
+}
+
+#[derive(FromForm, Debug)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+//换新的token和refresh token，设备的过期时间顺延，jti也换新的
+//按hash找设备，拿上一个refresh token来换说明被人偷用了，整个设备踢掉
+//对不上的一律401，不动设备，不然知道jti就能把人踢下线
+#[post("/refresh", data = "<request>")]
+pub async fn refresh(
+    request: Form<RefreshRequest>,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let invalid = || ApiError::Unauthorized("Invalid refresh token".to_string().into());
+    let reused = || ApiError::Unauthorized("Refresh token reused, device logged out".to_string().into());
+    let presented = hash_refresh_token(&request.refresh_token);
+    let db = mongo.database.collection::<LoginedDevice>("logined_devices");
+    let filter = doc! {
+        "type_": "Normal",
+        "$or": [{ "refresh_token": &presented }, { "previous_refresh_token": &presented }],
+    };
+    let device = match db.find_one(filter).await {
+        Ok(Some(device)) => device,
+        Ok(None) => return Err(invalid()),
+        Err(_) => return Err(ApiError::InternalServerError("DB error".to_string().into())),
+    };
+    if device.refresh_token.as_deref() != Some(presented.as_str()) {
+        drop_device(&device, mongo, redis).await;
+        return Err(reused());
+    }
+    if device.expire_at < Utc::now() {
+        drop_device(&device, mongo, redis).await;
+        return Err(invalid());
+    }
+    let user = match mongo.database.collection::<User>("users").find_one(doc! { "_id": device.user_uuid }).await {
+        Ok(Some(user)) if !user.disabled => user,
+        _ => {
+            drop_device(&device, mongo, redis).await;
+            return Err(invalid());
+        }
+    };
+    let (token, jti) = generate_jwt(&user._id, &config.jwt_secret);
+    let (refresh_token, refresh_hash) = generate_refresh_token(&jti);
+    let expire_at = Utc::now() + chrono::Duration::days(SESSION_DAYS);
+    //带上旧hash当条件，两个请求同时拿同一个refresh token来只有一个能成，输的那个就当是重用
+    let rotated = db
+        .update_one(
+            doc! { "_id": device._id, "refresh_token": &presented },
+            doc! { "$set": {
+                "uuid": &jti,
+                "refresh_token": refresh_hash,
+                "previous_refresh_token": &presented,
+                "expire_at": mongodb::bson::DateTime::from_chrono(expire_at),
+            } },
+        )
+        .await;
+    match rotated {
+        Ok(result) if result.modified_count == 1 => {}
+        Ok(_) => {
+            drop_device(&device, mongo, redis).await;
+            return Err(reused());
+        }
+        Err(_) => return Err(ApiError::InternalServerError("DB error".to_string().into())),
+    }
+    //旧jti签出去的token马上作废
+    redis.delete(device.redis_key()).await;
+    let key = LoginedDeviceType::Normal.redis_key(&jti);
+    redis.set(&key, &user._id.to_string()).await;
+    redis.expire(&key, 4 * 60 * 60).await;
+    Ok(Json(LoginResponse {
+        uuid: user._id.to_string(),
+        username: user.username,
+        nickname: user.nickname,
+        token,
+        expire_at: Utc::now().timestamp() + 4 * 60 * 60 - 1,
+        refresh_token,
+        refresh_expire_at: expire_at.timestamp(),
+    }))
+}
+
+//第一步验证通过之后，开了两步验证的发challenge，没开的直接登录
+async fn finish_login(
+    login_user: AuthenticatedUser,
+    device_name: String,
+    mongo: &MongoDb,
+    redis: &Redis,
+    config: &MyConfig,
+) -> LoginResult {
+    if !totp::totp_enabled(&login_user.uuid, mongo).await {
+        return LoginResult::Session(start_session(login_user, device_name, mongo, redis, config).await);
+    }
+    let challenge = uuid::Uuid::new_v4().to_string();
+    let key = format!("totp_challenge:{}", challenge);
+    let pending = PendingLogin { user: login_user.uuid, device_name };
+    redis.set(&key, serde_json::to_string(&pending).unwrap()).await;
+    redis.expire(&key, totp::CHALLENGE_TTL).await;
+    LoginResult::Totp(TotpChallengeResponse {
+        totp_required: true,
+        challenge,
+        expire_at: chrono::Utc::now().timestamp() + totp::CHALLENGE_TTL,
+    })
 }
 
 #[post("/login", data = "<user>")]
 pub async fn login(
     user: Form<LoginRequest>,
+    remote: ClientIp,
     mongo: &rocket::State<MongoDb>,
     redis: &rocket::State<Redis>,
     config: &rocket::State<MyConfig>,
-) -> Result<Json<LoginResponse>, ApiError> {
+) -> Result<Json<LoginResult>, ApiError> {
     let user = user.into_inner();
-    let auth_result = authenticate(&user.username, &user.password, mongo).await;
+    let counters = rate_limit::counters("login", remote.0, &user.username, config);
+    rate_limit::check_locked(redis, &counters).await?;
+    let auth_result = authenticate(&user.username, &user.password, mongo, config).await;
     match auth_result {
         Ok(login_user) => {
-            let (token, jti) = generate_jwt(&login_user.uuid, &config.jwt_secret);
-            redis.set(&token, &login_user.uuid.to_string()).await;
-            redis.expire(&token, 4 * 60 * 60).await;
-            let login_device = LoginedDevice {
-                user_uuid: login_user.uuid,
-                uuid: jti,
-                name: user.device_name,
-                logined_at: chrono::Utc::now(),
-                expire_at: chrono::Utc::now() + chrono::Duration::hours(4),
-                _id: ObjectId::new(),
-                type_: LoginedDeviceType::Normal,
-            };
-            let db = mongo.database.collection::<LoginedDevice>("logined_devices");
-            let _ = db.insert_one(login_device).await;
-            Ok(Json(LoginResponse {
-                uuid: login_user.uuid.to_string(),
-                username: login_user.username,
-                nickname: login_user.nickname,
-                token,
-                expire_at: chrono::Utc::now().timestamp() + 4 * 60 * 60 - 1,//-1做保险
-            }))
+            rate_limit::record_success(redis, &counters).await;
+            Ok(Json(finish_login(login_user, user.device_name, mongo, redis, config).await))
         }
         Err(_) => {
+            rate_limit::record_failure(redis, config, &counters).await;
             Err(
                 ApiError::Unauthorized("Invalid username or password".to_string().into()),
             )
@@ -69,6 +234,85 @@ pub async fn login(
     }
 }
 
+//跳到IdP去登录，回来走/oidc/callback
+#[get("/oidc/login?<device_name>")]
+pub async fn oidc_login(
+    device_name: String,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    oidc_cache: &rocket::State<oidc::OidcCache>,
+) -> Result<Redirect, ApiError> {
+    if !oidc::enabled(config) {
+        return Err(ApiError::NotFound("OIDC login is not configured".to_string().into()));
+    }
+    Ok(Redirect::to(oidc::begin(device_name, config, redis, oidc_cache).await?))
+}
+
+//返回和/login一样的东西
+#[get("/oidc/callback?<code>&<state>&<error>")]
+pub async fn oidc_callback(
+    code: Option<&str>,
+    state: &str,
+    error: Option<&str>,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    oidc_cache: &rocket::State<oidc::OidcCache>,
+) -> Result<Json<LoginResult>, ApiError> {
+    if !oidc::enabled(config) {
+        return Err(ApiError::NotFound("OIDC login is not configured".to_string().into()));
+    }
+    let pending = oidc::take_pending(state, redis).await?;
+    let code = match (code, error) {
+        (Some(code), None) => code,
+        (_, Some(error)) => return Err(ApiError::Unauthorized(format!("OIDC: {}", error).into())),
+        (None, None) => return Err(ApiError::BadRequest("Missing code".to_string().into())),
+    };
+    let claims = oidc::exchange(code, &pending, config, oidc_cache).await?;
+    let user = oidc::resolve_user(&claims, config, mongo).await?;
+    if user.disabled {
+        return Err(ApiError::Unauthorized("User disabled".to_string().into()));
+    }
+    let login_user = AuthenticatedUser::from_user(user, None);
+    Ok(Json(finish_login(login_user, pending.device_name, mongo, redis, config).await))
+}
+
+#[derive(FromForm, Debug)]
+pub struct TotpLoginRequest {
+    pub challenge: String,
+    pub code: String, //验证码或者恢复码
+}
+
+#[post("/login/totp", data = "<request>")]
+pub async fn login_totp(
+    request: Form<TotpLoginRequest>,
+    remote: ClientIp,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let key = format!("totp_challenge:{}", request.challenge);
+    if !redis.exists(&key).await {
+        return Err(ApiError::Unauthorized("Invalid or expired challenge".to_string().into()));
+    }
+    let pending: String = redis.get(&key).await;
+    let pending: PendingLogin = serde_json::from_str(&pending).unwrap();
+    //按用户算，换challenge重来也没用
+    let counters = rate_limit::counters("totp", remote.0, &pending.user.to_hex(), config);
+    rate_limit::check_locked(redis, &counters).await?;
+    if !totp::check_second_factor(&pending.user, &request.code, mongo, redis).await {
+        rate_limit::record_failure(redis, config, &counters).await;
+        return Err(ApiError::Unauthorized("Invalid code".to_string().into()));
+    }
+    rate_limit::record_success(redis, &counters).await;
+    redis.delete(&key).await;
+    let user = match mongo.database.collection::<User>("users").find_one(doc! { "_id": pending.user }).await {
+        Ok(Some(user)) if !user.disabled => user,
+        _ => return Err(ApiError::Unauthorized("User not found".to_string().into())),
+    };
+    Ok(Json(start_session(AuthenticatedUser::from_user(user, None), pending.device_name, mongo, redis, config).await))
+}
+
 #[post("/logout")]
 pub async fn logout(
     user: AuthenticatedUser,
@@ -77,9 +321,10 @@ pub async fn logout(
 ) -> Result<status::NoContent, ApiError> {
     match user.token {
         Some(token) => {
-            redis.delete(token.as_str()).await;
             let db = mongo.database.collection::<LoginedDevice>("logined_devices");
-            let _ = db.delete_one(doc! { "uuid": token }).await;
+            if let Ok(Some(device)) = db.find_one_and_delete(doc! { "uuid": token, "user_uuid": user.uuid }).await {
+                redis.delete(device.redis_key()).await;
+            }
         }
         None => {
             return Err(
@@ -95,26 +340,71 @@ pub struct AccessKeyResponse {
     pub token: String,
 }
 
+fn parse_scopes(scopes: &str) -> Result<Vec<AccessScope>, ApiError> {
+    scopes
+        .split(',')
+        .map(|scope| match scope.trim() {
+            "read" => Ok(AccessScope::Read),
+            "write" => Ok(AccessScope::Write),
+            "delete" => Ok(AccessScope::Delete),
+            "share" => Ok(AccessScope::Share),
+            _ => Err(ApiError::BadRequest(format!("Unknown scope: {}", scope).into())),
+        })
+        .collect()
+}
+
 //长效token
-#[get("/access_key?<device_name>")]
+//scopes是逗号分隔的read,write,delete,share，不填就是不限；folder限定只能动这个文件夹
+//expire_days不填是365天
+#[get("/access_key?<device_name>&<scopes>&<folder>&<expire_days>")]
 pub async fn create_access_key(
     user: AuthenticatedUser,
     device_name: String,
+    scopes: Option<&str>,
+    folder: Option<&str>,
+    expire_days: Option<u32>,
     redis: &rocket::State<Redis>,
     mongo: &rocket::State<MongoDb>
 ) -> Result<Json<AccessKeyResponse>, ApiError> {
+    let scopes = scopes.map(parse_scopes).transpose()?;
+    let expire_days = expire_days.unwrap_or(365);
+    if expire_days == 0 || expire_days > 3650 {
+        return Err(ApiError::BadRequest("expire_days must be between 1 and 3650".to_string().into()));
+    }
+    let folder = match folder {
+        Some(folder) => {
+            let folder = mongo
+                .database
+                .collection::<File>("files")
+                .find_one(doc! { "_id": parse_object_id(folder)? })
+                .await;
+            let folder = mongo_error_check(folder, Some("Folder"))?;
+            check_file_permission(&user, &folder, mongo).await?;
+            if folder.type_ == FileType::File {
+                return Err(ApiError::BadRequest("Target is not a folder".to_string().into()));
+            }
+            Some(folder._id)
+        }
+        None => None,
+    };
     let token = uuid::Uuid::new_v4().to_string();
-    redis.set(&token, &user.uuid.to_string()).await;
-    redis.expire(&token, 4 * 60 * 60).await;
+    let expire_at = Utc::now() + chrono::Duration::days(expire_days as i64);
+    let key = LoginedDeviceType::ApiKey.redis_key(&token);
+    redis.set(&key, &user.uuid.to_string()).await;
+    let _ = redis.expire_at(&key, expire_at).await;
     let db = mongo.database.collection::<LoginedDevice>("logined_devices");
     let _ = db.insert_one(LoginedDevice {
         user_uuid: user.uuid,
         uuid: token.clone(),
         name: device_name,
         logined_at: Utc::now(),
-        expire_at: Utc::now() + chrono::Duration::days(365),
+        expire_at,
         _id: ObjectId::new(),
         type_: LoginedDeviceType::ApiKey,
+        refresh_token: None,
+        previous_refresh_token: None,
+        scopes,
+        folder,
     }).await;
     Ok(Json(AccessKeyResponse { token }))
 }
@@ -132,9 +422,18 @@ pub async fn delete_access_key(
     mongo: &rocket::State<MongoDb>
 ) -> Result<status::NoContent, ApiError> {
     let db = mongo.database.collection::<LoginedDevice>("logined_devices");
-    let _ = db.delete_one(doc! { "uuid": request.token.clone(), "user_uuid": user.uuid }).await;
-    redis.delete(request.token.as_str()).await;
-    Ok(status::NoContent)
+    //只删自己的access key，mongo里真删掉了才去动redis
+    let result = db
+        .delete_one(doc! { "uuid": request.token.clone(), "user_uuid": user.uuid, "type_": "ApiKey" })
+        .await;
+    match result {
+        Ok(result) if result.deleted_count == 1 => {
+            redis.delete(LoginedDeviceType::ApiKey.redis_key(&request.token)).await;
+            Ok(status::NoContent)
+        }
+        Ok(_) => Err(ApiError::NotFound("Access key not found".to_string().into())),
+        Err(_) => Err(ApiError::InternalServerError("DB error".to_string().into())),
+    }
 }
 
 #[get("/list_devices")]
@@ -166,4 +465,217 @@ pub async fn list_devices(
     };
 
     Ok(Json(devices))
-}
\ No newline at end of file
+}
+#[derive(serde::Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    #[serde(default)]
+    pub revoke_other_sessions: bool, //其他设备的登录和access key都踢掉，当前这个留着
+}
+
+#[post("/password", data = "<request>")]
+pub async fn change_password(
+    user: AuthenticatedUser,
+    request: Json<ChangePasswordRequest>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+    redis: &rocket::State<Redis>,
+) -> Result<status::NoContent, ApiError> {
+    if request.new_password.is_empty() {
+        return Err(ApiError::BadRequest("Password is required".to_string().into()));
+    }
+    let db = mongo.database.collection::<User>("users");
+    //LDAP、OIDC的用户密码不在这里管
+    if let Ok(Some(User { provider: Some(_), .. })) = db.find_one(doc! { "_id": user.uuid }).await {
+        return Err(ApiError::BadRequest("Password is managed by an external provider".to_string().into()));
+    }
+    if authenticate(&user.username, &request.current_password, mongo, config).await.is_err() {
+        return Err(ApiError::Unauthorized("Invalid password".to_string().into()));
+    }
+    if db
+        .update_one(doc! { "_id": user.uuid }, doc! { "$set": { "password": hash_password(&request.new_password) } })
+        .await
+        .is_err()
+    {
+        return Err(ApiError::InternalServerError("DB error".to_string().into()));
+    }
+    if request.revoke_other_sessions
+        && revoke_sessions(&user.uuid, user.token.as_deref(), mongo, redis).await.is_err()
+    {
+        return Err(ApiError::InternalServerError("DB error".to_string().into()));
+    }
+    Ok(status::NoContent)
+}
+
+#[derive(serde::Deserialize)]
+pub struct UpdateSettingsRequest {
+    pub nickname: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SettingsResponse {
+    pub uuid: String,
+    pub username: String,
+    pub nickname: String,
+}
+
+#[put("/settings", data = "<request>")]
+pub async fn update_settings(
+    user: AuthenticatedUser,
+    request: Json<UpdateSettingsRequest>,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<SettingsResponse>, ApiError> {
+    let mut nickname = user.nickname;
+    if let Some(new_nickname) = request.into_inner().nickname {
+        if new_nickname.trim().is_empty() {
+            return Err(ApiError::BadRequest("Nickname cannot be empty".to_string().into()));
+        }
+        let db = mongo.database.collection::<User>("users");
+        if db
+            .update_one(doc! { "_id": user.uuid }, doc! { "$set": { "nickname": &new_nickname } })
+            .await
+            .is_err()
+        {
+            return Err(ApiError::InternalServerError("DB error".to_string().into()));
+        }
+        nickname = new_nickname;
+    }
+    Ok(Json(SettingsResponse {
+        uuid: user.uuid.to_string(),
+        username: user.username,
+        nickname,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TotpEnrollRequest {
+    pub password: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct TotpEnrollResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+//生成新密钥，确认之前不生效，重复调用会换一个密钥
+#[post("/totp/enroll", data = "<request>")]
+pub async fn totp_enroll(
+    user: AuthenticatedUser,
+    request: Json<TotpEnrollRequest>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<TotpEnrollResponse>, ApiError> {
+    if authenticate(&user.username, &request.password, mongo, config).await.is_err() {
+        return Err(ApiError::Unauthorized("Invalid password".to_string().into()));
+    }
+    if totp::totp_enabled(&user.uuid, mongo).await {
+        return Err(ApiError::BadRequest("Two-factor authentication already enabled".to_string().into()));
+    }
+    let secret = totp::generate_secret();
+    let settings = TotpSettings { secret: secret.clone(), enabled: false, recovery_codes: vec![] };
+    let db = mongo.database.collection::<User>("users");
+    if db
+        .update_one(doc! { "_id": user.uuid }, doc! { "$set": { "totp": mongodb::bson::to_bson(&settings).unwrap() } })
+        .await
+        .is_err()
+    {
+        return Err(ApiError::InternalServerError("DB error".to_string().into()));
+    }
+    Ok(Json(TotpEnrollResponse {
+        otpauth_uri: totp::otpauth_uri(&secret, &user.username),
+        secret,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>, //只在这里返回一次明文
+}
+
+#[post("/totp/confirm", data = "<request>")]
+pub async fn totp_confirm(
+    user: AuthenticatedUser,
+    request: Json<TotpCodeRequest>,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<RecoveryCodesResponse>, ApiError> {
+    let settings = match totp::get_totp(&user.uuid, mongo).await {
+        Some(settings) if !settings.enabled => settings,
+        Some(_) => return Err(ApiError::BadRequest("Two-factor authentication already enabled".to_string().into())),
+        None => return Err(ApiError::BadRequest("Two-factor authentication not enrolled".to_string().into())),
+    };
+    if totp::verify_code(&settings.secret, &request.code).is_none() {
+        return Err(ApiError::Unauthorized("Invalid code".to_string().into()));
+    }
+    let codes = totp::generate_recovery_codes();
+    let db = mongo.database.collection::<User>("users");
+    if db
+        .update_one(
+            doc! { "_id": user.uuid },
+            doc! { "$set": { "totp.enabled": true, "totp.recovery_codes": totp::hash_recovery_codes(&codes) } },
+        )
+        .await
+        .is_err()
+    {
+        return Err(ApiError::InternalServerError("DB error".to_string().into()));
+    }
+    Ok(Json(RecoveryCodesResponse { recovery_codes: codes }))
+}
+
+//旧的恢复码全部作废
+#[post("/totp/recovery_codes", data = "<request>")]
+pub async fn totp_recovery_codes(
+    user: AuthenticatedUser,
+    request: Json<TotpCodeRequest>,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<Json<RecoveryCodesResponse>, ApiError> {
+    if !totp::check_second_factor(&user.uuid, &request.code, mongo, redis).await {
+        return Err(ApiError::Unauthorized("Invalid code".to_string().into()));
+    }
+    let codes = totp::generate_recovery_codes();
+    let db = mongo.database.collection::<User>("users");
+    if db
+        .update_one(doc! { "_id": user.uuid }, doc! { "$set": { "totp.recovery_codes": totp::hash_recovery_codes(&codes) } })
+        .await
+        .is_err()
+    {
+        return Err(ApiError::InternalServerError("DB error".to_string().into()));
+    }
+    Ok(Json(RecoveryCodesResponse { recovery_codes: codes }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct TotpDisableRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[delete("/totp", data = "<request>")]
+pub async fn totp_disable(
+    user: AuthenticatedUser,
+    request: Json<TotpDisableRequest>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+    redis: &rocket::State<Redis>,
+) -> Result<status::NoContent, ApiError> {
+    if authenticate(&user.username, &request.password, mongo, config).await.is_err() {
+        return Err(ApiError::Unauthorized("Invalid password".to_string().into()));
+    }
+    if totp::totp_enabled(&user.uuid, mongo).await
+        && !totp::check_second_factor(&user.uuid, &request.code, mongo, redis).await
+    {
+        return Err(ApiError::Unauthorized("Invalid code".to_string().into()));
+    }
+    let db = mongo.database.collection::<User>("users");
+    if db.update_one(doc! { "_id": user.uuid }, doc! { "$unset": { "totp": "" } }).await.is_err() {
+        return Err(ApiError::InternalServerError("DB error".to_string().into()));
+    }
+    Ok(status::NoContent)
+}
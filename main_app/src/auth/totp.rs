@@ -0,0 +1,166 @@
+//两步验证，RFC 6238，SHA1、30秒、6位，和常见的验证器app一致
+use super::lib::{hash_password, verify_password};
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::{TotpSettings, User};
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use rand::Rng;
+use sha1::Sha1;
+
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+const ISSUER: &str = "Crate";
+const RECOVERY_CODE_COUNT: usize = 10;
+//登录第一步通过之后，第二步要在这段时间里完成
+pub const CHALLENGE_TTL: i64 = 5 * 60;
+
+pub fn generate_secret() -> String {
+    let secret: [u8; 20] = rand::thread_rng().gen();
+    BASE32_NOPAD.encode(&secret)
+}
+
+pub fn otpauth_uri(secret: &str, username: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        issuer = ISSUER,
+    )
+}
+
+fn code_at(secret: &[u8], counter: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+//前后各放宽一个周期，返回匹配上的周期，用来防重放
+pub fn verify_code(secret: &str, code: &str) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.trim().parse().ok()?;
+    let now = Utc::now().timestamp() / STEP;
+    (now - 1..=now + 1).find(|counter| code_at(&secret, *counter) == code)
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: [u8; 5] = rng.gen();
+            BASE32_NOPAD.encode(&code).to_lowercase()
+        })
+        .collect()
+}
+
+pub fn hash_recovery_codes(codes: &[String]) -> Vec<String> {
+    codes.iter().map(|code| hash_password(code)).collect()
+}
+
+pub async fn get_totp(user_id: &ObjectId, mongo: &MongoDb) -> Option<TotpSettings> {
+    match mongo.database.collection::<User>("users").find_one(doc! { "_id": user_id }).await {
+        Ok(Some(user)) => user.totp,
+        _ => None,
+    }
+}
+
+pub async fn totp_enabled(user_id: &ObjectId, mongo: &MongoDb) -> bool {
+    matches!(get_totp(user_id, mongo).await, Some(TotpSettings { enabled: true, .. }))
+}
+
+//验证码或者恢复码都可以，恢复码用掉就删
+pub async fn check_second_factor(user_id: &ObjectId, code: &str, mongo: &MongoDb, redis: &Redis) -> bool {
+    let totp = match get_totp(user_id, mongo).await {
+        Some(totp) if totp.enabled => totp,
+        _ => return false,
+    };
+    if let Some(counter) = verify_code(&totp.secret, code) {
+        let used_key = format!("totp_used:{}:{}", user_id, counter);
+        //两个请求同时拿同一个码来的话只有一个能写进去
+        return redis.set_nx_ex(&used_key, 1, (STEP * 3) as u64).await;
+    }
+    let code = code.trim().to_lowercase();
+    match totp.recovery_codes.iter().find(|hash| verify_password(&code, hash)) {
+        Some(hash) => {
+            let _ = mongo
+                .database
+                .collection::<User>("users")
+                .update_one(doc! { "_id": user_id }, doc! { "$pull": { "totp.recovery_codes": hash } })
+                .await;
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    //RFC 6238附录B的SHA1向量，原文是8位，这里取后6位
+    #[test]
+    fn rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(code_at(secret, time / STEP), code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_adjacent_steps_only() {
+        let secret = generate_secret();
+        let raw = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        let now = Utc::now().timestamp() / STEP;
+        for counter in [now - 1, now, now + 1] {
+            //相邻周期的码碰巧一样时返回的是先匹配上的那个
+            let matched = verify_code(&secret, &format!("{:06}", code_at(&raw, counter))).unwrap();
+            assert_eq!(code_at(&raw, matched), code_at(&raw, counter));
+        }
+        let stale = code_at(&raw, now - 5);
+        if (now - 1..=now + 1).all(|counter| code_at(&raw, counter) != stale) {
+            assert_eq!(verify_code(&secret, &format!("{:06}", stale)), None);
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev and redis_dev services from compose.yaml"]
+    async fn code_cannot_be_replayed() {
+        let mongo = test_support::mongo().await;
+        let redis = test_support::redis().await;
+        let login = test_support::user(&mongo).await;
+        let secret = generate_secret();
+        let user = User {
+            _id: login.uuid,
+            username: login.username.clone(),
+            nickname: login.nickname.clone(),
+            password: String::new(),
+            root_id: login.root_id,
+            role: login.role.clone(),
+            disabled: false,
+            provider: None,
+            oidc_subject: None,
+            totp: Some(TotpSettings { secret: secret.clone(), enabled: true, recovery_codes: vec![] }),
+            version_policy: None,
+            quota: None,
+            used_bytes: 0,
+        };
+        mongo.database.collection::<User>("users").insert_one(&user).await.unwrap();
+        let raw = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        let code = format!("{:06}", code_at(&raw, Utc::now().timestamp() / STEP));
+        assert!(check_second_factor(&user._id, &code, &mongo, &redis).await);
+        assert!(!check_second_factor(&user._id, &code, &mongo, &redis).await);
+        mongo.database.drop().await.unwrap();
+    }
+}
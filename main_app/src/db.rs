@@ -17,7 +17,8 @@ impl FirstInit for MongoDb {
             "files".to_string(),
             "logined_devices".to_string(),
         ];
-        if compare_vec(&check_collection, &collection_list) {
+        //后来加的集合(比如blobs)不一定有，只要核心的几个在就算已有数据
+        if contains_all(&check_collection, &collection_list) {
             print!("载入已有数据...");
             return Ok(());
 /*             return Ok(self
@@ -66,7 +67,7 @@ impl FirstInit for MongoDb {
         };
         let _ = metadata_collection.insert_one(root).await;
 
-        crate::auth::lib::create_user("admin", "admin", "admin", self, &root_id)
+        crate::auth::lib::create_user("admin", "admin", "admin", shared_lib::db::models::UserRole::Admin, self, &root_id)
             .await
             .unwrap();
 
@@ -92,13 +93,6 @@ impl FirstInit for Redis {
         }
 }
 
-fn compare_vec(a: &[String], b: &[String]) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-    let mut a = a.to_owned();
-    let mut b = b.to_owned();
-    a.sort();
-    b.sort();
-    a == b
+fn contains_all(required: &[String], existing: &[String]) -> bool {
+    required.iter().all(|name| existing.contains(name))
 }
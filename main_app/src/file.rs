@@ -1,4 +1,8 @@
 pub mod routes;
 pub mod lib;
 pub mod storage_backend;
-pub mod share;
\ No newline at end of file
+pub mod share;
+pub mod upload_session;
+pub mod tus;
+pub mod archive;
+pub mod version;
@@ -0,0 +1,501 @@
+//文件夹打包下载
+//边读边写zip，不在磁盘上落临时文件，所以只用stored（不压缩）+ data descriptor
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileType};
+use crate::libs::ApiError;
+use rocket::futures::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use rocket::tokio::sync::Mutex;
+use rocket::Request;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use super::storage_backend::blob_storage::resolve_blob;
+use super::storage_backend::lib::StorageFactory;
+
+const ZIP64_LIMIT: u64 = 0xFFFFFFFF;
+
+pub struct ArchiveEntry {
+    pub path: String,
+    pub file: Option<File>, //None是文件夹
+    pub updated_at: i64,
+}
+
+//zip里的名字不能带路径分隔符，也不能是..
+fn sanitize_name(name: &str) -> String {
+    let name = name.replace(['/', '\\'], "_");
+    if name.is_empty() || name == "." || name == ".." {
+        "_".to_string()
+    } else {
+        name
+    }
+}
+
+//同一个文件夹里sanitize之后重名的（或者文件和文件夹同名），后面的加上编号，不然解压的时候会互相覆盖
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    if taken.insert(name.to_string()) {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut n = 1;
+    loop {
+        let candidate = format!("{} ({}){}", stem, n, ext);
+        if taken.insert(candidate.clone()) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+//按father往下找子文件，带上owner，children字段不可信，不能靠它把别人的文件打包进来
+//ref在这里就解析成母文件，visited防止数据坏了出现环的时候死循环
+pub async fn collect_entries(
+    folder: &File,
+    prefix: &str,
+    mongo: &MongoDb,
+    entries: &mut Vec<ArchiveEntry>,
+    visited: &mut HashSet<ObjectId>,
+) -> Result<(), ApiError> {
+    if !visited.insert(folder._id) {
+        return Ok(());
+    }
+    let collection = mongo.database.collection::<File>("files");
+    let children: Vec<File> = match collection.find(doc! { "father": folder._id, "owner": folder.owner }).sort(doc! { "created_at": 1, "_id": 1 }).await {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(children) => children,
+            Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+        },
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    let mut taken = HashSet::new();
+    for child in children {
+        if child._id == folder._id {
+            continue;
+        }
+        let path = format!("{}{}", prefix, unique_name(&sanitize_name(&child.name), &mut taken));
+        match child.type_ {
+            FileType::File => {
+                let updated_at = child.updated_at;
+                let file = resolve_blob(child, mongo).await?;
+                entries.push(ArchiveEntry {
+                    path,
+                    file: Some(file),
+                    updated_at,
+                });
+            }
+            FileType::Folder => {
+                entries.push(ArchiveEntry {
+                    path: format!("{}/", path),
+                    file: None,
+                    updated_at: child.updated_at,
+                });
+                Box::pin(collect_entries(&child, &format!("{}/", path), mongo, entries, visited)).await?;
+            }
+            FileType::Root => {}
+        }
+    }
+    Ok(())
+}
+
+fn dos_datetime(timestamp: i64) -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+    let time = chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    let dos_date = (((time.year() - 1980) as u16) << 9) | ((time.month() as u16) << 5) | time.day() as u16;
+    (dos_time, dos_date)
+}
+
+struct CentralRecord {
+    name: Vec<u8>,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    is_dir: bool,
+    zip64: bool,
+}
+
+struct ZipWriter<W> {
+    writer: W,
+    offset: u64,
+    records: Vec<CentralRecord>,
+}
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            records: vec![],
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(buf).await?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn add_dir(&mut self, path: &str, updated_at: i64) -> std::io::Result<()> {
+        let (time, date) = dos_datetime(updated_at);
+        let name = path.as_bytes().to_vec();
+        let offset = self.offset;
+        let zip64 = offset >= ZIP64_LIMIT;
+        let mut header = vec![];
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        header.extend_from_slice(&0x0800u16.to_le_bytes()); //utf-8文件名
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&[0u8; 12]); //crc和两个size都是0
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&name);
+        self.write(&header).await?;
+        self.records.push(CentralRecord {
+            name,
+            time,
+            date,
+            crc: 0,
+            size: 0,
+            offset,
+            is_dir: true,
+            zip64,
+        });
+        Ok(())
+    }
+
+    async fn add_file<R: AsyncRead + Unpin>(
+        &mut self,
+        path: &str,
+        updated_at: i64,
+        size: u64,
+        mut reader: R,
+    ) -> std::io::Result<()> {
+        let (time, date) = dos_datetime(updated_at);
+        let name = path.as_bytes().to_vec();
+        let offset = self.offset;
+        let zip64 = size >= ZIP64_LIMIT || offset >= ZIP64_LIMIT;
+        let mut header = vec![];
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        header.extend_from_slice(&0x0808u16.to_le_bytes()); //data descriptor + utf-8文件名
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        if zip64 {
+            header.extend_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+            header.extend_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+        } else {
+            header.extend_from_slice(&[0u8; 8]);
+        }
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 20u16 } else { 0u16 }).to_le_bytes());
+        header.extend_from_slice(&name);
+        if zip64 {
+            header.extend_from_slice(&0x0001u16.to_le_bytes());
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&[0u8; 16]);
+        }
+        self.write(&header).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut written: u64 = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            self.write(&buffer[..n]).await?;
+            written += n as u64;
+        }
+        if written != size {
+            //存储里的文件和metadata对不上，没法继续写了
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "size not match"));
+        }
+        let crc = hasher.finalize();
+
+        let mut descriptor = vec![];
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend_from_slice(&size.to_le_bytes());
+            descriptor.extend_from_slice(&size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        self.write(&descriptor).await?;
+        self.records.push(CentralRecord {
+            name,
+            time,
+            date,
+            crc,
+            size,
+            offset,
+            is_dir: false,
+            zip64,
+        });
+        Ok(())
+    }
+
+    async fn finish(mut self) -> std::io::Result<()> {
+        let central_offset = self.offset;
+        let records = std::mem::take(&mut self.records);
+        for record in &records {
+            let mut extra = vec![];
+            if record.zip64 {
+                let mut fields = vec![];
+                if record.size >= ZIP64_LIMIT || !record.is_dir {
+                    fields.extend_from_slice(&record.size.to_le_bytes());
+                    fields.extend_from_slice(&record.size.to_le_bytes());
+                }
+                fields.extend_from_slice(&record.offset.to_le_bytes());
+                extra.extend_from_slice(&0x0001u16.to_le_bytes());
+                extra.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+                extra.extend_from_slice(&fields);
+            }
+            let sizes_in_extra = record.zip64 && !record.is_dir;
+            let mut entry = vec![];
+            entry.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            entry.extend_from_slice(&(0x0300u16 | 45).to_le_bytes()); //unix
+            entry.extend_from_slice(&(if record.zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+            entry.extend_from_slice(&(if record.is_dir { 0x0800u16 } else { 0x0808u16 }).to_le_bytes());
+            entry.extend_from_slice(&0u16.to_le_bytes());
+            entry.extend_from_slice(&record.time.to_le_bytes());
+            entry.extend_from_slice(&record.date.to_le_bytes());
+            entry.extend_from_slice(&record.crc.to_le_bytes());
+            let size = if sizes_in_extra { 0xFFFFFFFF } else { record.size as u32 };
+            entry.extend_from_slice(&size.to_le_bytes());
+            entry.extend_from_slice(&size.to_le_bytes());
+            entry.extend_from_slice(&(record.name.len() as u16).to_le_bytes());
+            entry.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            entry.extend_from_slice(&0u16.to_le_bytes());
+            entry.extend_from_slice(&0u16.to_le_bytes());
+            entry.extend_from_slice(&0u16.to_le_bytes());
+            let mode: u32 = if record.is_dir { 0o40755 } else { 0o100644 };
+            let external = (mode << 16) | if record.is_dir { 0x10 } else { 0 };
+            entry.extend_from_slice(&external.to_le_bytes());
+            let offset = if record.zip64 { 0xFFFFFFFF } else { record.offset as u32 };
+            entry.extend_from_slice(&offset.to_le_bytes());
+            entry.extend_from_slice(&record.name);
+            entry.extend_from_slice(&extra);
+            self.write(&entry).await?;
+        }
+        let central_size = self.offset - central_offset;
+        let count = records.len() as u64;
+        let zip64 = count >= 0xFFFF || central_offset >= ZIP64_LIMIT || central_size >= ZIP64_LIMIT;
+        if zip64 {
+            let zip64_end_offset = self.offset;
+            let mut end = vec![];
+            end.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&(0x0300u16 | 45).to_le_bytes());
+            end.extend_from_slice(&45u16.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&central_size.to_le_bytes());
+            end.extend_from_slice(&central_offset.to_le_bytes());
+            end.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+            self.write(&end).await?;
+        }
+        let mut end = vec![];
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        let count16 = if zip64 { 0xFFFF } else { count as u16 };
+        end.extend_from_slice(&count16.to_le_bytes());
+        end.extend_from_slice(&count16.to_le_bytes());
+        end.extend_from_slice(&(if zip64 { 0xFFFFFFFF } else { central_size as u32 }).to_le_bytes());
+        end.extend_from_slice(&(if zip64 { 0xFFFFFFFF } else { central_offset as u32 }).to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.write(&end).await?;
+        self.writer.flush().await?;
+        self.writer.shutdown().await
+    }
+}
+
+async fn write_archive(
+    writer: DuplexStream,
+    entries: Vec<ArchiveEntry>,
+    factory: Arc<Mutex<StorageFactory>>,
+) -> std::io::Result<()> {
+    let mut zip = ZipWriter::new(writer);
+    for entry in entries {
+        match entry.file {
+            Some(file) => {
+                //拿锁只是为了clone一份factory，打开文件的时候不占着
+                let reader = {
+                    let factory = factory.lock().await.clone();
+                    factory.get_file_range(&file, 0, file.size).await
+                };
+                let reader = match reader {
+                    Ok(reader) => reader,
+                    Err(_) => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "file missing in storage"));
+                    }
+                };
+                zip.add_file(&entry.path, entry.updated_at, file.size, reader).await?;
+            }
+            None => zip.add_dir(&entry.path, entry.updated_at).await?,
+        }
+    }
+    zip.finish().await
+}
+
+pub struct ArchiveResponse {
+    response: Response<'static>,
+}
+
+impl<'r> Responder<'r, 'static> for ArchiveResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Ok(self.response)
+    }
+}
+
+impl ArchiveResponse {
+    pub async fn new(
+        folder: File,
+        factory: &Arc<Mutex<StorageFactory>>,
+        mongo: &MongoDb,
+    ) -> Result<Self, ApiError> {
+        let mut entries = vec![];
+        collect_entries(&folder, "", mongo, &mut entries, &mut HashSet::new()).await?;
+
+        let (reader, writer) = rocket::tokio::io::duplex(64 * 1024);
+        let factory = factory.clone();
+        rocket::tokio::spawn(async move {
+            //客户端断开的话写入会失败，直接丢掉就行
+            let _ = write_archive(writer, entries, factory).await;
+        });
+
+        let mut response = Response::build();
+        response
+            .header(ContentType::ZIP)
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.zip\"", sanitize_name(&folder.name)),
+            ))
+            .streamed_body(reader);
+        Ok(Self {
+            response: response.finalize(),
+        })
+    }
+}
+
+//用zip crate把写出来的包读回去对一遍
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    const UPDATED_AT: i64 = 1_700_000_000;
+
+    fn read_entry(archive: &mut zip::ZipArchive<impl std::io::Read + std::io::Seek>, name: &str) -> Vec<u8> {
+        let mut entry = archive.by_name(name).unwrap();
+        let mut content = vec![];
+        entry.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn duplicate_names_get_numbered() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_name("a.txt", &mut taken), "a.txt");
+        assert_eq!(unique_name("a.txt", &mut taken), "a (1).txt");
+        assert_eq!(unique_name("a.txt", &mut taken), "a (2).txt");
+        assert_eq!(unique_name("docs", &mut taken), "docs");
+        assert_eq!(unique_name("docs", &mut taken), "docs (1)");
+        assert_eq!(unique_name(".env", &mut taken), ".env");
+        assert_eq!(unique_name(".env", &mut taken), ".env (1)");
+    }
+
+    #[rocket::async_test]
+    async fn round_trip() {
+        let mut buffer = vec![];
+        let mut zip = ZipWriter::new(&mut buffer);
+        zip.add_dir("docs/", UPDATED_AT).await.unwrap();
+        zip.add_file("docs/a.txt", UPDATED_AT, 5, &b"hello"[..]).await.unwrap();
+        zip.add_file("empty", UPDATED_AT, 0, &b""[..]).await.unwrap();
+        zip.add_file("中文.txt", UPDATED_AT, 6, "你好".as_bytes()).await.unwrap();
+        zip.finish().await.unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).unwrap();
+        assert_eq!(archive.len(), 4);
+        assert!(archive.by_name("docs/").unwrap().is_dir());
+        assert_eq!(read_entry(&mut archive, "docs/a.txt"), b"hello");
+        assert_eq!(read_entry(&mut archive, "empty"), b"");
+        assert_eq!(read_entry(&mut archive, "中文.txt"), "你好".as_bytes());
+        let modified = archive.by_name("docs/a.txt").unwrap().last_modified().unwrap();
+        assert_eq!((modified.year(), modified.month(), modified.day()), (2023, 11, 14));
+    }
+
+    #[rocket::async_test]
+    async fn size_mismatch_fails() {
+        let mut buffer = vec![];
+        let mut zip = ZipWriter::new(&mut buffer);
+        assert!(zip.add_file("short", UPDATED_AT, 10, &b"hello"[..]).await.is_err());
+    }
+
+    //条目数超过16位，要靠zip64的end of central directory
+    #[rocket::async_test]
+    async fn more_than_65535_entries() {
+        const COUNT: usize = 70_000;
+        let mut buffer = vec![];
+        let mut zip = ZipWriter::new(&mut buffer);
+        for i in 0..COUNT {
+            let content = format!("{}", i % 10);
+            zip.add_file(&format!("f{}", i), UPDATED_AT, 1, content.as_bytes()).await.unwrap();
+        }
+        zip.finish().await.unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).unwrap();
+        assert_eq!(archive.len(), COUNT);
+        assert_eq!(read_entry(&mut archive, "f0"), b"0");
+        assert_eq!(read_entry(&mut archive, "f65536"), b"6");
+        assert_eq!(read_entry(&mut archive, &format!("f{}", COUNT - 1)), b"9");
+    }
+
+    //文件本身超过4GiB，后面那个文件的offset也超过4GiB
+    #[rocket::async_test]
+    #[ignore = "writes more than 4GiB to the temp dir"]
+    async fn zip64_sizes_and_offsets() {
+        const BIG: u64 = ZIP64_LIMIT + 4096;
+        let path = std::env::temp_dir().join(format!("rc_zip64_{}.zip", uuid::Uuid::new_v4()));
+        let file = rocket::tokio::fs::File::create(&path).await.unwrap();
+        let mut zip = ZipWriter::new(rocket::tokio::io::BufWriter::new(file));
+        zip.add_file("big", UPDATED_AT, BIG, rocket::tokio::io::repeat(0x5a).take(BIG)).await.unwrap();
+        zip.add_file("after", UPDATED_AT, 5, &b"after"[..]).await.unwrap();
+        zip.finish().await.unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(read_entry(&mut archive, "after"), b"after");
+        {
+            let mut big = archive.by_name("big").unwrap();
+            assert_eq!(big.size(), BIG);
+            //读完的时候zip crate会校验crc
+            assert_eq!(std::io::copy(&mut big, &mut std::io::sink()).unwrap(), BIG);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,21 +1,121 @@
 use crate::db::models::File;
-use crate::libs::ApiError;
-use crate::db::connect::MongoDb;
+use crate::auth::guard::AuthenticatedUser;
+use crate::libs::{check_file_permission, ApiError};
+use crate::db::connect::{MongoDb, Redis};
+use crate::MyConfig;
 use mongodb::bson::doc;
 use rocket::response;
 use rocket::response::Response;
 use rocket::response::Responder;
 use rocket::tokio::io::AsyncReadExt;
-use rocket::Request;
-use rocket::http::Header;
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::http::{Header, Status};
 
-use super::storage_backend::lib::StorageFactory;
-use std::str::FromStr;
+use super::storage_backend::blob_storage::resolve_blob;
+use super::storage_backend::lib::{FileStream, StorageFactory};
 use std::sync::Arc;
 use rocket::tokio::sync::Mutex;
 
+//下载时用到的条件请求头
+pub struct ConditionalHeaders {
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ConditionalHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let get = |name: &str| headers.get_one(name).map(|v| v.to_string());
+        Outcome::Success(ConditionalHeaders {
+            range: get("Range"),
+            if_range: get("If-Range"),
+            if_none_match: get("If-None-Match"),
+            if_modified_since: get("If-Modified-Since"),
+        })
+    }
+}
+
+//最多接受的range数量，再多就当没有Range头，直接给整个文件
+const MAX_RANGES: usize = 32;
+
+//None表示Range头无效，忽略掉；Some(Err)表示没有一个range能满足，要返回416
+//返回的区间是闭区间
+fn parse_range(header: &str, size: u64) -> Option<Result<Vec<(u64, u64)>, ()>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = vec![];
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        let range = if start.is_empty() {
+            //bytes=-n 最后n个字节
+            let suffix = end.parse::<u64>().ok()?;
+            if suffix == 0 || size == 0 {
+                None
+            } else {
+                Some((size.saturating_sub(suffix), size - 1))
+            }
+        } else {
+            let start = start.parse::<u64>().ok()?;
+            let end = if end.is_empty() {
+                u64::MAX
+            } else {
+                end.parse::<u64>().ok()?
+            };
+            if end < start {
+                return None;
+            }
+            if start >= size {
+                None
+            } else {
+                Some((start, end.min(size - 1)))
+            }
+        };
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    if ranges.is_empty() {
+        return Some(Err(()));
+    }
+    //重叠或者挨着的合成一个，同一段内容不发两遍
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(Ok(merged))
+}
+
+fn http_date(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|t| t.trim().trim_start_matches("W/"))
+        .any(|t| t == "*" || t == etag)
+}
+
 pub struct CustomFileResponse {
     response: Response<'static>,
+    //返回了文件内容（200或者206）就算一次下载，分享链接扣次数用
+    //只算从头开始的range的话，分段从第二个字节开始拿就能绕开次数限制
+    pub serves_body: bool,
 }
 
 impl<'r> Responder<'r, 'static> for CustomFileResponse {
@@ -25,31 +125,228 @@ impl<'r> Responder<'r, 'static> for CustomFileResponse {
 }
 
 impl CustomFileResponse {
-    pub async fn new(metadata: File, factory: &rocket::State<Arc<Mutex<StorageFactory>>>, mongodb: &rocket::State<MongoDb>) -> Result<Self, ApiError> {
-        let factory = factory.lock().await;
-        let ext = rocket::http::ContentType::from_extension(metadata.name.split('.').last().unwrap());
+    pub async fn new(metadata: File, conditional: &ConditionalHeaders, factory: &rocket::State<Arc<Mutex<StorageFactory>>>, mongodb: &rocket::State<MongoDb>) -> Result<Self, ApiError> {
+        let factory = factory.lock().await.clone();
+        let ext = rocket::http::ContentType::from_extension(metadata.name.split('.').next_back().unwrap());
+        let content_type = ext.unwrap_or(rocket::http::ContentType::Binary);
+        let name = metadata.name.clone();
+        let etag = format!("\"{}\"", metadata.sha256);
+        let last_modified = http_date(metadata.updated_at);
 
-        let metadata = if metadata.storage_type == "ref" {//因为storage backend并没有传入db实例，只能在这里处理ref了
-            let collection = mongodb.database.collection::<File>("files");
-            collection.find_one(doc! { "_id": mongodb::bson::oid::ObjectId::from_str(metadata.path.as_str()).unwrap() }).await.unwrap().unwrap()
-        } else {
-            metadata
-        };
+        let metadata = resolve_blob(metadata, mongodb).await?;
+        let size = metadata.size;
 
-        let file =  factory.get_file(&metadata).await?;
         let mut response = Response::build();
         response
-            .header(if let Some(ext) = ext { ext } else { rocket::http::ContentType::Binary })
-            .header(Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", metadata.name)))
-            .streamed_body(file);
-        Ok(Self { response: response.finalize() })
-        
+            .header(Header::new("Accept-Ranges", "bytes"))
+            .header(Header::new("ETag", etag.clone()))
+            .header(Header::new("Last-Modified", last_modified));
+
+        //If-None-Match优先，没有的时候才看If-Modified-Since
+        let not_modified = match (&conditional.if_none_match, &conditional.if_modified_since) {
+            (Some(if_none_match), _) => etag_matches(if_none_match, &etag),
+            (None, Some(since)) => match chrono::DateTime::parse_from_rfc2822(since) {
+                Ok(since) => metadata.updated_at <= since.timestamp(),
+                Err(_) => false,
+            },
+            _ => false,
+        };
+        if not_modified {
+            response.status(Status::NotModified);
+            return Ok(Self { response: response.finalize(), serves_body: false });
+        }
+
+        //If-Range对不上的话就当没有Range，给整个文件
+        let range_valid = match &conditional.if_range {
+            Some(if_range) if if_range.starts_with('"') || if_range.starts_with("W/") => *if_range == etag,
+            Some(if_range) => http_date(metadata.updated_at) == *if_range,
+            None => true,
+        };
+        let ranges = match &conditional.range {
+            Some(range) if range_valid => parse_range(range, size),
+            _ => None,
+        };
+
+        match ranges {
+            None => {
+                //走get_file_range，S3之类的后端也能直接流式返回
+                let file = factory.get_file_range(&metadata, 0, size).await?;
+                response
+                    .header(content_type)
+                    .header(Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", name)))
+                    .streamed_body(file);
+                Ok(Self { response: response.finalize(), serves_body: true })
+            }
+            Some(Err(_)) => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", size)));
+                Ok(Self { response: response.finalize(), serves_body: false })
+            }
+            Some(Ok(ranges)) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                let body = factory.get_file_range(&metadata, start, end - start + 1).await?;
+                response
+                    .status(Status::PartialContent)
+                    .header(content_type)
+                    .header(Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", name)))
+                    .header(Header::new("Content-Range", format!("bytes {}-{}/{}", start, end, size)))
+                    .streamed_body(body);
+                Ok(Self { response: response.finalize(), serves_body: true })
+            }
+            Some(Ok(ranges)) => {
+                //多个range用multipart/byteranges拼起来
+                let boundary = uuid::Uuid::new_v4().simple().to_string();
+                let mut body: FileStream = Box::pin(std::io::Cursor::new(Vec::new()));
+                for (start, end) in &ranges {
+                    let part_header = format!(
+                        "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, content_type, start, end, size
+                    );
+                    let part = factory.get_file_range(&metadata, *start, end - start + 1).await?;
+                    body = Box::pin(
+                        body.chain(std::io::Cursor::new(part_header.into_bytes()))
+                            .chain(part)
+                            .chain(std::io::Cursor::new(b"\r\n".to_vec())),
+                    );
+                }
+                body = Box::pin(body.chain(std::io::Cursor::new(format!("--{}--\r\n", boundary).into_bytes())));
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new("Content-Type", format!("multipart/byteranges; boundary={}", boundary)))
+                    .streamed_body(body);
+                Ok(Self { response: response.finalize(), serves_body: true })
+            }
+        }
+    }
+}
+
+//取出redis里暂存的metadata
+pub async fn load_staged_metadata(
+    uuid: &str,
+    user: &AuthenticatedUser,
+    redis: &Redis,
+    mongo: &MongoDb,
+) -> Result<File, ApiError> {
+    if !redis.exists(uuid).await {
+        return Err(ApiError::NotFound("Metadata not found".to_string().into()));
+    }
+    let metadata: String = redis.get(uuid).await;
+    let metadata: File = match serde_json::from_str(metadata.as_str()) {
+        Ok(metadata) => metadata,
+        Err(_) => return Err(ApiError::NotFound("Metadata not found".to_string().into())),
+    };
+    check_file_permission(user, &metadata, mongo).await?;
+    Ok(metadata)
+}
+
+//清掉暂存的metadata以及没传完的分片/tus数据
+pub async fn discard_staged_upload(uuid: &str, redis: &Redis, config: &MyConfig) {
+    let _: () = redis.delete(uuid).await;
+    let _: () = redis.delete(super::upload_session::routes::session_key(uuid)).await;
+    let _ = rocket::tokio::fs::remove_dir_all(super::upload_session::routes::session_dir(uuid, config)).await;
+    let _: () = redis.delete(super::tus::routes::tus_key(uuid)).await;
+    let _ = rocket::tokio::fs::remove_file(super::tus::routes::partial_path(uuid, config)).await;
+}
+
+//mongodb里写入上传完成的文件，并挂到父文件夹下
+//先按配额占上用量再写files，超了的话这次拿到的blob引用要还回去
+pub async fn commit_uploaded_file(
+    mongo: &MongoDb,
+    factory: &Mutex<StorageFactory>,
+    metadata: &File,
+    default_quota: u64,
+) -> Result<(), ApiError> {
+    if let Err(e) = crate::quota::lib::reserve(mongo, &metadata.owner, metadata.size, default_quota).await {
+        super::storage_backend::blob_storage::release(mongo, &factory.lock().await.clone(), &metadata.sha256).await?;
+        return Err(e);
+    }
+    let collection = mongo.database.collection::<File>("files");
+    if collection.insert_one(metadata).await.is_err() {
+        crate::quota::lib::charge(mongo, &metadata.owner, -(metadata.size as i64)).await;
+        return Err(ApiError::InternalServerError("Database error".to_string().into()));
+    }
+    let _ = collection
+        .update_one(
+            doc! { "_id": metadata.father },
+            doc! { "$push": { "children": metadata._id } },
+        )
+        .await;
+    Ok(())
+}
+
+//记下是哪个设备传的这一版，版本历史里要用
+pub async fn stamp_device(metadata: File, user: &AuthenticatedUser, mongo: &MongoDb) -> File {
+    let device = crate::auth::lib::current_device(user, mongo).await;
+    let mut extra_metadata = metadata.extra_metadata.clone().unwrap_or_default();
+    extra_metadata.device = device;
+    File {
+        extra_metadata: Some(extra_metadata),
+        ..metadata
     }
 }
 
-pub async fn get_file_type(file: &rocket::fs::TempFile<'_>) -> Option<infer::Type> {
-    let mut stream = file.open().await.unwrap();
+pub async fn get_local_file_type(path: &std::path::Path) -> Option<infer::Type> {
+    let mut stream = rocket::tokio::fs::File::open(path).await.ok()?;
     let mut buf = [0;512];
-    stream.read(&mut buf).await.unwrap();
-    infer::get(&buf)
-}
\ No newline at end of file
+    let n = stream.read(&mut buf).await.ok()?;
+    infer::get(&buf[..n])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Ok(vec![(0, 9)])));
+        //结尾超过文件大小的截到最后一个字节
+        assert_eq!(parse_range("bytes=90-200", 100), Some(Ok(vec![(90, 99)])));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok(vec![(90, 99)])));
+        assert_eq!(parse_range("bytes=-500", 100), Some(Ok(vec![(0, 99)])));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=-10", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn parses_open_ended_ranges() {
+        assert_eq!(parse_range("bytes=40-", 100), Some(Ok(vec![(40, 99)])));
+        assert_eq!(parse_range("bytes=99-", 100), Some(Ok(vec![(99, 99)])));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(parse_range("bytes=50-59, 0-9, 5-20", 100), Some(Ok(vec![(0, 20), (50, 59)])));
+        assert_eq!(parse_range("bytes=0-9,10-19", 100), Some(Ok(vec![(0, 19)])));
+        assert_eq!(parse_range("bytes=0-,-10", 100), Some(Ok(vec![(0, 99)])));
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let specs: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        assert_eq!(parse_range(&format!("bytes={}", specs.join(",")), 1000), None);
+        let specs = &specs[..MAX_RANGES];
+        assert_eq!(parse_range(&format!("bytes={}", specs.join(",")), 1000).unwrap().unwrap().len(), MAX_RANGES);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=200-300,150-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        //有一个能满足的就只给那一个
+        assert_eq!(parse_range("bytes=200-300,0-0", 100), Some(Ok(vec![(0, 0)])));
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        assert_eq!(parse_range("items=0-9", 100), None);
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=a-b", 100), None);
+        assert_eq!(parse_range("bytes=10", 100), None);
+    }
+}
@@ -7,19 +7,31 @@ use mongodb::bson::oid::ObjectId;
 use rocket::response::status;
 use std::str::FromStr;
 
-use super::lib::CustomFileResponse;
+use super::archive::ArchiveResponse;
+use super::lib::{ConditionalHeaders, CustomFileResponse};
 use super::storage_backend::lib::StorageFactory;
-use super::storage_backend::ref_storage;
+use super::storage_backend::blob_storage;
+use super::version::lib as version_lib;
+use crate::MyConfig;
+use crate::quota::lib::check_quota;
 use rocket::tokio::sync::Mutex;
 use std::sync::Arc;
 
+#[derive(Responder)]
+pub enum GetFileResponse {
+    File(CustomFileResponse),
+    Archive(ArchiveResponse),
+}
+
+//文件夹的话打包成zip
 #[get("/<uuid>")]
 pub async fn get_file(
     uuid: &str,
     user: AuthenticatedUser,
+    conditional: ConditionalHeaders,
     mongo: &rocket::State<MongoDb>,
     storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
-) -> Result<CustomFileResponse, ApiError> {
+) -> Result<GetFileResponse, ApiError> {
     let db = &mongo.database;
     let collection = db.collection::<File>("files");
 
@@ -29,10 +41,11 @@ pub async fn get_file(
 
     let metadata = mongo_error_check(metadata, Some("File"))?;
 
-    check_file_permission(&user, &metadata)?;
+    check_file_permission(&user, &metadata, mongo).await?;
 
     match metadata.type_ {
-        FileType::File => Ok(CustomFileResponse::new(metadata, storage_factory, mongo).await?),
+        FileType::File => Ok(GetFileResponse::File(CustomFileResponse::new(metadata, &conditional, storage_factory, mongo).await?)),
+        FileType::Folder => Ok(GetFileResponse::Archive(ArchiveResponse::new(metadata, storage_factory, mongo).await?)),
         _ => Err(ApiError::NotFound(
             "Target is not a file".to_string().into(),
         )),
@@ -46,6 +59,7 @@ pub async fn upload_file(
     mut file: TempFile<'_>,
     mongo: &rocket::State<MongoDb>,
     redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
     storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
 ) -> Result<status::NoContent, ApiError> {
     if !redis.exists(uuid).await {
@@ -53,33 +67,16 @@ pub async fn upload_file(
     };
     let metadata: String = redis.get(uuid).await;
     let metadata: File = serde_json::from_str(metadata.as_str()).unwrap();
-    check_file_permission(&user, &metadata)?;
-
-    let db = &mongo.database;
-    let collection = db.collection::<File>("files");
+    check_file_permission(&user, &metadata, mongo).await?;
 
-    let factory = storage_factory.lock().await;
+    let factory = storage_factory.lock().await.clone();
+    let sha256 = metadata.sha256.clone();
+    let metadata = blob_storage::store_temp_file(mongo, &factory, metadata, &sha256, &mut file).await?;
+    let metadata = super::lib::stamp_device(metadata, &user, mongo).await;
 
-    let file_type = super::lib::get_file_type(&file).await;
-    let save_result = factory
-        .check_sha256_and_save(&metadata, None, &mut file)
-        .await?;
-
-    let metadata = File {
-        size: save_result.size,
-        ..metadata
-    };
-    let _ = collection.insert_one(metadata.clone()).await;
-    let _ = collection
-        .update_one(
-            doc! { "_id": metadata.father },
-            doc! { "$push": { "children": metadata._id } },
-        )
-        .await;
+    super::lib::commit_uploaded_file(mongo, storage_factory, &metadata, config.default_quota).await?;
     let _: () = redis.delete(uuid).await;
-    match file_type {
-        _ => Ok(status::NoContent),
-    }
+    Ok(status::NoContent)
 }
 
 use rocket::form::Form;
@@ -97,6 +94,7 @@ pub async fn update_file(
     user: AuthenticatedUser,
     mut form: Form<UpdateFileRequest<'_>>,
     mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
     storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
 ) -> Result<status::NoContent, ApiError> {
     let db = &mongo.database;
@@ -107,7 +105,7 @@ pub async fn update_file(
             .await,
         Some("File"),
     )?;
-    check_file_permission(&user, &metadata)?;
+    check_file_permission(&user, &metadata, mongo).await?;
     match metadata.type_ {
         FileType::File => {}
         _ => {
@@ -116,48 +114,20 @@ pub async fn update_file(
             ));
         }
     }
-    //先看看原本是不是ref,是的话先清理原本的ref
-    if metadata.storage_type.as_str() == "ref" {
-        ref_storage::remove_ref(&collection, &metadata).await?;
-    }
-    //如果是ref_mother的话要change_mother
-    if let Some(ext) = &metadata.extra_metadata {
-        if ext.file_references.len() > 0 {
-            ref_storage::change_mother(&collection, &metadata).await?;
-        }
-    }
-    //看看新的是否可以ref
-    //遇到可以ref的就直接ref然后返回，不管传上来的是什么了
-    if let Some(ref_mother) = ref_storage::find_and_add_ref(&collection, &metadata).await?
-    {
-        let _ = collection
-            .update_one(
-                doc! { "_id": metadata._id },
-                doc! { "$set": doc! { "storage_type": "ref" },
-                            "$set": doc! { "path": ref_mother._id.to_hex() },
-                            "$set": doc! { "updated_at": chrono::Utc::now().timestamp() },
-                            "$set": doc! { "sha256": ref_mother.sha256.clone() },
-                            "$set": doc! { "size": ref_mother.size as i64 },
-                },
-            )
-            .await;
-        return Ok(status::NoContent);
-    }
-
-    let factory = storage_factory.lock().await;
-    let save_result = factory
-        .check_sha256_and_save(&metadata, Some(&form.sha256.clone()), &mut form.file)
-        .await?;
-
-    let _ = collection
-        .update_one(
-            doc! { "_id": metadata._id },
-            doc! { "$set": doc! { "sha256": save_result.sha256 },
-                    "$set": doc! { "updated_at": chrono::Utc::now().timestamp() },
-                    "$set": doc! { "size": save_result.size as i64 },
-            },
-        )
-        .await;
+    //旧内容会留成历史版本，新内容整个都要算进用量
+    check_quota(mongo, &user.uuid, form.file.len(), config.default_quota).await?;
+    //新内容存到原来那个backend
+    let factory = storage_factory.lock().await.clone();
+    let target = File {
+        storage_type: blob_storage::resolve_blob(metadata.clone(), mongo).await?.storage_type,
+        ..metadata.clone()
+    };
+    let sha256 = form.sha256.clone();
+    let updated = blob_storage::store_temp_file(mongo, &factory, target, &sha256, &mut form.file).await?;
+    let updated = super::lib::stamp_device(updated, &user, mongo).await;
+    blob_storage::replace_content(mongo, &factory, &metadata, &updated, config.default_quota).await?;
+    let policy = version_lib::user_policy(&user.uuid, mongo, &version_lib::default_policy(config)).await;
+    version_lib::prune_versions(&metadata._id, &policy, mongo, &factory).await?;
     Ok(status::NoContent)
 }
 
@@ -1 +1,3 @@
+pub mod guard;
+pub mod lib;
 pub mod routes;
\ No newline at end of file
@@ -0,0 +1,25 @@
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+
+pub const SHARE_TOKEN_HEADER: &str = "X-Share-Token";
+pub const SHARE_TOKEN_COOKIE: &str = "share_token";
+
+//分享会话的token，header优先，没有再看cookie，都没有就是None
+//cookie的path限定在这个链接下面，同时开几个链接也不会串
+pub struct ShareToken(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ShareToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if let Some(token) = request.headers().get_one(SHARE_TOKEN_HEADER) {
+            return Outcome::Success(ShareToken(Some(token.to_string())));
+        }
+        let token = request
+            .cookies()
+            .get(SHARE_TOKEN_COOKIE)
+            .map(|cookie| cookie.value().to_string());
+        Outcome::Success(ShareToken(token))
+    }
+}
@@ -0,0 +1,587 @@
+//分享链接以mongo里的share_links为准，redis只是缓存，丢了就从mongo重新读
+//缓存里整条记录序列化了放一个key，剩余下载次数单独一个key
+use crate::auth::lib::{hash_password, verify_password};
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::{File, FileRequest, FileType, ShareLink};
+use crate::libs::ApiError;
+use chrono::Utc;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::ReturnDocument;
+use mongodb::Collection;
+use redis::AsyncCommands;
+use rocket::futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareLinkInfo {
+    pub uuid: String,
+    pub target: String,
+    pub target_name: Option<String>, //目标已经删了就是None
+    pub created_at: i64,
+    pub expire_at: i64,
+    pub download_limit: i64,
+    pub remaining_downloads: i64, //-1是不限
+    pub has_password: bool,
+    pub file_request: Option<FileRequest>, //只能上传的链接才有
+}
+
+fn db_error<E>(_: E) -> ApiError {
+    ApiError::InternalServerError("Database error".to_string().into())
+}
+
+pub fn share_links(mongo: &MongoDb) -> Collection<ShareLink> {
+    mongo.database.collection::<ShareLink>("share_links")
+}
+
+pub fn link_key(uuid: &str) -> String {
+    format!("{}_share", uuid)
+}
+
+pub fn limit_key(uuid: &str) -> String {
+    format!("{}_limit", uuid)
+}
+
+//分享会话多久过期，不会超过链接本身的过期时间
+pub const SHARE_SESSION_SECONDS: i64 = 60 * 60;
+
+//用密码换来的会话，记下当时的密码hash，改了密码以后旧会话就不认了
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareSession {
+    pub link: String,
+    pub password: Option<String>,
+}
+
+fn session_key(token: &str) -> String {
+    format!("{}_share_session", token)
+}
+
+//空的当没设密码
+pub fn hash_share_password(password: Option<&str>) -> Option<String> {
+    password.filter(|p| !p.is_empty()).map(hash_password)
+}
+
+pub fn check_share_password(link: &ShareLink, password: &str) -> bool {
+    match &link.password {
+        Some(hash) => verify_password(password, hash),
+        None => true,
+    }
+}
+
+//返回token和过期时间
+pub async fn open_session(link: &ShareLink, redis: &Redis) -> (String, i64) {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let now = Utc::now().timestamp();
+    let expire_at = (now + SHARE_SESSION_SECONDS).min(link.expire_at.timestamp());
+    let session = ShareSession {
+        link: link.uuid.clone(),
+        password: link.password.clone(),
+    };
+    redis.set(session_key(&token), serde_json::to_string(&session).unwrap()).await;
+    redis.expire(session_key(&token), (expire_at - now).max(1)).await;
+    (token, expire_at)
+}
+
+pub async fn check_session(link: &ShareLink, token: &str, redis: &Redis) -> bool {
+    if !redis.exists(session_key(token)).await {
+        return false;
+    }
+    let session: String = redis.get(session_key(token)).await;
+    match serde_json::from_str::<ShareSession>(session.as_str()) {
+        Ok(session) => session.link == link.uuid && session.password == link.password,
+        Err(_) => false,
+    }
+}
+
+fn not_found() -> ApiError {
+    ApiError::NotFound("Link not found or expired".to_string().into())
+}
+
+//写进缓存，过期时间和mongo里的一样
+pub async fn cache_link(link: &ShareLink, redis: &Redis) {
+    redis.set(link_key(&link.uuid), serde_json::to_string(link).unwrap()).await;
+    redis.set(limit_key(&link.uuid), link.remaining_downloads).await;
+    //已经过期的话expire_at会报错，删掉就行
+    if redis.expire_at(link_key(&link.uuid), link.expire_at).await.is_err()
+        || redis.expire_at(limit_key(&link.uuid), link.expire_at).await.is_err()
+    {
+        uncache_link(&link.uuid, redis).await;
+    }
+}
+
+pub async fn uncache_link(uuid: &str, redis: &Redis) {
+    redis.delete(link_key(uuid)).await;
+    redis.delete(limit_key(uuid)).await;
+}
+
+pub async fn save_link(link: &ShareLink, mongo: &MongoDb, redis: &Redis) -> Result<(), ApiError> {
+    share_links(mongo)
+        .replace_one(doc! { "uuid": &link.uuid }, link)
+        .upsert(true)
+        .await
+        .map_err(db_error)?;
+    cache_link(link, redis).await;
+    Ok(())
+}
+
+//只$set改了的字段，剩余次数、收到的文件数这些是别的请求$inc的，不能拿手上的旧记录整个盖回去
+pub async fn update_link(uuid: &str, fields: Document, mongo: &MongoDb, redis: &Redis) -> Result<ShareLink, ApiError> {
+    let collection = share_links(mongo);
+    let link = if fields.is_empty() {
+        collection.find_one(doc! { "uuid": uuid }).await
+    } else {
+        collection
+            .find_one_and_update(doc! { "uuid": uuid }, doc! { "$set": fields })
+            .return_document(ReturnDocument::After)
+            .await
+    };
+    let link = link.map_err(db_error)?.ok_or_else(not_found)?;
+    cache_link(&link, redis).await;
+    Ok(link)
+}
+
+//先查缓存，没有再去mongo找，找到了顺便放回缓存
+//TTL索引不是到点就删的，所以mongo这边还要自己比一下时间
+pub async fn load_link(uuid: &str, mongo: &MongoDb, redis: &Redis) -> Result<Option<ShareLink>, ApiError> {
+    if redis.exists(link_key(uuid)).await {
+        let link: String = redis.get(link_key(uuid)).await;
+        if let Ok(mut link) = serde_json::from_str::<ShareLink>(link.as_str()) {
+            if redis.exists(limit_key(uuid)).await {
+                link.remaining_downloads = redis.get(limit_key(uuid)).await;
+            }
+            return Ok(Some(link));
+        }
+    }
+    let link = share_links(mongo)
+        .find_one(doc! { "uuid": uuid, "expire_at": { "$gt": Utc::now() } })
+        .await
+        .map_err(db_error)?;
+    if let Some(link) = &link {
+        cache_link(link, redis).await;
+    }
+    Ok(link)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: FileType,
+    pub size: u64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareFolderListing {
+    pub name: String,
+    pub remaining_downloads: i64,
+    pub children: Vec<ShareEntry>,
+}
+
+//按名字从分享的那个文件夹往下找，按father和分享人查，不看children，所以出不了分享的范围
+//空路径就是分享的目标本身
+pub async fn resolve_path(link: &ShareLink, path: &str, mongo: &MongoDb) -> Result<File, ApiError> {
+    let collection = mongo.database.collection::<File>("files");
+    let mut current = match collection
+        .find_one(doc! { "_id": link.target, "owner": link.owner })
+        .await
+        .map_err(db_error)?
+    {
+        Some(file) => file,
+        None => return Err(ApiError::NotFound("File not found".to_string().into())),
+    };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if name == "." || name == ".." {
+            return Err(ApiError::BadRequest("Invalid path".to_string().into()));
+        }
+        if current.type_ != FileType::Folder {
+            return Err(ApiError::NotFound("File not found".to_string().into()));
+        }
+        current = match collection
+            .find_one(doc! { "father": current._id, "_id": { "$ne": current._id }, "name": name, "owner": link.owner })
+            .await
+            .map_err(db_error)?
+        {
+            Some(file) => file,
+            None => return Err(ApiError::NotFound("File not found".to_string().into())),
+        };
+    }
+    Ok(current)
+}
+
+pub async fn list_children(link: &ShareLink, folder: &File, mongo: &MongoDb) -> Result<Vec<ShareEntry>, ApiError> {
+    let children: Vec<File> = mongo
+        .database
+        .collection::<File>("files")
+        .find(doc! { "father": folder._id, "_id": { "$ne": folder._id }, "owner": link.owner })
+        .sort(doc! { "type": -1, "name": 1 })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    Ok(children
+        .into_iter()
+        .map(|child| ShareEntry {
+            name: child.name,
+            type_: child.type_,
+            size: child.size,
+            updated_at: child.updated_at,
+        })
+        .collect())
+}
+
+pub fn remaining_downloads(link: &ShareLink) -> i64 {
+    if link.download_limit < 0 {
+        return -1;
+    }
+    link.remaining_downloads.max(0)
+}
+
+//发内容之前先扣一次，不限次数的不用扣
+//在mongo里原子地减，没减成说明次数已经用完了（缓存里的可能是旧的），直接拒绝
+pub async fn consume_download(link: &ShareLink, mongo: &MongoDb, redis: &Redis) -> Result<(), ApiError> {
+    if link.download_limit < 0 {
+        return Ok(());
+    }
+    let updated = share_links(mongo)
+        .find_one_and_update(
+            doc! { "uuid": &link.uuid, "remaining_downloads": { "$gt": 0 } },
+            doc! { "$inc": { "remaining_downloads": -1 } },
+        )
+        .return_document(ReturnDocument::After)
+        .await
+        .map_err(db_error)?;
+    let remaining = updated.as_ref().map(|link| link.remaining_downloads).unwrap_or(0);
+    redis.set(limit_key(&link.uuid), remaining).await;
+    let _ = redis.expire_at(limit_key(&link.uuid), link.expire_at).await;
+    match updated {
+        Some(_) => Ok(()),
+        None => Err(ApiError::BadRequest("Download limit reached".to_string().into())),
+    }
+}
+
+//扣了次数但最后没发内容（304、416或者出错了），把次数还回去
+pub async fn refund_download(link: &ShareLink, mongo: &MongoDb, redis: &Redis) {
+    if link.download_limit < 0 {
+        return;
+    }
+    let updated = share_links(mongo)
+        .find_one_and_update(doc! { "uuid": &link.uuid }, doc! { "$inc": { "remaining_downloads": 1 } })
+        .return_document(ReturnDocument::After)
+        .await;
+    if let Ok(Some(updated)) = updated {
+        redis.set(limit_key(&link.uuid), updated.remaining_downloads).await;
+        let _ = redis.expire_at(limit_key(&link.uuid), link.expire_at).await;
+    }
+}
+
+//文件请求收文件之前先看一下还有没有余量，真正占名额在reserve_upload
+pub fn check_request_limits(request: &FileRequest, size: u64) -> Result<(), ApiError> {
+    if request.max_files > 0 && request.received_files >= request.max_files {
+        return Err(ApiError::Forbidden("File count limit reached".to_string().into()));
+    }
+    if request.max_bytes > 0 && request.received_bytes + size > request.max_bytes {
+        return Err(ApiError::Forbidden("Size limit reached".to_string().into()));
+    }
+    Ok(())
+}
+
+//在mongo里原子地占上一个文件和size这么多字节，满了就报错
+pub async fn reserve_upload(link: &ShareLink, size: u64, mongo: &MongoDb, redis: &Redis) -> Result<(), ApiError> {
+    let request = match &link.file_request {
+        Some(request) => request,
+        None => return Err(ApiError::BadRequest("Link does not accept uploads".to_string().into())),
+    };
+    check_request_limits(request, size)?;
+    let mut filter = doc! { "uuid": &link.uuid };
+    if request.max_files > 0 {
+        filter.insert("file_request.received_files", doc! { "$lt": request.max_files as i64 });
+    }
+    if request.max_bytes > 0 {
+        filter.insert("file_request.received_bytes", doc! { "$lte": (request.max_bytes - size) as i64 });
+    }
+    let updated = share_links(mongo)
+        .find_one_and_update(
+            filter,
+            doc! { "$inc": { "file_request.received_files": 1, "file_request.received_bytes": size as i64 } },
+        )
+        .return_document(ReturnDocument::After)
+        .await
+        .map_err(db_error)?;
+    match updated {
+        Some(updated) => {
+            cache_link(&updated, redis).await;
+            Ok(())
+        }
+        None => Err(ApiError::Forbidden("Upload limit reached".to_string().into())),
+    }
+}
+
+//上传失败的话把占的名额还回去
+pub async fn release_upload(link: &ShareLink, size: u64, mongo: &MongoDb, redis: &Redis) {
+    let updated = share_links(mongo)
+        .find_one_and_update(
+            doc! { "uuid": &link.uuid },
+            doc! { "$inc": { "file_request.received_files": -1, "file_request.received_bytes": -(size as i64) } },
+        )
+        .return_document(ReturnDocument::After)
+        .await;
+    if let Ok(Some(updated)) = updated {
+        cache_link(&updated, redis).await;
+    }
+}
+
+//在文件的metadata里记上是哪个链接传上来的
+pub fn attribute_to_link(metadata: File, uuid: &str) -> File {
+    let mut extra_metadata = metadata.extra_metadata.clone().unwrap_or_default();
+    extra_metadata.share_link = Some(uuid.to_string());
+    File {
+        extra_metadata: Some(extra_metadata),
+        ..metadata
+    }
+}
+
+//只有自己建的链接能看能改，别人的当不存在
+pub async fn owned_link(uuid: &str, owner: &ObjectId, mongo: &MongoDb, redis: &Redis) -> Result<ShareLink, ApiError> {
+    match load_link(uuid, mongo, redis).await? {
+        Some(link) if link.owner == *owner => Ok(link),
+        _ => Err(not_found()),
+    }
+}
+
+pub async fn revoke_link(link: &ShareLink, mongo: &MongoDb, redis: &Redis) -> Result<(), ApiError> {
+    share_links(mongo)
+        .delete_one(doc! { "uuid": &link.uuid })
+        .await
+        .map_err(db_error)?;
+    uncache_link(&link.uuid, redis).await;
+    Ok(())
+}
+
+//删用户的时候用，分享链接和文件请求都在这里，缓存也一起清掉
+pub async fn revoke_user_links(owner: &ObjectId, mongo: &MongoDb, redis: &Redis) -> Result<(), ApiError> {
+    let links: Vec<ShareLink> = share_links(mongo)
+        .find(doc! { "owner": owner })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    share_links(mongo)
+        .delete_many(doc! { "owner": owner })
+        .await
+        .map_err(db_error)?;
+    for link in links {
+        uncache_link(&link.uuid, redis).await;
+    }
+    Ok(())
+}
+
+pub async fn list_links(owner: &ObjectId, mongo: &MongoDb) -> Result<Vec<ShareLink>, ApiError> {
+    share_links(mongo)
+        .find(doc! { "owner": owner, "expire_at": { "$gt": Utc::now() } })
+        .sort(doc! { "created_at": -1 })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)
+}
+
+pub async fn link_info(link: &ShareLink, mongo: &MongoDb) -> ShareLinkInfo {
+    let target_name = mongo
+        .database
+        .collection::<File>("files")
+        .find_one(doc! { "_id": link.target })
+        .await
+        .ok()
+        .flatten()
+        .map(|file| file.name);
+    ShareLinkInfo {
+        uuid: link.uuid.clone(),
+        target: link.target.to_hex(),
+        target_name,
+        created_at: link.created_at,
+        expire_at: link.expire_at.timestamp(),
+        download_limit: link.download_limit,
+        remaining_downloads: remaining_downloads(link),
+        has_password: link.password.is_some(),
+        file_request: link.file_request.clone(),
+    }
+}
+
+//以前的链接只在redis里: <uuid>是目标文件id，<uuid>_limit剩余次数，<uuid>_password明文密码，过期靠TTL
+//升级的时候搬进share_links，不然发出去的链接全失效了；新链接也有<uuid>_limit，靠mongo里有没有记录区分
+pub async fn import_legacy_links(mongo: &MongoDb, redis: &Redis) -> Result<(), mongodb::error::Error> {
+    let mut con = redis.get_connection().await;
+    let mut uuids = vec![];
+    match con.scan_match::<_, String>("*_limit").await {
+        Ok(mut keys) => {
+            while let Some(key) = keys.next_item().await {
+                if let Some(uuid) = key.strip_suffix("_limit") {
+                    uuids.push(uuid.to_string());
+                }
+            }
+        }
+        Err(e) => {
+            warn!("读取旧分享链接失败: {}", e);
+            return Ok(());
+        }
+    }
+    let collection = share_links(mongo);
+    let mut imported = 0;
+    for uuid in uuids {
+        if collection.find_one(doc! { "uuid": &uuid }).await?.is_some() {
+            continue;
+        }
+        let target: Option<String> = con.get(&uuid).await.unwrap_or(None);
+        let target = match target.and_then(|t| ObjectId::parse_str(t).ok()) {
+            Some(target) => target,
+            None => continue,
+        };
+        let ttl: i64 = con.ttl(&uuid).await.unwrap_or(-2);
+        let limit: Option<i64> = con.get(limit_key(&uuid)).await.unwrap_or(None);
+        let password: Option<String> = con.get(format!("{}_password", uuid)).await.unwrap_or(None);
+        let file = mongo.database.collection::<File>("files").find_one(doc! { "_id": target }).await?;
+        //-2是刚好过期了，目标删了的也不要了
+        if let (Some(file), true) = (file, ttl != -2) {
+            let now = Utc::now();
+            let link = ShareLink {
+                _id: ObjectId::new(),
+                uuid: uuid.clone(),
+                owner: file.owner,
+                target,
+                created_at: now.timestamp(),
+                //-1是没设过期，当成很久以后
+                expire_at: now + chrono::Duration::seconds(if ttl < 0 { 100 * 365 * 24 * 60 * 60 } else { ttl }),
+                download_limit: limit.unwrap_or(-1),
+                remaining_downloads: limit.unwrap_or(-1),
+                password: hash_share_password(password.as_deref()),
+                file_request: None,
+            };
+            collection.insert_one(&link).await?;
+            imported += 1;
+        }
+        redis.delete(&uuid).await;
+        redis.delete(limit_key(&uuid)).await;
+        redis.delete(format!("{}_password", uuid)).await;
+    }
+    if imported > 0 {
+        info!("已导入{}个旧分享链接", imported);
+    }
+    Ok(())
+}
+
+//启动的时候建TTL索引（老库里没有），把redis里的旧链接搬过来，再把没过期的链接都放回redis
+pub async fn init_share_links(mongo: &MongoDb, redis: &Redis) -> Result<(), mongodb::error::Error> {
+    let collection = share_links(mongo);
+    let index_model = mongodb::IndexModel::builder()
+        .keys(doc! { "expire_at": 1 })
+        .options(
+            mongodb::options::IndexOptions::builder()
+                .expire_after(std::time::Duration::from_secs(1))
+                .build(),
+        )
+        .build();
+    collection.create_index(index_model).await?;
+    let index_model = mongodb::IndexModel::builder()
+        .keys(doc! { "uuid": 1 })
+        .options(mongodb::options::IndexOptions::builder().unique(true).build())
+        .build();
+    collection.create_index(index_model).await?;
+    import_legacy_links(mongo, redis).await?;
+    //以前的密码是明文存的，启动的时候换成hash
+    let mut cursor = collection.find(doc! { "password": { "$type": "string" } }).await?;
+    while cursor.advance().await? {
+        let link = cursor.deserialize_current()?;
+        if let Some(password) = link.password.filter(|p| !p.starts_with("$argon2")) {
+            collection
+                .update_one(
+                    doc! { "_id": link._id },
+                    doc! { "$set": { "password": hash_share_password(Some(&password)) } },
+                )
+                .await?;
+        }
+    }
+    let mut cursor = collection.find(doc! { "expire_at": { "$gt": Utc::now() } }).await?;
+    while cursor.advance().await? {
+        let link = cursor.deserialize_current()?;
+        cache_link(&link, redis).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev and redis_dev services from compose.yaml"]
+    async fn legacy_redis_links_are_imported() {
+        let mongo = test_support::mongo().await;
+        let redis = test_support::redis().await;
+        let user = test_support::user(&mongo).await;
+        let file = test_support::file(&mongo, "a.txt", &user.root_id, &user.uuid, 1).await;
+        let uuid = uuid::Uuid::new_v4().to_string();
+        redis.set(&uuid, file._id.to_hex()).await;
+        redis.expire(&uuid, 3600).await;
+        redis.set(format!("{}_limit", uuid), 3).await;
+        redis.set(format!("{}_password", uuid), "secret").await;
+        //目标已经删了的直接丢掉
+        let orphan = uuid::Uuid::new_v4().to_string();
+        redis.set(&orphan, ObjectId::new().to_hex()).await;
+        redis.set(format!("{}_limit", orphan), 1).await;
+
+        init_share_links(&mongo, &redis).await.unwrap();
+
+        let link = share_links(&mongo).find_one(doc! { "uuid": &uuid }).await.unwrap().unwrap();
+        assert_eq!(link.owner, user.uuid);
+        assert_eq!(link.target, file._id);
+        assert_eq!(link.remaining_downloads, 3);
+        assert!(check_share_password(&link, "secret"));
+        assert!(!check_share_password(&link, "wrong"));
+        assert!(link.expire_at > Utc::now() + chrono::Duration::seconds(3000));
+        assert!(!redis.exists(&uuid).await);
+        assert!(!redis.exists(format!("{}_password", uuid)).await);
+        assert!(redis.exists(link_key(&uuid)).await);
+        assert!(share_links(&mongo).find_one(doc! { "uuid": &orphan }).await.unwrap().is_none());
+        assert!(!redis.exists(&orphan).await);
+        mongo.database.drop().await.unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev and redis_dev services from compose.yaml"]
+    async fn update_keeps_counters_changed_since_load() {
+        let mongo = test_support::mongo().await;
+        let redis = test_support::redis().await;
+        let user = test_support::user(&mongo).await;
+        let file = test_support::file(&mongo, "a.txt", &user.root_id, &user.uuid, 1).await;
+        let now = Utc::now();
+        let link = ShareLink {
+            _id: ObjectId::new(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            owner: user.uuid,
+            target: file._id,
+            created_at: now.timestamp(),
+            expire_at: now + chrono::Duration::seconds(3600),
+            download_limit: 3,
+            remaining_downloads: 3,
+            password: None,
+            file_request: None,
+        };
+        save_link(&link, &mongo, &redis).await.unwrap();
+        //改密码的请求拿到记录之后，别人下载了一次
+        consume_download(&link, &mongo, &redis).await.unwrap();
+
+        let fields = doc! { "password": hash_share_password(Some("secret")) };
+        let updated = update_link(&link.uuid, fields, &mongo, &redis).await.unwrap();
+        assert_eq!(updated.remaining_downloads, 2);
+        assert!(check_share_password(&updated, "secret"));
+        let cached = load_link(&link.uuid, &mongo, &redis).await.unwrap().unwrap();
+        assert_eq!(cached.remaining_downloads, 2);
+        uncache_link(&link.uuid, &redis).await;
+        mongo.database.drop().await.unwrap();
+    }
+}
@@ -1,17 +1,34 @@
 use crate::auth::guard::AuthenticatedUser;
 use crate::db::connect::{MongoDb, Redis};
 use crate::db::models::{File, FileType};
-use crate::libs::{check_file_permission, mongo_error_check, ApiError};
+use crate::libs::{check_file_permission, mongo_error_check, parse_object_id, ApiError};
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
-use mongodb::Collection;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
 use rocket::serde::json::Json;
 
-use super::super::storage_backend::lib::StorageFactory;
+use super::super::storage_backend::lib::{pick_storage_type, StorageFactory};
 use std::sync::Arc;
 use rocket::tokio::sync::Mutex;
+use crate::rate_limit::guard::ClientIp;
+use crate::rate_limit::lib as rate_limit;
+use crate::MyConfig;
+use super::lib::{
+    consume_download, link_info, list_children, list_links, load_link, owned_link, refund_download, remaining_downloads,
+    resolve_path, revoke_link, save_link, update_link, ShareFolderListing, ShareLinkInfo,
+};
+use super::lib::{attribute_to_link, check_request_limits, release_upload, reserve_upload};
+use super::lib::{check_session, check_share_password, hash_share_password, open_session};
+use super::guard::{ShareToken, SHARE_TOKEN_COOKIE};
+use rocket::http::{Cookie, CookieJar, SameSite};
+use super::super::lib::commit_uploaded_file;
+use super::super::storage_backend::blob_storage;
+use crate::db::models::{FileRequest, ShareLink};
+use crate::file_metadata::routes::MetaDataCreateResponse;
+use crate::quota::lib::check_quota;
+use rocket::fs::TempFile;
+use rocket::response::status;
+use chrono::{Duration, Utc};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CrateShareLinkRequest<'r> {
@@ -39,23 +56,89 @@ pub async  fn crate_share_link(
     let db = &mongo.database;
     let collection = db.collection::<File>("files");
     
-    let metadata = collection.find_one(doc! { "_id": ObjectId::from_str(request.target_uuid).unwrap() }).await;
+    let metadata = collection.find_one(doc! { "_id": parse_object_id(request.target_uuid)? }).await;
     let metadata = mongo_error_check(metadata, Some("File"))?;
 
-    check_file_permission(&user, &metadata)?;
+    check_file_permission(&user, &metadata, mongo).await?;
+    if request.live_second <= 0 {
+        return Err(ApiError::BadRequest("live_second must be positive".to_string().into()));
+    }
 
     let share_link_uuid = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let download_limit = request.download_count_limit.max(-1);
+    let link = ShareLink {
+        _id: ObjectId::new(),
+        uuid: share_link_uuid.clone(),
+        owner: user.uuid,
+        target: metadata._id,
+        created_at: now.timestamp(),
+        expire_at: now + Duration::seconds(request.live_second),
+        download_limit,
+        remaining_downloads: download_limit,
+        password: hash_share_password(request.password),
+        file_request: None,
+    };
+    save_link(&link, mongo, redis).await?;
+
+    Ok(Json(CrateShareLinkResponse {
+        status: "Success".to_string(),
+        link: share_link_uuid,
+        info: "".to_string(),
+    }))
+}
 
-    let _: () = redis.set(&share_link_uuid, &metadata._id.to_string()).await;
-    let _: () = redis.expire(&share_link_uuid, request.live_second).await;
-    let _: () = redis.set(format!("{}_limit", share_link_uuid), &request.download_count_limit).await;
-    let _: () = redis.expire(format!("{}_limit", share_link_uuid), request.live_second).await;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrateFileRequestRequest<'r> {
+    target_uuid: &'r str, //收到的文件放在这个文件夹
+    live_second: i64,
+    password: Option<&'r str>,
+    #[serde(default)]
+    max_files: u64, //0 for no limit
+    #[serde(default)]
+    max_bytes: u64, //0 for no limit
+}
+
+//文件请求：拿到链接的人只能往文件夹里传文件，看不到也下载不了
+#[post("/crate_request", data = "<request>")]
+pub async fn crate_file_request(
+    request: Json<CrateFileRequestRequest<'_>>,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<Json<CrateShareLinkResponse>, ApiError> {
+    let collection = mongo.database.collection::<File>("files");
+    let metadata = collection.find_one(doc! { "_id": parse_object_id(request.target_uuid)? }).await;
+    let metadata = mongo_error_check(metadata, Some("File"))?;
 
-    if request.password.is_some() {
-        let _: () = redis.set(format!("{}_password", share_link_uuid).as_str(), request.password.unwrap()).await;
-        let _: () = redis.expire(format!("{}_password", share_link_uuid).as_str(), request.live_second).await;
+    check_file_permission(&user, &metadata, mongo).await?;
+    if metadata.type_ != FileType::Folder {
+        return Err(ApiError::BadRequest("Target is not a folder".to_string().into()));
+    }
+    if request.live_second <= 0 {
+        return Err(ApiError::BadRequest("live_second must be positive".to_string().into()));
     }
 
+    let share_link_uuid = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let link = ShareLink {
+        _id: ObjectId::new(),
+        uuid: share_link_uuid.clone(),
+        owner: user.uuid,
+        target: metadata._id,
+        created_at: now.timestamp(),
+        expire_at: now + Duration::seconds(request.live_second),
+        download_limit: -1,
+        remaining_downloads: -1,
+        password: hash_share_password(request.password),
+        file_request: Some(FileRequest {
+            max_files: request.max_files,
+            max_bytes: request.max_bytes,
+            ..Default::default()
+        }),
+    };
+    save_link(&link, mongo, redis).await?;
+
     Ok(Json(CrateShareLinkResponse {
         status: "Success".to_string(),
         link: share_link_uuid,
@@ -63,105 +146,367 @@ pub async  fn crate_share_link(
     }))
 }
 
-use super::super::lib::CustomFileResponse;
+use super::super::archive::ArchiveResponse;
+use super::super::lib::{ConditionalHeaders, CustomFileResponse};
 
 #[derive(Responder)]
 pub enum GetFileResponse {
-    #[response(status = 200)]
     File(CustomFileResponse),
+    Archive(ArchiveResponse),
     #[response(status = 200)]
     Metadata(Json<File>),
 }
 
-async fn path_find(mut path: Vec<&str>, file_metadata: File, collation: Collection<File>) -> Result<File, ApiError> {
-    if path.is_empty() {
-        return Ok(file_metadata);
-    }
-    let t = path.pop().unwrap();
-    if !file_metadata.children.contains(&ObjectId::from_str(t).unwrap()) {
-        return Err(ApiError::NotFound("File not found".to_string().into()));
+//有密码的链接要先用密码换会话token，之后的请求带token
+async fn unlock_link(
+    uuid: &str,
+    token: &ShareToken,
+    mongo: &MongoDb,
+    redis: &Redis,
+) -> Result<ShareLink, ApiError> {
+    //撤销了就是记录没了，这里每次都现读
+    let link = match load_link(uuid, mongo, redis).await? {
+        Some(link) => link,
+        None => return Err(ApiError::NotFound("Link not found or expired".to_string().into())),
+    };
+    if link.password.is_some() {
+        let valid = match &token.0 {
+            Some(token) => check_session(&link, token, redis).await,
+            None => false,
+        };
+        if !valid {
+            return Err(ApiError::Unauthorized("Share password required".to_string().into()));
+        }
     }
+    Ok(link)
+}
 
-    let file_metadata = collation.find_one(doc! { "_id": ObjectId::from_str(t).unwrap() }).await;
-    let file_metadata = mongo_error_check(file_metadata, Some("File"))?;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareSessionRequest {
+    pub password: String,
+}
 
-    Box::pin(async move {
-        path_find(path, file_metadata, collation).await
-    }).await
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareSessionResponse {
+    pub token: String,
+    pub expire_at: i64,
 }
 
-#[get("/<uuid>?<path>&<password>&<metadata>")]
+//密码放在body里换token，token在响应里给一份，也种到cookie里
+//输错按分享链接计数防爆破
+#[post("/<uuid>/session", data = "<request>")]
+pub async fn open_share_session(
+    uuid: &str,
+    request: Json<ShareSessionRequest>,
+    remote: ClientIp,
+    cookies: &CookieJar<'_>,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<ShareSessionResponse>, ApiError> {
+    let link = match load_link(uuid, mongo, redis).await? {
+        Some(link) => link,
+        None => return Err(ApiError::NotFound("Link not found or expired".to_string().into())),
+    };
+    if link.password.is_some() {
+        let counters = rate_limit::counters("share", remote.0, uuid, config);
+        rate_limit::check_locked(redis, &counters).await?;
+
+        if !check_share_password(&link, &request.password) {
+            rate_limit::record_failure(redis, config, &counters).await;
+            return Err(ApiError::BadRequest("Wrong password".to_string().into()));
+        }
+        rate_limit::record_success(redis, &counters).await;
+    }
+    let (token, expire_at) = open_session(&link, redis).await;
+    cookies.add(
+        Cookie::build((SHARE_TOKEN_COOKIE, token.clone()))
+            .path(format!("/file/share/{}", uuid))
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(rocket::time::Duration::seconds(expire_at - Utc::now().timestamp())),
+    );
+    Ok(Json(ShareSessionResponse { token, expire_at }))
+}
+
+#[get("/<uuid>?<path>&<metadata>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn get_share_file(
     uuid: &str,
     path: Option<&str>,
-    password: Option<&str>,
     metadata: Option<bool>,
+    token: ShareToken,
+    conditional: ConditionalHeaders,
     mongo: &rocket::State<MongoDb>,
     redis: &rocket::State<Redis>,
     storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
 ) -> Result<GetFileResponse, ApiError> {
+    let link = unlock_link(uuid, &token, mongo, redis).await?;
+    if link.file_request.is_some() {
+        return Err(ApiError::Forbidden("Link only accepts uploads".to_string().into()));
+    }
 
-    let db = &mongo.database;
-    let collection = db.collection::<File>("files");
-    if !redis.exists(&uuid).await {
-        return Err(ApiError::NotFound("Link not found or expired".to_string().into()));
+    if remaining_downloads(&link) == 0 {
+        return Err(ApiError::BadRequest("Download limit reached".to_string().into()));
     }
-    if redis.exists(format!("{}_password", uuid).as_str()).await {
-        let true_password: String = redis.get(format!("{}_password", uuid).as_str()).await;
+    let file_metadata = resolve_path(&link, path.unwrap_or(""), mongo).await?;
 
-        if true_password.is_empty() || password.is_none() {
-            return Err(ApiError::BadRequest("Wrong password".to_string().into()));
-        }
+    if metadata.unwrap_or(false) {
+        return Ok(GetFileResponse::Metadata(Json(file_metadata)));
+    }
 
-        if true_password != password.unwrap() {
-            return Err(ApiError::BadRequest("Wrong password".to_string().into()));
+    //文件夹里单个文件的下载也算一次
+    //先扣次数再生成响应，没扣成就不发内容，最后没发内容的再还回去
+    match file_metadata.type_ {
+        FileType::File => {
+            consume_download(&link, mongo, redis).await?;
+            match CustomFileResponse::new(file_metadata, &conditional, storage_factory, mongo).await {
+                Ok(response) => {
+                    if !response.serves_body {
+                        refund_download(&link, mongo, redis).await;
+                    }
+                    Ok(GetFileResponse::File(response))
+                }
+                Err(e) => {
+                    refund_download(&link, mongo, redis).await;
+                    Err(e)
+                }
+            }
+        },
+        FileType::Folder => {
+            consume_download(&link, mongo, redis).await?;
+            match ArchiveResponse::new(file_metadata, storage_factory, mongo).await {
+                Ok(response) => Ok(GetFileResponse::Archive(response)),
+                Err(e) => {
+                    refund_download(&link, mongo, redis).await;
+                    Err(e)
+                }
+            }
+        },
+        _ => {
+            Err(ApiError::BadRequest("Target is not a file".to_string().into()))
         }
     }
+}
 
-    let limit: i64 = redis.get(format!("{}_limit", uuid).as_str()).await;
-    if limit == 0 {
-        return Err(ApiError::BadRequest("Download limit reached".to_string().into()));
+//列分享出来的文件夹，path和下载一样是名字路径，不扣下载次数
+#[get("/<uuid>/list?<path>")]
+pub async fn list_share_folder(
+    uuid: &str,
+    path: Option<&str>,
+    token: ShareToken,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<Json<ShareFolderListing>, ApiError> {
+    let link = unlock_link(uuid, &token, mongo, redis).await?;
+    if link.file_request.is_some() {
+        return Err(ApiError::Forbidden("Link only accepts uploads".to_string().into()));
     }
-    let file_id: String = redis.get(&uuid).await;
-    let file_metadata = collection.find_one(doc! { "_id": ObjectId::from_str(file_id.as_str()).unwrap() }).await;
+    let folder = resolve_path(&link, path.unwrap_or(""), mongo).await?;
+    if folder.type_ != FileType::Folder {
+        return Err(ApiError::BadRequest("Target is not a folder".to_string().into()));
+    }
+    Ok(Json(ShareFolderListing {
+        name: folder.name.clone(),
+        remaining_downloads: remaining_downloads(&link),
+        children: list_children(&link, &folder, mongo).await?,
+    }))
+}
 
-    let file_metadata = mongo_error_check(file_metadata, Some("File"))?;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestMetadataCreateRequest {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub storage_type: String,
+}
 
-    let metadata = metadata.unwrap_or(false);
+//文件请求的上传和add_metadata、upload_file一样分两步，文件记在链接主人名下
+//先交metadata，能秒传的话直接就建好了
+#[post("/<uuid>/metadata", data = "<metadata>")]
+pub async fn request_add_metadata(
+    uuid: &str,
+    metadata: Json<RequestMetadataCreateRequest>,
+    token: ShareToken,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<Json<MetaDataCreateResponse>, ApiError> {
+    let link = unlock_link(uuid, &token, mongo, redis).await?;
+    let request = match &link.file_request {
+        Some(request) => request,
+        None => return Err(ApiError::BadRequest("Link does not accept uploads".to_string().into())),
+    };
+    check_request_limits(request, metadata.size)?;
+    let name = metadata.name.as_str();
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(ApiError::BadRequest("Invalid file name".to_string().into()));
+    }
+    let storage_type = pick_storage_type(&metadata.storage_type, config);
+    if storage_factory.lock().await.get_backend(&storage_type).is_none() {
+        return Err(ApiError::BadRequest("Storage backend not found".to_string().into()));
+    }
+    let folder = mongo.database.collection::<File>("files").find_one(doc! { "_id": link.target }).await;
+    match mongo_error_check(folder, Some("Folder"))?.type_ {
+        FileType::Folder => {}
+        _ => return Err(ApiError::BadRequest("Target is not a folder".to_string().into())),
+    }
+    check_quota(mongo, &link.owner, metadata.size, config.default_quota).await?;
 
-    if path.is_none() {
-        if metadata {
-            return Ok(GetFileResponse::Metadata(Json(file_metadata)));
-        }
-        match file_metadata.type_ {
-            FileType::File => {
-                let _ = redis.decr(format!("{}_limit", uuid).as_str()).await;
-                return Ok(GetFileResponse::File(CustomFileResponse::new(file_metadata, storage_factory,mongo).await?));
-            },
-            _ => {
-                return Err(ApiError::BadRequest("Target is not a file".to_string().into()));
-            }
+    let id = ObjectId::new();
+    let metadata = metadata.into_inner();
+    let file = attribute_to_link(
+        File {
+            _id: id,
+            name: metadata.name,
+            type_: FileType::File,
+            father: link.target,
+            size: metadata.size,
+            sha256: metadata.sha256,
+            owner: link.owner,
+            created_at: Utc::now().timestamp(),
+            updated_at: Utc::now().timestamp(),
+            children: vec![],
+            path: id.to_hex(),
+            storage_type,
+            extra_metadata: None,
+        },
+        uuid,
+    );
+    if let Some(deduped) = blob_storage::try_dedup(mongo, &file).await? {
+        reserve_upload(&link, file.size, mongo, redis).await?;
+        if let Err(e) = commit_uploaded_file(mongo, storage_factory, &deduped, config.default_quota).await {
+            release_upload(&link, file.size, mongo, redis).await;
+            return Err(e);
         }
+        return Ok(Json(MetaDataCreateResponse::ref_file(id.to_string())));
     }
+    let _: () = redis.set(id.to_string().as_str(), serde_json::to_string(&file).unwrap().as_str()).await;
+    let _: () = redis.expire(id.to_string().as_str(), 24 * 60 * 60).await;
+    Ok(Json(MetaDataCreateResponse::normal(id.to_string())))
+}
 
-    let path = path.unwrap();
-    let mut path = path.split("/").collect::<Vec<&str>>();
-    path.reverse();
+#[post("/<uuid>/file/<id>", data = "<file>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn request_upload_file(
+    uuid: &str,
+    id: &str,
+    mut file: TempFile<'_>,
+    token: ShareToken,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<status::NoContent, ApiError> {
+    let link = unlock_link(uuid, &token, mongo, redis).await?;
+    if !redis.exists(id).await {
+        return Err(ApiError::NotFound("Metadata not found".to_string().into()));
+    }
+    let metadata: String = redis.get(id).await;
+    //别的链接或者别人暂存的metadata不能从这里传
+    let metadata: File = match serde_json::from_str::<File>(metadata.as_str()) {
+        Ok(metadata) if metadata.extra_metadata.as_ref().and_then(|e| e.share_link.as_deref()) == Some(uuid) => metadata,
+        _ => return Err(ApiError::NotFound("Metadata not found".to_string().into())),
+    };
+    if file.len() != metadata.size {
+        return Err(ApiError::BadRequest("Size not match".to_string().into()));
+    }
+    reserve_upload(&link, metadata.size, mongo, redis).await?;
 
-    let file_metadata = path_find(path, file_metadata, collection).await?;
+    let factory = storage_factory.lock().await.clone();
+    let sha256 = metadata.sha256.clone();
+    let size = metadata.size;
+    let stored = blob_storage::store_temp_file(mongo, &factory, metadata, &sha256, &mut file).await;
+    let stored = match stored {
+        Ok(stored) => attribute_to_link(stored, uuid),
+        Err(e) => {
+            release_upload(&link, size, mongo, redis).await;
+            return Err(e);
+        }
+    };
+    if let Err(e) = commit_uploaded_file(mongo, storage_factory, &stored, config.default_quota).await {
+        release_upload(&link, size, mongo, redis).await;
+        return Err(e);
+    }
+    let _: () = redis.delete(id).await;
+    Ok(status::NoContent)
+}
 
-    if metadata {
-        return Ok(GetFileResponse::Metadata(Json(file_metadata)));
+#[get("/links")]
+pub async fn list_share_links(
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<Vec<ShareLinkInfo>>, ApiError> {
+    let mut result = vec![];
+    for link in list_links(&user.uuid, mongo).await? {
+        result.push(link_info(&link, mongo).await);
     }
+    Ok(Json(result))
+}
 
-    match file_metadata.type_ {
-        FileType::File => {
-            let _ = redis.decr(format!("{}_limit", uuid).as_str()).await;
-            Ok(GetFileResponse::File(CustomFileResponse::new(file_metadata, storage_factory,mongo).await?))
-        },
-        _ => {
-            Err(ApiError::BadRequest("Target is not a file".to_string().into()))
+#[get("/links/<uuid>")]
+pub async fn get_share_link(
+    uuid: &str,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<Json<ShareLinkInfo>, ApiError> {
+    let link = owned_link(uuid, &user.uuid, mongo, redis).await?;
+    Ok(Json(link_info(&link, mongo).await))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateShareLinkRequest {
+    pub live_second: Option<i64>, //从现在开始重新算
+    pub download_count_limit: Option<i64>, //剩余次数直接改成这个，-1是不限
+    pub password: Option<String>, //空字符串是去掉密码
+    pub max_files: Option<u64>, //只对文件请求有用，0是不限
+    pub max_bytes: Option<u64>,
+}
+
+#[put("/links/<uuid>", data = "<request>")]
+pub async fn update_share_link(
+    uuid: &str,
+    request: Json<UpdateShareLinkRequest>,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<Json<ShareLinkInfo>, ApiError> {
+    let link = owned_link(uuid, &user.uuid, mongo, redis).await?;
+    let mut fields = doc! {};
+    if let Some(live_second) = request.live_second {
+        if live_second <= 0 {
+            return Err(ApiError::BadRequest("live_second must be positive".to_string().into()));
+        }
+        fields.insert("expire_at", mongodb::bson::DateTime::from_chrono(Utc::now() + Duration::seconds(live_second)));
+    }
+    if link.file_request.is_some() {
+        if let Some(max_files) = request.max_files {
+            fields.insert("file_request.max_files", max_files as i64);
         }
+        if let Some(max_bytes) = request.max_bytes {
+            fields.insert("file_request.max_bytes", max_bytes as i64);
+        }
+    } else if let Some(limit) = request.download_count_limit {
+        fields.insert("download_limit", limit.max(-1));
+        fields.insert("remaining_downloads", limit.max(-1));
+    }
+    if let Some(password) = &request.password {
+        fields.insert("password", hash_share_password(Some(password)));
     }
+    let link = update_link(uuid, fields, mongo, redis).await?;
+    Ok(Json(link_info(&link, mongo).await))
 }
 
+#[delete("/links/<uuid>")]
+pub async fn revoke_share_link(
+    uuid: &str,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+) -> Result<(), ApiError> {
+    let link = owned_link(uuid, &user.uuid, mongo, redis).await?;
+    revoke_link(&link, mongo, redis).await
+}
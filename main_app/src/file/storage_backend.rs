@@ -1,3 +1,6 @@
 pub mod lib;
 pub mod flat;
-pub mod ref_storage;
\ No newline at end of file
+pub mod s3;
+pub mod encrypted;
+pub mod compressed;
+pub mod blob_storage;
\ No newline at end of file
@@ -0,0 +1,435 @@
+//按sha256去重的blob存储
+//File里storage_type为blob，path为sha256，真正的存储位置记在blobs集合里
+//去重、删除、更新都只是改ref_count
+//虽然不是storage_backend的一部分，但是也放在这里
+
+use std::path::{Path, PathBuf};
+
+use super::lib::{file_sha256, StorageFactory};
+use crate::db::connect::MongoDb;
+use crate::db::models::{Blob, File, FileType};
+use crate::libs::ApiError;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::ReturnDocument;
+use rocket::fs::TempFile;
+use rocket::futures::TryStreamExt;
+use rocket::tokio::fs;
+use rocket::tokio::fs::File as AsyncFile;
+use std::str::FromStr;
+
+pub const BLOB_STORAGE_TYPE: &str = "blob";
+
+fn blob_collection(mongo: &MongoDb) -> mongodb::Collection<Blob> {
+    mongo.database.collection::<Blob>("blobs")
+}
+
+//给storage backend用的File，backend只认path和storage_type
+fn blob_as_file(blob: &Blob, metadata: &File) -> File {
+    File {
+        storage_type: blob.storage_type.clone(),
+        path: blob.path.clone(),
+        size: blob.size,
+        sha256: blob._id.clone(),
+        ..metadata.clone()
+    }
+}
+
+//File指向blob之后的样子
+fn point_to_blob(metadata: File, blob: &Blob) -> File {
+    //老blob没记stored_size，就不写
+    let mut extra_metadata = metadata.extra_metadata.clone().unwrap_or_default();
+    extra_metadata.stored_size = Some(blob.stored_size).filter(|size| *size > 0);
+    File {
+        storage_type: BLOB_STORAGE_TYPE.to_string(),
+        path: blob._id.clone(),
+        size: blob.size,
+        sha256: blob._id.clone(),
+        extra_metadata: Some(extra_metadata),
+        ..metadata
+    }
+}
+
+//已有这个sha256的话直接加一次引用
+pub async fn acquire_existing(mongo: &MongoDb, sha256: &str) -> Result<Option<Blob>, ApiError> {
+    match blob_collection(mongo)
+        .find_one_and_update(doc! { "_id": sha256 }, doc! { "$inc": { "ref_count": 1 } })
+        .return_document(ReturnDocument::After)
+        .await
+    {
+        Ok(blob) => Ok(blob),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+//metadata已经声明了sha256，能秒传的话返回指向blob的File
+pub async fn try_dedup(mongo: &MongoDb, metadata: &File) -> Result<Option<File>, ApiError> {
+    if metadata.sha256.is_empty() {
+        return Ok(None);
+    }
+    Ok(acquire_existing(mongo, &metadata.sha256)
+        .await?
+        .map(|blob| point_to_blob(metadata.clone(), &blob)))
+}
+
+//把本地已经校验过sha256的文件存成blob，返回指向blob的File
+//本地文件会被移走或删掉
+pub async fn store_local_file(
+    mongo: &MongoDb,
+    factory: &StorageFactory,
+    metadata: File,
+    local_path: &Path,
+) -> Result<File, ApiError> {
+    if let Some(blob) = acquire_existing(mongo, &metadata.sha256).await? {
+        let _ = fs::remove_file(local_path).await;
+        return Ok(point_to_blob(metadata, &blob));
+    }
+    //每个blob用独立的path，两个相同内容同时上传也不会互相覆盖
+    let target = File {
+        path: ObjectId::new().to_hex(),
+        ..metadata.clone()
+    };
+    let save_result = factory.save_local_file(&target, local_path).await?;
+    let collection = blob_collection(mongo);
+    let upserted = collection
+        .find_one_and_update(
+            doc! { "_id": &metadata.sha256 },
+            doc! {
+                "$inc": { "ref_count": 1 },
+                "$setOnInsert": {
+                    "storage_type": &target.storage_type,
+                    "path": &target.path,
+                    "size": save_result.size as i64,
+                    "stored_size": save_result.stored_size as i64,
+                    "created_at": chrono::Utc::now().timestamp(),
+                },
+            },
+        )
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await;
+    let blob = match upserted {
+        Ok(Some(blob)) => blob,
+        _ => {
+            let _ = factory.delete_file(&target).await;
+            return Err(ApiError::InternalServerError("Database error".to_string().into()));
+        }
+    };
+    //别人先存好了，自己这份不要了
+    if blob.path != target.path || blob.storage_type != target.storage_type {
+        let _ = factory.delete_file(&target).await;
+    }
+    Ok(point_to_blob(metadata, &blob))
+}
+
+//普通上传的TempFile先落到cache里校验sha256，再按本地文件存
+pub async fn store_temp_file(
+    mongo: &MongoDb,
+    factory: &StorageFactory,
+    metadata: File,
+    sha256: &str,
+    file: &mut TempFile<'_>,
+) -> Result<File, ApiError> {
+    let temp_path = PathBuf::from(format!(
+        "{}/blobs/{}",
+        factory.get_config().cache_storage_path,
+        uuid::Uuid::new_v4()
+    ));
+    let _ = fs::create_dir_all(temp_path.parent().unwrap()).await;
+    if file.persist_to(&temp_path).await.is_err() {
+        return Err(ApiError::InternalServerError("Failed to save file".to_string().into()));
+    }
+    let hash = match AsyncFile::open(&temp_path).await {
+        Ok(file) => file_sha256(file).await,
+        Err(_) => String::new(),
+    };
+    if hash != sha256 {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(ApiError::BadRequest("Hash not match".to_string().into()));
+    }
+    let metadata = File {
+        sha256: hash,
+        extra_metadata: super::super::lib::get_local_file_type(&temp_path)
+            .await
+            .map(|t| shared_lib::db::models::FileExtraMetadata {
+                detected_mime_type: Some(t.mime_type().to_string()),
+                ..Default::default()
+            }),
+        ..metadata
+    };
+    store_local_file(mongo, factory, metadata, &temp_path).await
+}
+
+//少一次引用，没人用了就把数据删掉
+pub async fn release(mongo: &MongoDb, factory: &StorageFactory, sha256: &str) -> Result<(), ApiError> {
+    let collection = blob_collection(mongo);
+    let blob = match collection
+        .find_one_and_update(doc! { "_id": sha256 }, doc! { "$inc": { "ref_count": -1 } })
+        .return_document(ReturnDocument::After)
+        .await
+    {
+        Ok(Some(blob)) => blob,
+        Ok(None) => return Ok(()),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    if blob.ref_count > 0 {
+        return Ok(());
+    }
+    //删之前又被引用了的话这里删不掉，数据也就留着
+    match collection
+        .delete_one(doc! { "_id": sha256, "ref_count": { "$lte": 0 } })
+        .await
+    {
+        Ok(result) if result.deleted_count == 1 => {
+            let target = File {
+                type_: FileType::File,
+                ..File::new_folder("", &ObjectId::new(), &ObjectId::new(), None)
+            };
+            factory.delete_file(&blob_as_file(&blob, &target)).await
+        }
+        _ => Ok(()),
+    }
+}
+
+//已有文件换成新内容，new是store_*返回的File，旧内容变成历史版本
+pub async fn replace_content(
+    mongo: &MongoDb,
+    factory: &StorageFactory,
+    old: &File,
+    new: &File,
+    default_quota: u64,
+) -> Result<(), ApiError> {
+    //旧内容留作历史版本，引用不release，用量也还算着，所以新内容要整个占配额
+    if let Err(e) = crate::quota::lib::reserve(mongo, &old.owner, new.size, default_quota).await {
+        release(mongo, factory, &new.sha256).await?;
+        return Err(e);
+    }
+    let result = mongo
+        .database
+        .collection::<File>("files")
+        .update_one(
+            doc! { "_id": old._id },
+            doc! { "$set": {
+                "storage_type": &new.storage_type,
+                "path": &new.path,
+                "sha256": &new.sha256,
+                "size": new.size as i64,
+                "updated_at": chrono::Utc::now().timestamp(),
+                "extra_metadata": new.extra_metadata.clone(),
+            } },
+        )
+        .await;
+    if result.is_err() {
+        crate::quota::lib::charge(mongo, &old.owner, -(new.size as i64)).await;
+        release(mongo, factory, &new.sha256).await?;
+        return Err(ApiError::InternalServerError("Database error".to_string().into()));
+    }
+    if old.storage_type == BLOB_STORAGE_TYPE {
+        crate::file::version::lib::keep_version(mongo, old).await?;
+    }
+    Ok(())
+}
+
+//取实际存储用的File，下载、打包、webdav读都走这里
+pub async fn resolve_blob(metadata: File, mongo: &MongoDb) -> Result<File, ApiError> {
+    if metadata.storage_type != BLOB_STORAGE_TYPE {
+        return Ok(metadata);
+    }
+    match blob_collection(mongo).find_one(doc! { "_id": &metadata.path }).await {
+        Ok(Some(blob)) => Ok(blob_as_file(&blob, &metadata)),
+        Ok(None) => Err(ApiError::NotFound("Blob not found".to_string().into())),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+//把旧的FLAT/ref文件迁到blob里，启动时跑一次，已经迁过的不会再动
+//数据不搬家，blob直接记原来的path
+//每一步都能重跑：先把文件标成blob并记下migrating，再按文件给blob加引用，blob上记着加过谁，不会重复加
+//内容重复的那份存储等全部迁完了才删，中途崩了也不会丢数据
+pub async fn migrate_legacy_files(mongo: &MongoDb, factory: &StorageFactory) -> Result<(), ApiError> {
+    let files = mongo.database.collection::<File>("files");
+    let raw_files = mongo.database.collection::<Document>("files");
+    let blobs = blob_collection(mongo);
+    let db_error = |_| ApiError::InternalServerError("Database error".to_string().into());
+
+    //先处理母文件，ref要靠母文件的blob
+    let mothers: Vec<File> = files
+        .find(doc! { "type": "File", "storage_type": { "$nin": [BLOB_STORAGE_TYPE, "ref"] } })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    let refs: Vec<File> = files
+        .find(doc! { "type": "File", "storage_type": "ref" })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    if !mothers.is_empty() || !refs.is_empty() {
+        info!("正在迁移{}个文件到blob存储", mothers.len() + refs.len());
+    }
+
+    for file in mothers {
+        if file.sha256.is_empty() {
+            warn!("文件{}没有sha256，跳过", file._id);
+            continue;
+        }
+        //没有blob就建一个，引用数从0开始，下面按文件一个个加
+        blobs
+            .update_one(
+                doc! { "_id": &file.sha256 },
+                doc! { "$setOnInsert": {
+                    "storage_type": &file.storage_type,
+                    "path": &file.path,
+                    "size": file.size as i64,
+                    "stored_size": file.size as i64,
+                    "ref_count": 0i64,
+                    "created_at": file.created_at,
+                } },
+            )
+            .upsert(true)
+            .await
+            .map_err(db_error)?;
+        let blob = match blobs.find_one(doc! { "_id": &file.sha256 }).await.map_err(db_error)? {
+            Some(blob) => blob,
+            None => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+        };
+        //内容一样的另一份，先记下来，迁完再删
+        let duplicate = blob.path != file.path || blob.storage_type != file.storage_type;
+        mark_migrated(&raw_files, &file, &blob._id, blob.size, duplicate).await?;
+    }
+
+    for file in refs {
+        //ref的sha256不一定靠得住，以母文件为准
+        let mother = match ObjectId::from_str(&file.path) {
+            Ok(id) => files.find_one(doc! { "_id": id }).await.map_err(db_error)?,
+            Err(_) => None,
+        };
+        let sha256 = mother.map(|m| m.sha256).unwrap_or(file.sha256.clone());
+        match blobs.find_one(doc! { "_id": &sha256 }).await.map_err(db_error)? {
+            Some(blob) => mark_migrated(&raw_files, &file, &blob._id, blob.size, false).await?,
+            None => warn!("文件{}的母文件已丢失，跳过", file._id),
+        }
+    }
+
+    //标过的文件一个个给blob加引用，上次崩在半路的也在这里接着做
+    let pending: Vec<File> = files
+        .find(doc! { "migrating": true })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    for file in pending {
+        blobs
+            .update_one(
+                doc! { "_id": &file.sha256, "migrated_refs": { "$ne": file._id } },
+                doc! { "$inc": { "ref_count": 1 }, "$push": { "migrated_refs": file._id } },
+            )
+            .await
+            .map_err(db_error)?;
+        raw_files
+            .update_one(doc! { "_id": file._id }, doc! { "$unset": { "migrating": "" } })
+            .await
+            .map_err(db_error)?;
+    }
+
+    //引用都记好了，再删重复的存储
+    let duplicates: Vec<Document> = raw_files
+        .find(doc! { "legacy_path": { "$exists": true } })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    for duplicate in duplicates {
+        let (id, storage_type, path) = match (
+            duplicate.get_object_id("_id"),
+            duplicate.get_str("legacy_storage_type"),
+            duplicate.get_str("legacy_path"),
+        ) {
+            (Ok(id), Ok(storage_type), Ok(path)) => (id, storage_type.to_string(), path.to_string()),
+            _ => continue,
+        };
+        let target = File {
+            type_: FileType::File,
+            storage_type,
+            path,
+            ..File::new_folder("", &ObjectId::new(), &ObjectId::new(), None)
+        };
+        if let Err(e) = factory.delete_file(&target).await {
+            warn!("删除文件{}重复的存储失败: {:?}", id, e);
+        }
+        raw_files
+            .update_one(doc! { "_id": id }, doc! { "$unset": { "legacy_storage_type": "", "legacy_path": "" } })
+            .await
+            .map_err(db_error)?;
+    }
+    blobs
+        .update_many(doc! { "migrated_refs": { "$exists": true } }, doc! { "$unset": { "migrated_refs": "" } })
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+//只改还没迁过的，重跑的时候不会把已经标过的再标一遍
+async fn mark_migrated(
+    files: &mongodb::Collection<Document>,
+    file: &File,
+    sha256: &str,
+    size: u64,
+    duplicate: bool,
+) -> Result<(), ApiError> {
+    let mut set = doc! {
+        "storage_type": BLOB_STORAGE_TYPE,
+        "path": sha256,
+        "sha256": sha256,
+        "size": size as i64,
+        "migrating": true,
+    };
+    if duplicate {
+        set.insert("legacy_storage_type", &file.storage_type);
+        set.insert("legacy_path", &file.path);
+    }
+    let result = files
+        .update_one(
+            doc! { "_id": file._id, "storage_type": &file.storage_type },
+            doc! {
+                "$set": set,
+                "$unset": { "extra_metadata.file_references": "", "file_references": "" },
+            },
+        )
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+//主密钥换了之后把所有blob的数据密钥用新主密钥重新包一遍，数据不用重传
+pub async fn rotate_keys(mongo: &MongoDb, factory: &StorageFactory) -> Result<(), ApiError> {
+    let db_error = |_| ApiError::InternalServerError("Database error".to_string().into());
+    let blobs: Vec<Blob> = blob_collection(mongo)
+        .find(doc! {})
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    let target = File {
+        type_: FileType::File,
+        ..File::new_folder("", &ObjectId::new(), &ObjectId::new(), None)
+    };
+    let mut rotated = 0;
+    for blob in blobs {
+        match factory.rewrap_key(&blob_as_file(&blob, &target)).await {
+            Ok(true) => rotated += 1,
+            Ok(false) => {}
+            Err(e) => warn!("blob {}换密钥失败: {:?}", blob._id, e),
+        }
+    }
+    info!("已为{}个blob更换密钥", rotated);
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//透明压缩存储，套在任意一个backend外面，每个blob单独一个zstd流
+//infer认得出来的图片、音视频、压缩包本来就压过了，直接原样存；压完没变小的也原样存
+//存进去的内容前面有个头: "RCZ1" | 模式(0原样,1zstd) | 后面内容的长度u64
+use std::path::{Path, PathBuf};
+
+use super::flat::LocalFlatStorageBackend;
+use super::lib::{stream_to_temp_file, FileStream, SaveResult, StorageBackend, StorageConfig};
+use crate::db::models::File;
+use crate::file::lib::get_local_file_type;
+use crate::libs::ApiError;
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use async_compression::Level;
+use async_trait::async_trait;
+use infer::MatcherType;
+use rocket::tokio::fs;
+use rocket::tokio::fs::{File as AsyncFile, OpenOptions};
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use std::io::SeekFrom;
+
+const HEADER_MAGIC: &[u8; 4] = b"RCZ1";
+const HEADER_SIZE: u64 = 4 + 1 + 8;
+const MODE_RAW: u8 = 0;
+const MODE_ZSTD: u8 = 1;
+
+fn write_error() -> ApiError {
+    ApiError::InternalServerError("Failed to save file".to_string().into())
+}
+
+fn read_error() -> ApiError {
+    ApiError::InternalServerError("Failed to read file".to_string().into())
+}
+
+//已经压缩过的格式再压一遍只是白费CPU
+async fn is_compressible(local_path: &Path) -> bool {
+    match get_local_file_type(local_path).await {
+        Some(t) => !matches!(
+            t.matcher_type(),
+            MatcherType::Archive | MatcherType::Image | MatcherType::Video | MatcherType::Audio
+        ),
+        None => true,
+    }
+}
+
+pub struct CompressedStorageBackend {
+    inner: Box<dyn StorageBackend>,
+    level: i32,
+    cache_storage_path: String,
+}
+
+impl CompressedStorageBackend {
+    pub fn wrap(inner: Box<dyn StorageBackend>, config: &StorageConfig) -> Self {
+        Self {
+            inner,
+            level: config.compression_level,
+            cache_storage_path: config.cache_storage_path.clone(),
+        }
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/compressed/{}",
+            self.cache_storage_path,
+            uuid::Uuid::new_v4()
+        ))
+    }
+
+    //先留好头的位置，写完内容再回来填
+    async fn write_body(&self, local_path: &Path, target: &Path, mode: u8) -> std::io::Result<u64> {
+        let mut source = BufReader::new(AsyncFile::open(local_path).await?);
+        let mut file = AsyncFile::create(target).await?;
+        file.write_all(&[0; HEADER_SIZE as usize]).await?;
+        if mode == MODE_ZSTD {
+            let mut encoder = ZstdEncoder::with_quality(file, Level::Precise(self.level));
+            rocket::tokio::io::copy_buf(&mut source, &mut encoder).await?;
+            encoder.shutdown().await?;
+        } else {
+            rocket::tokio::io::copy_buf(&mut source, &mut file).await?;
+            file.flush().await?;
+        }
+        let length = fs::metadata(target).await?.len() - HEADER_SIZE;
+        let mut file = OpenOptions::new().write(true).open(target).await?;
+        let mut header = HEADER_MAGIC.to_vec();
+        header.push(mode);
+        header.extend_from_slice(&length.to_be_bytes());
+        file.seek(SeekFrom::Start(0)).await?;
+        file.write_all(&header).await?;
+        file.flush().await?;
+        Ok(length)
+    }
+
+    async fn read_header(&self, metadata: &File) -> Result<(u8, u64), ApiError> {
+        let mut reader = self.inner.get_file_range(metadata, 0, HEADER_SIZE).await?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        if reader.read_exact(&mut header).await.is_err() || &header[..4] != HEADER_MAGIC {
+            return Err(read_error());
+        }
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[5..]);
+        Ok((header[4], u64::from_be_bytes(length)))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for CompressedStorageBackend {
+    fn new(config: &StorageConfig) -> Self {
+        Self::wrap(Box::new(LocalFlatStorageBackend::new(config)), config)
+    }
+
+    async fn save_local_file(&self, metadata: &File, local_path: &Path) -> Result<SaveResult, ApiError> {
+        let size = match fs::metadata(local_path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => return Err(read_error()),
+        };
+        let target = self.temp_path();
+        let _ = fs::create_dir_all(target.parent().unwrap()).await;
+        let mode = if is_compressible(local_path).await { MODE_ZSTD } else { MODE_RAW };
+        let length = match self.write_body(local_path, &target, mode).await {
+            Ok(length) => length,
+            Err(_) => {
+                let _ = fs::remove_file(&target).await;
+                return Err(write_error());
+            }
+        };
+        if mode == MODE_ZSTD
+            && length >= size
+            && self.write_body(local_path, &target, MODE_RAW).await.is_err()
+        {
+            let _ = fs::remove_file(&target).await;
+            return Err(write_error());
+        }
+        let saved = match self.inner.save_local_file(metadata, &target).await {
+            Ok(saved) => saved,
+            Err(e) => {
+                let _ = fs::remove_file(&target).await;
+                return Err(e);
+            }
+        };
+        let _ = fs::remove_file(local_path).await;
+        //里面还套了加密的话，实际占用以里面的为准
+        Ok(SaveResult {
+            size,
+            stored_size: saved.stored_size,
+            _path: metadata.path.clone(),
+        })
+    }
+
+    //只有要完整AsyncFile的地方才会走这里，先解压到cache
+    async fn get_file(&self, metadata: &File) -> Result<AsyncFile, ApiError> {
+        let body = self.get_file_range(metadata, 0, metadata.size).await?;
+        stream_to_temp_file(body, &self.temp_path()).await
+    }
+
+    async fn get_file_range(&self, metadata: &File, start: u64, length: u64) -> Result<FileStream, ApiError> {
+        if length == 0 {
+            return Ok(Box::pin(rocket::tokio::io::empty()));
+        }
+        let (mode, stored_length) = self.read_header(metadata).await?;
+        match mode {
+            MODE_RAW => self.inner.get_file_range(metadata, HEADER_SIZE + start, length).await,
+            MODE_ZSTD => {
+                let body = self.inner.get_file_range(metadata, HEADER_SIZE, stored_length).await?;
+                let mut decoder = ZstdDecoder::new(BufReader::new(body));
+                //zstd流没法跳着读，Range只能从头解压再丢掉前面的部分
+                if start > 0 {
+                    let skipped = rocket::tokio::io::copy(&mut (&mut decoder).take(start), &mut rocket::tokio::io::sink()).await;
+                    if !matches!(skipped, Ok(n) if n == start) {
+                        return Err(read_error());
+                    }
+                }
+                Ok(Box::pin(decoder.take(length)))
+            }
+            _ => Err(read_error()),
+        }
+    }
+
+    async fn delete_file(&self, metadata: &File) -> Result<(), ApiError> {
+        self.inner.delete_file(metadata).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::FileType;
+    use crate::file::storage_backend::encrypted::EncryptedStorageBackend;
+    use base64::Engine;
+    use mongodb::bson::oid::ObjectId;
+
+    fn test_config() -> StorageConfig {
+        let root = std::env::temp_dir().join(format!("rc_zstd_test/{}", uuid::Uuid::new_v4()));
+        StorageConfig {
+            flat_storage_path: root.join("flat").to_string_lossy().to_string(),
+            cache_storage_path: root.join("cache").to_string_lossy().to_string(),
+            s3_endpoint: "".to_string(),
+            s3_region: "".to_string(),
+            s3_bucket: "".to_string(),
+            s3_access_key: "".to_string(),
+            s3_secret_key: "".to_string(),
+            s3_part_size: 0,
+            encryption_keys: format!("test:{}", base64::engine::general_purpose::STANDARD.encode([7u8; 32])),
+            compression_level: 3,
+        }
+    }
+
+    fn test_file(size: u64) -> File {
+        let id = ObjectId::new();
+        File {
+            type_: FileType::File,
+            size,
+            path: id.to_hex(),
+            storage_type: "FLAT_ZSTD_ENC".to_string(),
+            ..File::new_folder("test.txt", &id, &id, Some(id))
+        }
+    }
+
+    async fn read_all(mut stream: FileStream) -> Vec<u8> {
+        let mut buffer = vec![];
+        stream.read_to_end(&mut buffer).await.unwrap();
+        buffer
+    }
+
+    //压缩套在加密外面，里面的密文长度和metadata.size对不上，Range也要读得对
+    #[rocket::async_test]
+    async fn compressed_encrypted_round_trip() {
+        let config = test_config();
+        fs::create_dir_all(&config.flat_storage_path).await.unwrap();
+        let backend = CompressedStorageBackend::wrap(
+            Box::new(EncryptedStorageBackend::wrap(Box::new(LocalFlatStorageBackend::new(&config)), &config).unwrap()),
+            &config,
+        );
+        let data: Vec<u8> = "hello compressed and encrypted\n".repeat(20000).into_bytes();
+        let file = test_file(data.len() as u64);
+        let local = PathBuf::from(&config.cache_storage_path).join("upload");
+        fs::create_dir_all(local.parent().unwrap()).await.unwrap();
+        fs::write(&local, &data).await.unwrap();
+
+        let saved = backend.save_local_file(&file, &local).await.unwrap();
+        assert_eq!(saved.size, data.len() as u64);
+        assert!(saved.stored_size < saved.size);
+        let stored = fs::read(format!("{}/{}", config.flat_storage_path, file.path)).await.unwrap();
+        assert!(!stored.windows(5).any(|w| w == b"hello"));
+
+        let whole = read_all(backend.get_file_range(&file, 0, file.size).await.unwrap()).await;
+        assert_eq!(whole, data);
+        let part = read_all(backend.get_file_range(&file, 100_000, 50).await.unwrap()).await;
+        assert_eq!(part, &data[100_000..100_050]);
+
+        backend.delete_file(&file).await.unwrap();
+        assert!(backend.get_file_range(&file, 0, 1).await.is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//加密存储，套在任意一个backend外面
+//每个blob一把随机的数据密钥，按64KiB分块AES-256-GCM加密，nonce就是块序号
+//数据密钥用配置里的主密钥包一层，单独存在<path>.key里，换主密钥只要重写这个小文件
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::flat::LocalFlatStorageBackend;
+use super::lib::{stream_to_temp_file, FileStream, SaveResult, StorageBackend, StorageConfig};
+use crate::db::models::File;
+use crate::libs::ApiError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use base64::Engine;
+use rocket::tokio::fs;
+use rocket::tokio::fs::File as AsyncFile;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE + TAG_SIZE;
+
+//.key文件: "RCK1" | key_id(16字节，不够补0) | nonce(12) | 包好的数据密钥(32+16)
+const KEY_MAGIC: &[u8; 4] = b"RCK1";
+const KEY_ID_SIZE: usize = 16;
+const WRAPPED_KEY_SIZE: usize = 32 + 16;
+const KEY_FILE_SIZE: u64 = (4 + KEY_ID_SIZE + 12 + WRAPPED_KEY_SIZE) as u64;
+
+pub struct MasterKey {
+    pub id: String,
+    pub key: Key<Aes256Gcm>,
+}
+
+//格式是"id:base64密钥,id:base64密钥"，第一个是当前用的，后面的留着解旧数据
+//配置写错了返回错误，启动的时候报出来，不要panic
+pub fn parse_master_keys(config: &str) -> Result<Vec<MasterKey>, String> {
+    config
+        .split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (id, key) = match entry.split_once(':') {
+                Some(pair) => pair,
+                None => return Err(format!("encryption key \"{}\" should be id:base64", entry)),
+            };
+            let key = match base64::engine::general_purpose::STANDARD.decode(key) {
+                Ok(key) => key,
+                Err(_) => return Err(format!("encryption key \"{}\" is not valid base64", id)),
+            };
+            if id.is_empty() || id.len() > KEY_ID_SIZE {
+                return Err(format!("encryption key id \"{}\" should be 1 to {} bytes", id, KEY_ID_SIZE));
+            }
+            if key.len() != 32 {
+                return Err(format!("encryption key \"{}\" should be 32 bytes", id));
+            }
+            Ok(MasterKey {
+                id: id.to_string(),
+                key: *Key::<Aes256Gcm>::from_slice(&key),
+            })
+        })
+        .collect()
+}
+
+fn chunk_nonce(index: u64) -> Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+//第index块加密后的长度，最后一块可能不满
+fn sealed_len(index: u64, size: u64) -> u64 {
+    (size - index * CHUNK_SIZE).min(CHUNK_SIZE) + TAG_SIZE
+}
+
+//密文长度反推明文长度，每块多一个tag
+fn plain_len(sealed: u64) -> u64 {
+    sealed - sealed.div_ceil(SEALED_CHUNK_SIZE) * TAG_SIZE
+}
+
+fn key_file(metadata: &File) -> File {
+    File {
+        path: format!("{}.key", metadata.path),
+        size: KEY_FILE_SIZE,
+        ..metadata.clone()
+    }
+}
+
+fn crypto_error() -> ApiError {
+    ApiError::InternalServerError("Failed to decrypt file".to_string().into())
+}
+
+pub struct EncryptedStorageBackend {
+    inner: Box<dyn StorageBackend>,
+    keys: Vec<MasterKey>,
+    cache_storage_path: String,
+}
+
+impl EncryptedStorageBackend {
+    pub fn wrap(inner: Box<dyn StorageBackend>, config: &StorageConfig) -> Result<Self, String> {
+        let keys = parse_master_keys(&config.encryption_keys)?;
+        if keys.is_empty() {
+            return Err("no encryption key configured".to_string());
+        }
+        Ok(Self {
+            inner,
+            keys,
+            cache_storage_path: config.cache_storage_path.clone(),
+        })
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/encrypted/{}",
+            self.cache_storage_path,
+            uuid::Uuid::new_v4()
+        ))
+    }
+
+    fn wrap_key(&self, data_key: &Key<Aes256Gcm>) -> Vec<u8> {
+        let master = &self.keys[0];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped = Aes256Gcm::new(&master.key)
+            .encrypt(&nonce, data_key.as_slice())
+            .unwrap();
+        let mut key_id = [0u8; KEY_ID_SIZE];
+        key_id[..master.id.len()].copy_from_slice(master.id.as_bytes());
+        let mut content = KEY_MAGIC.to_vec();
+        content.extend_from_slice(&key_id);
+        content.extend_from_slice(&nonce);
+        content.extend_from_slice(&wrapped);
+        content
+    }
+
+    //返回数据密钥和包它用的主密钥id
+    fn unwrap_key(&self, content: &[u8]) -> Result<(Key<Aes256Gcm>, String), ApiError> {
+        if content.len() as u64 != KEY_FILE_SIZE || &content[..4] != KEY_MAGIC {
+            return Err(crypto_error());
+        }
+        let key_id = &content[4..4 + KEY_ID_SIZE];
+        let key_id = String::from_utf8_lossy(key_id).trim_end_matches('\0').to_string();
+        let master = match self.keys.iter().find(|k| k.id == key_id) {
+            Some(master) => master,
+            None => {
+                return Err(ApiError::InternalServerError(
+                    format!("Encryption key {} not configured", key_id).into(),
+                ))
+            }
+        };
+        let nonce = Nonce::from_slice(&content[4 + KEY_ID_SIZE..4 + KEY_ID_SIZE + 12]);
+        match Aes256Gcm::new(&master.key).decrypt(nonce, &content[4 + KEY_ID_SIZE + 12..]) {
+            Ok(key) if key.len() == 32 => Ok((*Key::<Aes256Gcm>::from_slice(&key), key_id)),
+            _ => Err(crypto_error()),
+        }
+    }
+
+    async fn read_key_file(&self, metadata: &File) -> Result<(Key<Aes256Gcm>, String), ApiError> {
+        let mut reader = self.inner.get_file_range(&key_file(metadata), 0, KEY_FILE_SIZE).await?;
+        let mut content = vec![];
+        if reader.read_to_end(&mut content).await.is_err() {
+            return Err(crypto_error());
+        }
+        self.unwrap_key(&content)
+    }
+
+    async fn write_key_file(&self, metadata: &File, data_key: &Key<Aes256Gcm>) -> Result<(), ApiError> {
+        let temp_path = self.temp_path();
+        let _ = fs::create_dir_all(temp_path.parent().unwrap()).await;
+        if fs::write(&temp_path, self.wrap_key(data_key)).await.is_err() {
+            return Err(ApiError::InternalServerError("Failed to save file".to_string().into()));
+        }
+        let result = self.inner.save_local_file(&key_file(metadata), &temp_path).await;
+        let _ = fs::remove_file(&temp_path).await;
+        result.map(|_| ())
+    }
+
+    async fn encrypt_to(&self, cipher: &Aes256Gcm, plain_path: &Path, sealed_path: &Path) -> Result<u64, ApiError> {
+        let io_error = |_| ApiError::InternalServerError("Failed to encrypt file".to_string().into());
+        let mut plain = AsyncFile::open(plain_path).await.map_err(io_error)?;
+        let mut sealed = AsyncFile::create(sealed_path).await.map_err(io_error)?;
+        let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+        let mut index = 0;
+        let mut size = 0;
+        loop {
+            //凑满一块再加密，只有最后一块可以不满
+            let mut filled = 0;
+            while filled < buffer.len() {
+                let n = plain.read(&mut buffer[filled..]).await.map_err(io_error)?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 {
+                break;
+            }
+            let chunk = cipher.encrypt(&chunk_nonce(index), &buffer[..filled]).unwrap();
+            sealed.write_all(&chunk).await.map_err(io_error)?;
+            size += filled as u64;
+            index += 1;
+            if filled < buffer.len() {
+                break;
+            }
+        }
+        sealed.flush().await.map_err(io_error)?;
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedStorageBackend {
+    //启动时走的是wrap，配置错了在那里就报出来了，这里只给已经校验过的配置用
+    fn new(config: &StorageConfig) -> Self {
+        Self::wrap(Box::new(LocalFlatStorageBackend::new(config)), config).expect("Invalid encryption key config")
+    }
+
+    async fn save_local_file(&self, metadata: &File, local_path: &Path) -> Result<SaveResult, ApiError> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let sealed_path = self.temp_path();
+        let _ = fs::create_dir_all(sealed_path.parent().unwrap()).await;
+        let size = match self.encrypt_to(&Aes256Gcm::new(&data_key), local_path, &sealed_path).await {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = fs::remove_file(&sealed_path).await;
+                return Err(e);
+            }
+        };
+        self.write_key_file(metadata, &data_key).await?;
+        let saved = match self.inner.save_local_file(metadata, &sealed_path).await {
+            Ok(saved) => saved,
+            Err(e) => {
+                let _ = fs::remove_file(&sealed_path).await;
+                let _ = self.inner.delete_file(&key_file(metadata)).await;
+                return Err(e);
+            }
+        };
+        let _ = fs::remove_file(local_path).await;
+        //记录的是明文大小，Range按明文算
+        Ok(SaveResult {
+            size,
+            stored_size: saved.stored_size + KEY_FILE_SIZE,
+            _path: metadata.path.clone(),
+        })
+    }
+
+    //只有要完整AsyncFile的地方才会走这里，先解密到cache
+    async fn get_file(&self, metadata: &File) -> Result<AsyncFile, ApiError> {
+        let size = self.stored_length(metadata).await?;
+        let body = self.get_file_range(metadata, 0, size).await?;
+        stream_to_temp_file(body, &self.temp_path()).await
+    }
+
+    async fn get_file_range(&self, metadata: &File, start: u64, length: u64) -> Result<FileStream, ApiError> {
+        if length == 0 {
+            return Ok(Box::pin(rocket::tokio::io::empty()));
+        }
+        let (data_key, _) = self.read_key_file(metadata).await?;
+        //明文长度按存储里的密文算，外面套了压缩的话metadata.size是解压后的大小
+        let size = self.stored_length(metadata).await?;
+        if start + length > size {
+            return Err(crypto_error());
+        }
+        //只取覆盖到的那几块
+        let first = start / CHUNK_SIZE;
+        let last = (start + length - 1) / CHUNK_SIZE;
+        let sealed_start = first * SEALED_CHUNK_SIZE;
+        let sealed_length = (last - first) * SEALED_CHUNK_SIZE + sealed_len(last, size);
+        let inner = self.inner.get_file_range(metadata, sealed_start, sealed_length).await?;
+        Ok(Box::pin(DecryptReader {
+            inner,
+            cipher: Aes256Gcm::new(&data_key),
+            index: first,
+            size,
+            sealed: vec![],
+            filled: 0,
+            plain: vec![],
+            pos: 0,
+            skip: (start - first * CHUNK_SIZE) as usize,
+            remaining: length,
+        }))
+    }
+
+    async fn delete_file(&self, metadata: &File) -> Result<(), ApiError> {
+        self.inner.delete_file(metadata).await?;
+        self.inner.delete_file(&key_file(metadata)).await
+    }
+
+    async fn stored_length(&self, metadata: &File) -> Result<u64, ApiError> {
+        Ok(plain_len(self.inner.stored_length(metadata).await?))
+    }
+
+    async fn rewrap_key(&self, metadata: &File) -> Result<bool, ApiError> {
+        let (data_key, key_id) = self.read_key_file(metadata).await?;
+        if key_id == self.keys[0].id {
+            return Ok(false);
+        }
+        self.write_key_file(metadata, &data_key).await?;
+        Ok(true)
+    }
+}
+
+//边读密文边解密，解不开直接报错，不会把坏数据吐出去
+pub struct DecryptReader {
+    inner: FileStream,
+    cipher: Aes256Gcm,
+    index: u64,
+    size: u64,
+    sealed: Vec<u8>,
+    filled: usize,
+    plain: Vec<u8>,
+    pos: usize,
+    skip: usize,
+    remaining: u64,
+}
+
+impl AsyncRead for DecryptReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.remaining == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if this.pos < this.plain.len() {
+                let n = (this.plain.len() - this.pos)
+                    .min(buf.remaining())
+                    .min(this.remaining as usize);
+                buf.put_slice(&this.plain[this.pos..this.pos + n]);
+                this.pos += n;
+                this.remaining -= n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let want = sealed_len(this.index, this.size) as usize;
+            this.sealed.resize(want, 0);
+            while this.filled < want {
+                let mut read_buf = ReadBuf::new(&mut this.sealed[this.filled..want]);
+                ready!(this.inner.as_mut().poll_read(cx, &mut read_buf))?;
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Encrypted file truncated")));
+                }
+                this.filled += n;
+            }
+            this.plain = match this.cipher.decrypt(&chunk_nonce(this.index), &this.sealed[..want]) {
+                Ok(plain) => plain,
+                Err(_) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Failed to decrypt file"))),
+            };
+            this.pos = this.skip;
+            this.skip = 0;
+            this.filled = 0;
+            this.index += 1;
+        }
+    }
+}
@@ -1,14 +1,68 @@
 use std::collections::HashMap;
+use std::path::Path;
 use async_trait::async_trait;
-use rocket::fs::TempFile;
 use rocket::tokio::fs::File as AsyncFile;
 use crate::libs::ApiError;
 use crate::db::models::File;
 use crate::MyConfig;
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type FileStream = Pin<Box<dyn AsyncRead + Send>>;
+use sha2::{Digest, Sha256};
+
+pub async fn file_sha256(file: AsyncFile) -> String {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 1024];
+    let mut reader = BufReader::new(file);
+
+    loop {
+        let n = reader.read(&mut buffer).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    let hash = hasher.finalize();
+    let hash = format!("{:x}", hash);
+
+    hash
+}
+
+//给不能直接打开本地文件的backend实现get_file用，流先写到cache里再打开
+//打开之后临时文件就删掉，已经打开的句柄不受影响
+pub async fn stream_to_temp_file(mut stream: FileStream, temp_path: &Path) -> Result<AsyncFile, ApiError> {
+    let _ = fs::create_dir_all(temp_path.parent().unwrap()).await;
+    let mut temp = match AsyncFile::create(temp_path).await {
+        Ok(temp) => temp,
+        Err(_) => return Err(ApiError::InternalServerError("Failed to read file".to_string().into())),
+    };
+    let copied = rocket::tokio::io::copy(&mut stream, &mut temp).await;
+    let _ = temp.flush().await;
+    drop(temp);
+    let file = AsyncFile::open(temp_path).await;
+    let _ = fs::remove_file(temp_path).await;
+    match (copied, file) {
+        (Ok(_), Ok(file)) => Ok(file),
+        _ => Err(ApiError::InternalServerError("Failed to read file".to_string().into())),
+    }
+}
 
 #[derive(Clone)]
 pub struct StorageConfig {
     pub flat_storage_path: String,
+    pub cache_storage_path: String,
+    pub s3_endpoint: String,
+    pub s3_region: String,
+    pub s3_bucket: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    pub s3_part_size: u64,
+    pub encryption_keys: String,
+    pub compression_level: i32,
 }
 
 #[async_trait]
@@ -17,28 +71,67 @@ pub trait StorageBackend: Send + Sync {
     where
         Self: Sized;
 
-    async fn _save_file(&self, metadata: &File, file:&mut  TempFile<'_>) -> Result<SaveResult, ApiError>;
-    async fn check_sha256_and_save(&self, metadata: &File, sha256: &str, file:&mut TempFile<'_>) -> Result<SaveResult, ApiError>;
+    //把已经在本地校验过的文件移入存储，上传的内容都先落到cache里校验完再走这里
+    async fn save_local_file(&self, metadata: &File, file_path: &Path) -> Result<SaveResult, ApiError>;
     async fn get_file(&self, metadata: &File) -> Result<AsyncFile, ApiError>;
+    //Range请求用，默认实现就是seek之后take
+    async fn get_file_range(&self, metadata: &File, start: u64, length: u64) -> Result<FileStream, ApiError> {
+        let mut file = self.get_file(metadata).await?;
+        if file.seek(SeekFrom::Start(start)).await.is_err() {
+            return Err(ApiError::InternalServerError("Failed to read file".to_string().into()));
+        }
+        Ok(Box::pin(file.take(length)))
+    }
     async fn delete_file(&self, metadata: &File) -> Result<(), ApiError>;
+    //get_file_range一共能读出多少字节，默认就是metadata.size
+    //套在里面的是加密这种要按真实长度切块的，外面又改了内容长度（比如压缩）时要问到真实值
+    async fn stored_length(&self, metadata: &File) -> Result<u64, ApiError> {
+        Ok(metadata.size)
+    }
+    //换主密钥用，只重新包数据密钥，不动数据本身；不加密的backend什么都不做
+    async fn rewrap_key(&self, _metadata: &File) -> Result<bool, ApiError> {
+        Ok(false)
+    }
+}
+
+//客户端没指定或者传的是FLAT，就换成服务端配置的默认存储
+//这样开了加密之后老客户端传上来的也会加密
+pub fn pick_storage_type(requested: &str, config: &MyConfig) -> String {
+    if requested.is_empty() || requested == "FLAT" {
+        config.default_storage_type.clone()
+    } else {
+        requested.to_string()
+    }
 }
 
 pub struct SaveResult {
-    pub size: u64,
+    pub size: u64, //逻辑大小，就是下载下来的大小
+    pub stored_size: u64, //实际占的空间，压缩、加密之后会不一样
     pub _path: String,
-    pub sha256: String
 }
 
+//backend都是Arc，clone一份很便宜
+//用的时候lock之后马上clone出来再放锁，别拿着全局锁去传S3，不然一个大文件上传会卡住所有读写
+#[derive(Clone)]
 pub struct StorageFactory {
     pub config: StorageConfig,
-    backends: HashMap<String, Box<dyn StorageBackend>>
+    backends: HashMap<String, Arc<dyn StorageBackend>>
 }
 
 
 impl StorageFactory {
     pub fn new(config: &MyConfig) -> Self {
         let config = StorageConfig {
-            flat_storage_path: config.flat_storage_path.clone()
+            flat_storage_path: config.flat_storage_path.clone(),
+            cache_storage_path: config.cache_storage_path.clone(),
+            s3_endpoint: config.s3_endpoint.clone(),
+            s3_region: config.s3_region.clone(),
+            s3_bucket: config.s3_bucket.clone(),
+            s3_access_key: config.s3_access_key.clone(),
+            s3_secret_key: config.s3_secret_key.clone(),
+            s3_part_size: config.s3_part_size,
+            encryption_keys: config.encryption_keys.clone(),
+            compression_level: config.compression_level,
         };
         Self { 
             config,
@@ -51,38 +144,37 @@ impl StorageFactory {
     }
 
     pub fn register_backend(&mut self, name: &str, backend: Box<dyn StorageBackend>) {
-        self.backends.insert(name.to_string(), backend);
+        self.backends.insert(name.to_string(), Arc::from(backend));
     }
 
-    pub fn get_backend(&self, name: &str) -> Option<&Box<dyn StorageBackend>> {
-        self.backends.get(name)
+    pub fn get_backend(&self, name: &str) -> Option<&dyn StorageBackend> {
+        self.backends.get(name).map(|backend| backend.as_ref())
     }
 
-    pub fn get_backend_check(&self, name: &str) -> Result<&Box<dyn StorageBackend>, ApiError> {
+    pub fn get_backend_check(&self, name: &str) -> Result<&dyn StorageBackend, ApiError> {
         match self.backends.get(name) {
-            Some(backend) => Ok(backend),
+            Some(backend) => Ok(backend.as_ref()),
             None => Err(ApiError::InternalServerError("Storage backend not found".to_string().into()))
         }
     }
 
-    pub async fn get_file(&self, metadata: &File) -> Result<AsyncFile, ApiError> {
+    pub async fn get_file_range(&self, metadata: &File, start: u64, length: u64) -> Result<FileStream, ApiError> {
         let backend = self.get_backend_check(&metadata.storage_type)?;
-        backend.get_file(metadata).await
+        backend.get_file_range(metadata, start, length).await
     }
 
-    pub async fn _save_file(&self, metadata: &File, file: &mut TempFile<'_>) -> Result<SaveResult, ApiError> {
+    pub async fn save_local_file(&self, metadata: &File, file_path: &Path) -> Result<SaveResult, ApiError> {
         let backend = self.get_backend_check(&metadata.storage_type)?;
-        backend._save_file(metadata, file).await
+        backend.save_local_file(metadata, file_path).await
     }
 
-    pub async fn check_sha256_and_save(&self, metadata: &File, sha256: Option<&str>, file:&mut TempFile<'_>) -> Result<SaveResult, ApiError> {
+    pub async fn delete_file(&self, metadata: &File) -> Result<(), ApiError> {
         let backend = self.get_backend_check(&metadata.storage_type)?;
-        let sha256 = sha256.unwrap_or(&metadata.sha256);
-        backend.check_sha256_and_save(metadata, sha256, file).await
+        backend.delete_file(metadata).await
     }
 
-    pub async fn delete_file(&self, metadata: &File) -> Result<(), ApiError> {
+    pub async fn rewrap_key(&self, metadata: &File) -> Result<bool, ApiError> {
         let backend = self.get_backend_check(&metadata.storage_type)?;
-        backend.delete_file(metadata).await
+        backend.rewrap_key(metadata).await
     }
 }
\ No newline at end of file
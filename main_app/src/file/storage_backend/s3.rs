@@ -0,0 +1,344 @@
+//S3兼容的对象存储，key就是metadata.path
+//endpoint可以指向minio之类的本地服务，统一用path style
+use std::path::{Path, PathBuf};
+
+use super::lib::{stream_to_temp_file, FileStream, SaveResult, StorageBackend, StorageConfig};
+use crate::db::models::File;
+use crate::libs::ApiError;
+use async_trait::async_trait;
+use aws_sdk_s3::config::{
+    BehaviorVersion, Credentials, Region, RequestChecksumCalculation, ResponseChecksumValidation,
+};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use rocket::tokio::fs;
+use rocket::tokio::fs::File as AsyncFile;
+use rocket::tokio::io::AsyncReadExt;
+
+//S3规定除了最后一块，每块至少5MiB
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+pub struct S3StorageBackend {
+    config: StorageConfig,
+    client: Client,
+}
+
+impl S3StorageBackend {
+    fn temp_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/s3/{}",
+            self.config.cache_storage_path,
+            uuid::Uuid::new_v4()
+        ))
+    }
+
+    async fn put_small(&self, key: &str, local_path: &Path) -> Result<(), ApiError> {
+        let body = match ByteStream::from_path(local_path).await {
+            Ok(body) => body,
+            Err(_) => return Err(ApiError::InternalServerError("Failed to read file".to_string().into())),
+        };
+        match self
+            .client
+            .put_object()
+            .bucket(&self.config.s3_bucket)
+            .key(key)
+            .body(body)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ApiError::InternalServerError("Failed to upload to S3".to_string().into())),
+        }
+    }
+
+    async fn put_multipart(&self, key: &str, local_path: &Path) -> Result<(), ApiError> {
+        let upload = match self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.config.s3_bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(upload) => upload,
+            Err(_) => return Err(ApiError::InternalServerError("Failed to upload to S3".to_string().into())),
+        };
+        let upload_id = upload.upload_id().unwrap_or_default().to_string();
+        if let Ok(parts) = self.upload_parts(key, &upload_id, local_path).await {
+            let completed = CompletedMultipartUpload::builder().set_parts(Some(parts)).build();
+            if self
+                .client
+                .complete_multipart_upload()
+                .bucket(&self.config.s3_bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .multipart_upload(completed)
+                .send()
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+        //失败了要abort，不然已经传上去的分片会一直占着空间
+        let _ = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.config.s3_bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .send()
+            .await;
+        Err(ApiError::InternalServerError("Failed to upload to S3".to_string().into()))
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        local_path: &Path,
+    ) -> Result<Vec<CompletedPart>, ApiError> {
+        let part_size = self.config.s3_part_size.max(MIN_PART_SIZE);
+        let file = match AsyncFile::open(local_path).await {
+            Ok(file) => file,
+            Err(_) => return Err(ApiError::InternalServerError("Failed to read file".to_string().into())),
+        };
+        let mut parts = vec![];
+        let mut part_number = 1;
+        let mut reader = file.take(0);
+        loop {
+            let mut buffer = Vec::with_capacity(part_size as usize);
+            reader.set_limit(part_size);
+            if reader.read_to_end(&mut buffer).await.is_err() {
+                return Err(ApiError::InternalServerError("Failed to read file".to_string().into()));
+            }
+            if buffer.is_empty() {
+                break;
+            }
+            let part = match self
+                .client
+                .upload_part()
+                .bucket(&self.config.s3_bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(buffer))
+                .send()
+                .await
+            {
+                Ok(part) => part,
+                Err(_) => return Err(ApiError::InternalServerError("Failed to upload to S3".to_string().into())),
+            };
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(|tag| tag.to_string()))
+                    .part_number(part_number)
+                    .build(),
+            );
+            part_number += 1;
+        }
+        Ok(parts)
+    }
+
+    //上传成功后本地文件就删掉，和flat的rename效果一样
+    async fn upload_local(&self, metadata: &File, local_path: &Path) -> Result<SaveResult, ApiError> {
+        let size = match fs::metadata(local_path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => return Err(ApiError::InternalServerError("Failed to read file".to_string().into())),
+        };
+        let result = if size > self.config.s3_part_size.max(MIN_PART_SIZE) {
+            self.put_multipart(&metadata.path, local_path).await
+        } else {
+            self.put_small(&metadata.path, local_path).await
+        };
+        let _ = fs::remove_file(local_path).await;
+        result?;
+        Ok(SaveResult {
+            size,
+            stored_size: size,
+            _path: metadata.path.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3StorageBackend {
+    fn new(config: &StorageConfig) -> Self {
+        let mut builder = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(config.s3_region.clone()))
+            .credentials_provider(Credentials::new(
+                config.s3_access_key.clone(),
+                config.s3_secret_key.clone(),
+                None,
+                None,
+                "rustcloud",
+            ))
+            .force_path_style(true)
+            //很多S3兼容实现不认新版SDK默认加的checksum头
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+        if !config.s3_endpoint.is_empty() {
+            builder = builder.endpoint_url(config.s3_endpoint.clone());
+        }
+        Self {
+            config: config.clone(),
+            client: Client::from_conf(builder.build()),
+        }
+    }
+
+    async fn save_local_file(
+        &self,
+        metadata: &File,
+        local_path: &Path,
+    ) -> Result<SaveResult, ApiError> {
+        self.upload_local(metadata, local_path).await
+    }
+
+    //只有要完整AsyncFile的地方才会走这里，先下载到cache
+    async fn get_file(&self, metadata: &File) -> Result<AsyncFile, ApiError> {
+        let body = self.get_file_range(metadata, 0, metadata.size).await?;
+        stream_to_temp_file(body, &self.temp_path()).await
+    }
+
+    async fn get_file_range(&self, metadata: &File, start: u64, length: u64) -> Result<FileStream, ApiError> {
+        if length == 0 {
+            return Ok(Box::pin(rocket::tokio::io::empty()));
+        }
+        match self
+            .client
+            .get_object()
+            .bucket(&self.config.s3_bucket)
+            .key(&metadata.path)
+            .range(format!("bytes={}-{}", start, start + length - 1))
+            .send()
+            .await
+        {
+            Ok(object) => Ok(Box::pin(object.body.into_async_read())),
+            Err(_) => Err(ApiError::NotFound("File not found in storage".to_string().into())),
+        }
+    }
+
+    async fn delete_file(&self, metadata: &File) -> Result<(), ApiError> {
+        let _ = self
+            .client
+            .delete_object()
+            .bucket(&self.config.s3_bucket)
+            .key(&metadata.path)
+            .send()
+            .await;
+        Ok(())
+    }
+
+    async fn stored_length(&self, metadata: &File) -> Result<u64, ApiError> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.config.s3_bucket)
+            .key(&metadata.path)
+            .send()
+            .await
+        {
+            Ok(object) => Ok(object.content_length().unwrap_or(0) as u64),
+            Err(_) => Err(ApiError::NotFound("File not found in storage".to_string().into())),
+        }
+    }
+}
+
+//要先起compose.yaml里的minio_dev，然后cargo test -- --ignored
+//地址和账号默认就是compose里的，可以用RC_TEST_S3_*覆盖
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::FileType;
+    use mongodb::bson::oid::ObjectId;
+
+    fn env_or(name: &str, default: &str) -> String {
+        std::env::var(name).unwrap_or_else(|_| default.to_string())
+    }
+
+    fn test_config() -> StorageConfig {
+        StorageConfig {
+            flat_storage_path: std::env::temp_dir().join("rc_s3_test/flat").to_string_lossy().to_string(),
+            cache_storage_path: std::env::temp_dir().join("rc_s3_test/cache").to_string_lossy().to_string(),
+            s3_endpoint: env_or("RC_TEST_S3_ENDPOINT", "http://localhost:9000"),
+            s3_region: "us-east-1".to_string(),
+            s3_bucket: env_or("RC_TEST_S3_BUCKET", "rc-test"),
+            s3_access_key: env_or("RC_TEST_S3_ACCESS_KEY", "minioadmin"),
+            s3_secret_key: env_or("RC_TEST_S3_SECRET_KEY", "minioadmin"),
+            s3_part_size: MIN_PART_SIZE,
+            encryption_keys: "".to_string(),
+            compression_level: 3,
+        }
+    }
+
+    async fn backend() -> S3StorageBackend {
+        let backend = S3StorageBackend::new(&test_config());
+        //已经有了会报错，不用管
+        let _ = backend.client.create_bucket().bucket(&backend.config.s3_bucket).send().await;
+        backend
+    }
+
+    fn test_file(size: u64) -> File {
+        let id = ObjectId::new();
+        File {
+            type_: FileType::File,
+            size,
+            path: id.to_hex(),
+            storage_type: "S3".to_string(),
+            ..File::new_folder("test", &id, &id, Some(id))
+        }
+    }
+
+    async fn write_local(data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rc_s3_test/{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(&path, data).await.unwrap();
+        path
+    }
+
+    async fn read_all(mut stream: FileStream) -> Vec<u8> {
+        let mut buffer = vec![];
+        stream.read_to_end(&mut buffer).await.unwrap();
+        buffer
+    }
+
+    //比分片大，走multipart
+    #[rocket::async_test]
+    #[ignore = "needs the minio_dev service from compose.yaml"]
+    async fn multipart_round_trip() {
+        let backend = backend().await;
+        let data: Vec<u8> = (0..MIN_PART_SIZE * 2 + 12345).map(|i| (i * 31 % 251) as u8).collect();
+        let file = test_file(data.len() as u64);
+        let local = write_local(&data).await;
+
+        let saved = backend.save_local_file(&file, &local).await.unwrap();
+        assert_eq!(saved.size, data.len() as u64);
+        assert!(!local.exists());
+
+        let whole = read_all(backend.get_file_range(&file, 0, file.size).await.unwrap()).await;
+        assert_eq!(whole, data);
+        let start = MIN_PART_SIZE - 10;
+        let part = read_all(backend.get_file_range(&file, start, 20).await.unwrap()).await;
+        assert_eq!(part, &data[start as usize..start as usize + 20]);
+
+        backend.delete_file(&file).await.unwrap();
+        assert!(backend.get_file_range(&file, 0, 1).await.is_err());
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the minio_dev service from compose.yaml"]
+    async fn small_round_trip() {
+        let backend = backend().await;
+        let data = b"hello s3".to_vec();
+        let file = test_file(data.len() as u64);
+        let local = write_local(&data).await;
+
+        backend.save_local_file(&file, &local).await.unwrap();
+        let mut read = vec![];
+        backend.get_file(&file).await.unwrap().read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+        backend.delete_file(&file).await.unwrap();
+    }
+}
@@ -0,0 +1,561 @@
+//tus 1.0 协议，支持creation, termination, checksum扩展
+//创建时走和metadata/create一样的暂存流程，传完以后和upload_file一样写入
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::{File, FileExtraMetadata, FileType};
+use crate::file_metadata::routes::{stage_metadata, MetaDataCreateRequest};
+use crate::libs::ApiError;
+use crate::MyConfig;
+use base64::Engine;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::fs;
+use rocket::tokio::fs::{File as AsyncFile, OpenOptions};
+use rocket::tokio::io::AsyncWriteExt;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use super::super::lib::{
+    commit_uploaded_file, discard_staged_upload, get_local_file_type, load_staged_metadata, stamp_device,
+};
+use super::super::storage_backend::blob_storage::store_local_file;
+use super::super::storage_backend::lib::{file_sha256, StorageFactory};
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,checksum";
+const TUS_LIVE_SECOND: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TusUpload {
+    //客户端在Upload-Metadata里给了sha256的话，传完要校验
+    pub declared_sha256: Option<String>,
+}
+
+pub fn tus_key(uuid: &str) -> String {
+    format!("{}_tus", uuid)
+}
+
+pub fn partial_path(uuid: &str, config: &MyConfig) -> PathBuf {
+    PathBuf::from(format!("{}/tus/{}", config.cache_storage_path, uuid))
+}
+
+pub struct TusHeaders {
+    pub resumable: Option<String>,
+    pub upload_offset: Option<String>,
+    pub upload_length: Option<String>,
+    pub upload_metadata: Option<String>,
+    pub upload_checksum: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl TusHeaders {
+    fn check_version(&self) -> Result<(), TusResponse> {
+        match self.resumable.as_deref() {
+            Some(TUS_VERSION) => Ok(()),
+            _ => Err(TusResponse::new(Status::PreconditionFailed)
+                .header("Tus-Version", TUS_VERSION)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let get = |name: &str| headers.get_one(name).map(|v| v.to_string());
+        Outcome::Success(TusHeaders {
+            resumable: get("Tus-Resumable"),
+            upload_offset: get("Upload-Offset"),
+            upload_length: get("Upload-Length"),
+            upload_metadata: get("Upload-Metadata"),
+            upload_checksum: get("Upload-Checksum"),
+            content_type: get("Content-Type"),
+        })
+    }
+}
+
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        Self {
+            status,
+            headers: vec![Header::new("Tus-Resumable", TUS_VERSION)],
+        }
+    }
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+        for header in self.headers {
+            response.header_adjoin(header);
+        }
+        Ok(response.finalize())
+    }
+}
+
+#[derive(Responder)]
+pub enum TusResult {
+    Tus(TusResponse),
+    Error(ApiError),
+}
+
+impl From<ApiError> for TusResult {
+    fn from(err: ApiError) -> Self {
+        TusResult::Error(err)
+    }
+}
+
+impl From<TusResponse> for TusResult {
+    fn from(response: TusResponse) -> Self {
+        TusResult::Tus(response)
+    }
+}
+
+//Upload-Metadata: key base64,key base64
+fn parse_upload_metadata(raw: &str) -> Result<Vec<(String, String)>, ApiError> {
+    let mut result = vec![];
+    for pair in raw.split(',') {
+        let pair = pair.trim();
+        if pair.is_empty() {
+            continue;
+        }
+        let mut iter = pair.splitn(2, ' ');
+        let key = iter.next().unwrap_or_default().to_string();
+        let value = match iter.next() {
+            Some(value) => base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok()),
+            None => Some("".to_string()),
+        };
+        match value {
+            Some(value) => result.push((key, value)),
+            None => return Err(ApiError::BadRequest("Invalid Upload-Metadata".to_string().into())),
+        }
+    }
+    Ok(result)
+}
+
+//Upload-Checksum: sha256 base64
+fn parse_checksum(raw: &str) -> Result<Vec<u8>, TusResponse> {
+    let mut iter = raw.splitn(2, ' ');
+    let algorithm = iter.next().unwrap_or_default();
+    if algorithm != "sha256" {
+        return Err(TusResponse::new(Status::BadRequest));
+    }
+    match iter
+        .next()
+        .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v.trim()).ok())
+    {
+        Some(checksum) => Ok(checksum),
+        None => Err(TusResponse::new(Status::BadRequest)),
+    }
+}
+
+async fn current_offset(uuid: &str, config: &MyConfig) -> u64 {
+    match fs::metadata(partial_path(uuid, config)).await {
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+async fn load_tus_upload(uuid: &str, redis: &Redis) -> TusUpload {
+    if !redis.exists(tus_key(uuid)).await {
+        return TusUpload { declared_sha256: None };
+    }
+    let upload: String = redis.get(tus_key(uuid)).await;
+    serde_json::from_str(upload.as_str()).unwrap_or(TusUpload { declared_sha256: None })
+}
+
+#[options("/")]
+pub async fn tus_options(limits: &Limits) -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Checksum-Algorithm", "sha256")
+        .header("Tus-Max-Size", limits.get("file").unwrap_or(4.gibibytes()).as_u64())
+}
+
+#[post("/")]
+pub async fn create_upload(
+    headers: TusHeaders,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+    limits: &Limits,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> TusResult {
+    if let Err(response) = headers.check_version() {
+        return response.into();
+    }
+    let length = match headers.upload_length.as_deref().map(|v| v.parse::<u64>()) {
+        Some(Ok(length)) => length,
+        _ => return ApiError::BadRequest("Upload-Length required".to_string().into()).into(),
+    };
+    let max_size = limits.get("file").unwrap_or(4.gibibytes()).as_u64();
+    if length > max_size {
+        return TusResponse::new(Status::PayloadTooLarge).into();
+    }
+    let upload_metadata = match parse_upload_metadata(headers.upload_metadata.as_deref().unwrap_or("")) {
+        Ok(upload_metadata) => upload_metadata,
+        Err(err) => return err.into(),
+    };
+    let find = |keys: &[&str]| {
+        upload_metadata
+            .iter()
+            .find(|(k, _)| keys.contains(&k.as_str()))
+            .map(|(_, v)| v.clone())
+    };
+    let name = match find(&["filename", "name"]) {
+        Some(name) if !name.is_empty() => name,
+        _ => return ApiError::BadRequest("filename required in Upload-Metadata".to_string().into()).into(),
+    };
+    //hash要等传完才算，这里不填，也就不做ref
+    let request = MetaDataCreateRequest {
+        name,
+        type_: FileType::File,
+        father: find(&["father"]).unwrap_or(user.root_id.to_hex()),
+        size: length,
+        sha256: "".to_string(),
+        storage_type: find(&["storage_type"]).unwrap_or_default(),
+    };
+    let staged = match stage_metadata(request, &user, redis, mongo, config, storage_factory).await {
+        Ok(staged) => staged,
+        Err(err) => return err.into(),
+    };
+    let upload = TusUpload {
+        declared_sha256: find(&["sha256"]).map(|v| v.to_lowercase()),
+    };
+    let _: () = redis
+        .set(tus_key(&staged.id), serde_json::to_string(&upload).unwrap())
+        .await;
+    let _: () = redis.expire(tus_key(&staged.id), TUS_LIVE_SECOND).await;
+
+    let path = partial_path(&staged.id, config);
+    let _ = fs::create_dir_all(path.parent().unwrap()).await;
+    if AsyncFile::create(&path).await.is_err() {
+        discard_staged_upload(&staged.id, redis, config).await;
+        return ApiError::InternalServerError("Failed to create upload".to_string().into()).into();
+    }
+    TusResponse::new(Status::Created)
+        .header("Location", format!("/tus/{}", staged.id))
+        .header("Upload-Offset", 0)
+        .into()
+}
+
+#[head("/<uuid>")]
+pub async fn upload_offset(
+    uuid: &str,
+    headers: TusHeaders,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> TusResult {
+    if let Err(response) = headers.check_version() {
+        return response.into();
+    }
+    let metadata = match load_staged_metadata(uuid, &user, redis, mongo).await {
+        Ok(metadata) => metadata,
+        Err(err) => return err.into(),
+    };
+    TusResponse::new(Status::Ok)
+        .header("Upload-Offset", current_offset(uuid, config).await)
+        .header("Upload-Length", metadata.size)
+        .header("Cache-Control", "no-store")
+        .into()
+}
+
+#[patch("/<uuid>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn append_upload(
+    uuid: &str,
+    headers: TusHeaders,
+    data: Data<'_>,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> TusResult {
+    if let Err(response) = headers.check_version() {
+        return response.into();
+    }
+    if headers.content_type.as_deref() != Some("application/offset+octet-stream") {
+        return TusResponse::new(Status::UnsupportedMediaType).into();
+    }
+    let metadata = match load_staged_metadata(uuid, &user, redis, mongo).await {
+        Ok(metadata) => metadata,
+        Err(err) => return err.into(),
+    };
+    let offset = match headers.upload_offset.as_deref().map(|v| v.parse::<u64>()) {
+        Some(Ok(offset)) => offset,
+        _ => return ApiError::BadRequest("Upload-Offset required".to_string().into()).into(),
+    };
+    let path = partial_path(uuid, config);
+    if offset != current_offset(uuid, config).await {
+        return TusResponse::new(Status::Conflict).into();
+    }
+    let checksum = match headers.upload_checksum.as_deref().map(parse_checksum) {
+        Some(Ok(checksum)) => Some(checksum),
+        Some(Err(response)) => return response.into(),
+        None => None,
+    };
+
+    let mut partial = match OpenOptions::new().append(true).open(&path).await {
+        Ok(partial) => partial,
+        Err(_) => return ApiError::NotFound("Upload not found".to_string().into()).into(),
+    };
+    let remaining = metadata.size - offset;
+    let written = if let Some(checksum) = checksum {
+        //有checksum的话先落到临时文件里，校验过了再追加
+        let tmp_path = path.with_extension("patch");
+        let written = match data.open(remaining.bytes()).into_file(&tmp_path).await {
+            Ok(written) => written,
+            Err(_) => {
+                return ApiError::InternalServerError("Failed to save chunk".to_string().into()).into();
+            }
+        };
+        if !written.is_complete() {
+            let _ = fs::remove_file(&tmp_path).await;
+            return TusResponse::new(Status::PayloadTooLarge).into();
+        }
+        let hash = file_sha256(AsyncFile::open(&tmp_path).await.unwrap()).await;
+        if hash != checksum.iter().map(|b| format!("{:02x}", b)).collect::<String>() {
+            let _ = fs::remove_file(&tmp_path).await;
+            return TusResponse::new(Status::new(460)).into();
+        }
+        let mut tmp = AsyncFile::open(&tmp_path).await.unwrap();
+        let copied = rocket::tokio::io::copy(&mut tmp, &mut partial).await;
+        let _ = fs::remove_file(&tmp_path).await;
+        match copied {
+            Ok(copied) => copied,
+            Err(_) => {
+                let _ = partial.set_len(offset).await;
+                return ApiError::InternalServerError("Failed to save chunk".to_string().into()).into();
+            }
+        }
+    } else {
+        //没有checksum就直接追加，断线的时候已经收到的部分也算数
+        match data.open(remaining.bytes()).stream_to(&mut partial).await {
+            Ok(n) if n.complete => n.written,
+            Ok(_) => {
+                let _ = partial.set_len(offset).await;
+                return TusResponse::new(Status::PayloadTooLarge).into();
+            }
+            Err(_) => {
+                let _ = partial.flush().await;
+                return ApiError::InternalServerError("Failed to save chunk".to_string().into()).into();
+            }
+        }
+    };
+    let _ = partial.flush().await;
+    drop(partial);
+    let new_offset = offset + written;
+
+    if new_offset == metadata.size {
+        if let Err(err) = finish_upload(uuid, metadata, &user, mongo, redis, config, storage_factory).await {
+            return err.into();
+        }
+    } else {
+        //还在传的话续期
+        let _: () = redis.expire(uuid, TUS_LIVE_SECOND).await;
+        let _: () = redis.expire(tus_key(uuid), TUS_LIVE_SECOND).await;
+    }
+    TusResponse::new(Status::NoContent)
+        .header("Upload-Offset", new_offset)
+        .into()
+}
+
+async fn finish_upload(
+    uuid: &str,
+    metadata: File,
+    user: &AuthenticatedUser,
+    mongo: &MongoDb,
+    redis: &Redis,
+    config: &MyConfig,
+    storage_factory: &Mutex<StorageFactory>,
+) -> Result<(), ApiError> {
+    let path = partial_path(uuid, config);
+    let upload = load_tus_upload(uuid, redis).await;
+    let hash = file_sha256(AsyncFile::open(&path).await.unwrap()).await;
+    if let Some(declared) = upload.declared_sha256 {
+        if declared != hash {
+            //整个文件都不对，只能从头再来
+            let _ = AsyncFile::create(&path).await;
+            return Err(ApiError::BadRequest("Hash not match".to_string().into()));
+        }
+    }
+    let file_type = get_local_file_type(&path).await;
+    let metadata = File {
+        sha256: hash,
+        ..metadata
+    };
+    let factory = storage_factory.lock().await.clone();
+    let metadata = store_local_file(mongo, &factory, metadata, &path).await?;
+
+    let metadata = File {
+        extra_metadata: file_type.map(|t| FileExtraMetadata {
+            detected_mime_type: Some(t.mime_type().to_string()),
+            ..Default::default()
+        }),
+        ..metadata
+    };
+    let metadata = stamp_device(metadata, user, mongo).await;
+    commit_uploaded_file(mongo, storage_factory, &metadata, config.default_quota).await?;
+    discard_staged_upload(uuid, redis, config).await;
+    Ok(())
+}
+
+#[delete("/<uuid>")]
+pub async fn terminate_upload(
+    uuid: &str,
+    headers: TusHeaders,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> TusResult {
+    if let Err(response) = headers.check_version() {
+        return response.into();
+    }
+    if let Err(err) = load_staged_metadata(uuid, &user, redis, mongo).await {
+        return err.into();
+    }
+    discard_staged_upload(uuid, redis, config).await;
+    TusResponse::new(Status::NoContent).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+    use rocket::local::asynchronous::{Client, LocalResponse};
+    use sha2::{Digest, Sha256};
+
+    const CONTENT: &[u8] = b"hello resumable world";
+
+    fn b64(value: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(value)
+    }
+
+    #[test]
+    fn parses_upload_metadata() {
+        let parsed = parse_upload_metadata(&format!("filename {}, is_confidential", b64(b"a b.txt"))).unwrap();
+        assert_eq!(parsed, vec![("filename".to_string(), "a b.txt".to_string()), ("is_confidential".to_string(), "".to_string())]);
+        assert!(parse_upload_metadata("filename not-base64!").is_err());
+    }
+
+    #[test]
+    fn parses_sha256_checksum_only() {
+        assert_eq!(parse_checksum(&format!("sha256 {}", b64(&[1, 2, 3]))).ok(), Some(vec![1, 2, 3]));
+        assert!(parse_checksum(&format!("md5 {}", b64(&[1, 2, 3]))).is_err());
+        assert!(parse_checksum("sha256").is_err());
+    }
+
+    fn offset(response: &LocalResponse<'_>) -> Option<u64> {
+        response.headers().get_one("Upload-Offset").and_then(|v| v.parse().ok())
+    }
+
+    async fn patch<'c>(client: &'c Client, auth: &str, location: &str, offset: u64, body: &[u8], checksum: Option<&[u8]>) -> LocalResponse<'c> {
+        let mut request = client
+            .patch(location.to_string())
+            .header(Header::new("Authorization", auth.to_string()))
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .header(Header::new("Content-Type", "application/offset+octet-stream"))
+            .header(Header::new("Upload-Offset", offset.to_string()))
+            .body(body);
+        if let Some(checksum) = checksum {
+            request = request.header(Header::new("Upload-Checksum", format!("sha256 {}", b64(checksum))));
+        }
+        request.dispatch().await
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev and redis_dev services from compose.yaml"]
+    async fn appends_at_offset_and_finishes() {
+        let mongo = test_support::mongo().await;
+        let redis = test_support::redis().await;
+        let (config, factory) = test_support::storage();
+        let client = test_support::client(
+            &mongo,
+            &redis,
+            &config,
+            factory,
+            vec![("/tus", routes![tus_options, create_upload, upload_offset, append_upload, terminate_upload])],
+        )
+        .await;
+        let (_, auth) = test_support::login(&mongo, &redis, &config).await;
+        let sha256 = format!("{:x}", Sha256::digest(CONTENT));
+
+        let created = client
+            .post("/tus")
+            .header(Header::new("Authorization", auth.clone()))
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .header(Header::new("Upload-Length", CONTENT.len().to_string()))
+            .header(Header::new(
+                "Upload-Metadata",
+                format!("filename {},sha256 {}", b64(b"a.txt"), b64(sha256.as_bytes())),
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(created.status(), Status::Created);
+        assert_eq!(offset(&created), Some(0));
+        let location = created.headers().get_one("Location").unwrap().to_string();
+        let id = location.trim_start_matches("/tus/").to_string();
+
+        let first = patch(&client, &auth, &location, 0, &CONTENT[..8], None).await;
+        assert_eq!(first.status(), Status::NoContent);
+        assert_eq!(offset(&first), Some(8));
+        //offset对不上的不收
+        assert_eq!(patch(&client, &auth, &location, 0, &CONTENT[..8], None).await.status(), Status::Conflict);
+        //checksum不对的整段丢掉，offset不动
+        let wrong = patch(&client, &auth, &location, 8, &CONTENT[8..12], Some(&Sha256::digest(b"other"))).await;
+        assert_eq!(wrong.status(), Status::new(460));
+        let head = client
+            .head(location.clone())
+            .header(Header::new("Authorization", auth.clone()))
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .dispatch()
+            .await;
+        assert_eq!(offset(&head), Some(8));
+        assert_eq!(head.headers().get_one("Upload-Length"), Some(CONTENT.len().to_string().as_str()));
+
+        let second = patch(&client, &auth, &location, 8, &CONTENT[8..12], Some(&Sha256::digest(&CONTENT[8..12]))).await;
+        assert_eq!(offset(&second), Some(12));
+        let last = patch(&client, &auth, &location, 12, &CONTENT[12..], None).await;
+        assert_eq!(last.status(), Status::NoContent);
+        assert_eq!(offset(&last), Some(CONTENT.len() as u64));
+
+        let file = mongo
+            .database
+            .collection::<File>("files")
+            .find_one(doc! { "_id": ObjectId::parse_str(&id).unwrap() })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.sha256, sha256);
+        assert_eq!(file.size, CONTENT.len() as u64);
+        assert!(!partial_path(&id, &config).exists());
+        mongo.database.drop().await.unwrap();
+    }
+}
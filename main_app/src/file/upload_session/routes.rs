@@ -0,0 +1,414 @@
+//分片上传
+//先用metadata/create拿到id，再对这个id开session，按序号PUT分片，最后finalize合并
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::{File, FileExtraMetadata};
+use crate::libs::ApiError;
+use crate::MyConfig;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::tokio::fs;
+use rocket::tokio::fs::File as AsyncFile;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use super::super::lib::{commit_uploaded_file, get_local_file_type, load_staged_metadata, stamp_device};
+use super::super::storage_backend::blob_storage::store_local_file;
+use super::super::storage_backend::lib::StorageFactory;
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+
+const SESSION_LIVE_SECOND: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenSessionRequest {
+    pub chunk_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionStatusResponse {
+    pub id: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    pub received: Vec<u64>,
+}
+
+pub fn session_key(uuid: &str) -> String {
+    format!("{}_session", uuid)
+}
+
+pub fn session_dir(uuid: &str, config: &MyConfig) -> PathBuf {
+    PathBuf::from(format!("{}/sessions/{}", config.cache_storage_path, uuid))
+}
+
+fn chunk_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{}.part", index))
+}
+
+async fn load_session(
+    uuid: &str,
+    user: &AuthenticatedUser,
+    redis: &Redis,
+    mongo: &MongoDb,
+) -> Result<(File, UploadSession), ApiError> {
+    let metadata = load_staged_metadata(uuid, user, redis, mongo).await?;
+    if !redis.exists(session_key(uuid)).await {
+        return Err(ApiError::NotFound("Upload session not found".to_string().into()));
+    }
+    let session: String = redis.get(session_key(uuid)).await;
+    match serde_json::from_str(session.as_str()) {
+        Ok(session) => Ok((metadata, session)),
+        Err(_) => Err(ApiError::InternalServerError("Broken upload session".to_string().into())),
+    }
+}
+
+async fn received_chunks(dir: &Path) -> Vec<u64> {
+    let mut received = vec![];
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return received,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if let Some(index) = name.strip_suffix(".part") {
+            if let Ok(index) = index.parse::<u64>() {
+                received.push(index);
+            }
+        }
+    }
+    received.sort();
+    received
+}
+
+fn expected_chunk_size(metadata: &File, session: &UploadSession, index: u64) -> u64 {
+    if index + 1 == session.chunk_count {
+        metadata.size - session.chunk_size * index
+    } else {
+        session.chunk_size
+    }
+}
+
+#[post("/<uuid>", data = "<request>")]
+pub async fn open_session(
+    uuid: &str,
+    request: Json<OpenSessionRequest>,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+    limits: &Limits,
+) -> Result<Json<SessionStatusResponse>, ApiError> {
+    let metadata = load_staged_metadata(uuid, &user, redis, mongo).await?;
+    let max_chunk_size = limits.get("file").unwrap_or(4.gibibytes()).as_u64();
+    if request.chunk_size == 0 || request.chunk_size > max_chunk_size {
+        return Err(ApiError::BadRequest("Invalid chunk size".to_string().into()));
+    }
+    //重复open的话直接返回已有的session，方便客户端断线后恢复
+    if redis.exists(session_key(uuid)).await {
+        let (metadata, session) = load_session(uuid, &user, redis, mongo).await?;
+        return Ok(Json(SessionStatusResponse {
+            id: uuid.to_string(),
+            size: metadata.size,
+            chunk_size: session.chunk_size,
+            chunk_count: session.chunk_count,
+            received: received_chunks(&session_dir(uuid, config)).await,
+        }));
+    }
+    let session = UploadSession {
+        chunk_size: request.chunk_size,
+        chunk_count: metadata.size.div_ceil(request.chunk_size),
+    };
+    if fs::create_dir_all(session_dir(uuid, config)).await.is_err() {
+        return Err(ApiError::InternalServerError("Failed to create upload session".to_string().into()));
+    }
+    let _: () = redis
+        .set(session_key(uuid), serde_json::to_string(&session).unwrap())
+        .await;
+    let _: () = redis.expire(session_key(uuid), SESSION_LIVE_SECOND).await;
+    //metadata也续一下期，不然上传到一半过期了
+    let _: () = redis.expire(uuid, SESSION_LIVE_SECOND).await;
+    Ok(Json(SessionStatusResponse {
+        id: uuid.to_string(),
+        size: metadata.size,
+        chunk_size: session.chunk_size,
+        chunk_count: session.chunk_count,
+        received: vec![],
+    }))
+}
+
+#[get("/<uuid>")]
+pub async fn get_session(
+    uuid: &str,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<SessionStatusResponse>, ApiError> {
+    let (metadata, session) = load_session(uuid, &user, redis, mongo).await?;
+    Ok(Json(SessionStatusResponse {
+        id: uuid.to_string(),
+        size: metadata.size,
+        chunk_size: session.chunk_size,
+        chunk_count: session.chunk_count,
+        received: received_chunks(&session_dir(uuid, config)).await,
+    }))
+}
+
+#[put("/<uuid>/<index>", data = "<chunk>")]
+pub async fn upload_chunk(
+    uuid: &str,
+    index: u64,
+    chunk: Data<'_>,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Result<status::NoContent, ApiError> {
+    let (metadata, session) = load_session(uuid, &user, redis, mongo).await?;
+    if index >= session.chunk_count {
+        return Err(ApiError::BadRequest("Chunk index out of range".to_string().into()));
+    }
+    let expected = expected_chunk_size(&metadata, &session, index);
+    let dir = session_dir(uuid, config);
+    //先写到tmp再rename，避免半截的分片被当成已收到
+    let tmp_path = dir.join(format!("{}.tmp", index));
+    let written = match chunk.open(expected.bytes()).into_file(&tmp_path).await {
+        Ok(written) => written,
+        Err(_) => {
+            return Err(ApiError::InternalServerError("Failed to save chunk".to_string().into()));
+        }
+    };
+    if !written.is_complete() || written.n.written != expected {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(ApiError::BadRequest("Chunk size not match".to_string().into()));
+    }
+    if fs::rename(&tmp_path, chunk_path(&dir, index)).await.is_err() {
+        return Err(ApiError::InternalServerError("Failed to save chunk".to_string().into()));
+    }
+    Ok(status::NoContent)
+}
+
+#[post("/<uuid>/finalize")]
+pub async fn finalize_session(
+    uuid: &str,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    redis: &rocket::State<Redis>,
+    config: &rocket::State<MyConfig>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<status::NoContent, ApiError> {
+    let (metadata, session) = load_session(uuid, &user, redis, mongo).await?;
+    let dir = session_dir(uuid, config);
+    let received = received_chunks(&dir).await;
+    if received.len() as u64 != session.chunk_count {
+        return Err(ApiError::BadRequest("Missing chunks".to_string().into()));
+    }
+
+    //边合并边算sha256
+    let assembled_path = dir.join("assembled");
+    let mut assembled = match AsyncFile::create(&assembled_path).await {
+        Ok(file) => file,
+        Err(_) => {
+            return Err(ApiError::InternalServerError("Failed to assemble chunks".to_string().into()));
+        }
+    };
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    for index in 0..session.chunk_count {
+        let mut part = match AsyncFile::open(chunk_path(&dir, index)).await {
+            Ok(part) => part,
+            Err(_) => return Err(ApiError::BadRequest("Missing chunks".to_string().into())),
+        };
+        loop {
+            let n = match part.read(&mut buffer).await {
+                Ok(n) => n,
+                Err(_) => {
+                    return Err(ApiError::InternalServerError("Failed to assemble chunks".to_string().into()));
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            if assembled.write_all(&buffer[..n]).await.is_err() {
+                return Err(ApiError::InternalServerError("Failed to assemble chunks".to_string().into()));
+            }
+            size += n as u64;
+        }
+    }
+    let _ = assembled.flush().await;
+    drop(assembled);
+
+    let hash = format!("{:x}", hasher.finalize());
+    if hash != metadata.sha256 || size != metadata.size {
+        let _ = fs::remove_file(&assembled_path).await;
+        return Err(ApiError::BadRequest("Hash not match".to_string().into()));
+    }
+
+    let file_type = get_local_file_type(&assembled_path).await;
+    let factory = storage_factory.lock().await.clone();
+    let metadata = store_local_file(mongo, &factory, metadata, &assembled_path).await?;
+
+    let metadata = File {
+        extra_metadata: file_type.map(|t| FileExtraMetadata {
+            detected_mime_type: Some(t.mime_type().to_string()),
+            ..Default::default()
+        }),
+        ..metadata
+    };
+    let metadata = stamp_device(metadata, &user, mongo).await;
+    commit_uploaded_file(mongo, storage_factory, &metadata, config.default_quota).await?;
+
+    let _: () = redis.delete(uuid).await;
+    let _: () = redis.delete(session_key(uuid)).await;
+    let _ = fs::remove_dir_all(&dir).await;
+    Ok(status::NoContent)
+}
+
+#[delete("/<uuid>")]
+pub async fn abort_session(
+    uuid: &str,
+    user: AuthenticatedUser,
+    redis: &rocket::State<Redis>,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Result<status::NoContent, ApiError> {
+    //只清理session，metadata留着，可以重新open或者走普通上传
+    let _ = load_session(uuid, &user, redis, mongo).await?;
+    let _: () = redis.delete(session_key(uuid)).await;
+    let _ = fs::remove_dir_all(session_dir(uuid, config)).await;
+    Ok(status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+
+    const CONTENT: &[u8] = b"hello chunked world!";
+
+    async fn put_chunk(client: &Client, auth: &str, id: &str, index: u64, body: &[u8]) -> Status {
+        client
+            .put(format!("/file/session/{}/{}", id, index))
+            .header(Header::new("Authorization", auth.to_string()))
+            .body(body)
+            .dispatch()
+            .await
+            .status()
+    }
+
+    async fn status(client: &Client, auth: &str, id: &str) -> SessionStatusResponse {
+        let response = client
+            .get(format!("/file/session/{}", id))
+            .header(Header::new("Authorization", auth.to_string()))
+            .dispatch()
+            .await;
+        response.into_json().await.unwrap()
+    }
+
+    #[test]
+    fn last_chunk_gets_the_remainder() {
+        let metadata = File { size: 20, ..File::new_folder("a", &ObjectId::new(), &ObjectId::new(), None) };
+        let session = UploadSession { chunk_size: 8, chunk_count: 3 };
+        assert_eq!(expected_chunk_size(&metadata, &session, 0), 8);
+        assert_eq!(expected_chunk_size(&metadata, &session, 2), 4);
+        let metadata = File { size: 16, ..metadata };
+        let session = UploadSession { chunk_size: 8, chunk_count: 2 };
+        assert_eq!(expected_chunk_size(&metadata, &session, 1), 8);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev and redis_dev services from compose.yaml"]
+    async fn chunks_resume_and_finalize() {
+        let mongo = test_support::mongo().await;
+        let redis = test_support::redis().await;
+        let (config, factory) = test_support::storage();
+        let client = test_support::client(
+            &mongo,
+            &redis,
+            &config,
+            factory,
+            vec![
+                ("/metadata", routes![crate::file_metadata::routes::add_metadata]),
+                ("/file/session", routes![open_session, get_session, upload_chunk, finalize_session, abort_session]),
+            ],
+        )
+        .await;
+        let (user, auth) = test_support::login(&mongo, &redis, &config).await;
+        let auth_header = || Header::new("Authorization", auth.clone());
+
+        let staged: serde_json::Value = client
+            .post("/metadata/create")
+            .header(auth_header())
+            .header(ContentType::JSON)
+            .body(
+                serde_json::json!({
+                    "name": "a.txt",
+                    "type_": "File",
+                    "father": user.root_id.to_hex(),
+                    "size": CONTENT.len(),
+                    "sha256": format!("{:x}", Sha256::digest(CONTENT)),
+                })
+                .to_string(),
+            )
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let id = staged["id"].as_str().unwrap().to_string();
+        let open = || {
+            client
+                .post(format!("/file/session/{}", id))
+                .header(auth_header())
+                .header(ContentType::JSON)
+                .body(r#"{"chunk_size": 8}"#)
+        };
+        let opened: SessionStatusResponse = open().dispatch().await.into_json().await.unwrap();
+        assert_eq!(opened.chunk_count, 3);
+
+        assert_eq!(put_chunk(&client, &auth, &id, 1, &CONTENT[8..16]).await, Status::NoContent);
+        //最后一片只有4字节，多了少了都不收
+        assert_eq!(put_chunk(&client, &auth, &id, 2, &CONTENT[16..19]).await, Status::BadRequest);
+        assert_eq!(put_chunk(&client, &auth, &id, 3, b"x").await, Status::BadRequest);
+        //断线重新open拿到的是已经收到的分片
+        let reopened: SessionStatusResponse = open().dispatch().await.into_json().await.unwrap();
+        assert_eq!(reopened.received, vec![1]);
+        let finalize = || client.post(format!("/file/session/{}/finalize", id)).header(auth_header());
+        assert_eq!(finalize().dispatch().await.status(), Status::BadRequest);
+
+        assert_eq!(put_chunk(&client, &auth, &id, 2, &CONTENT[16..]).await, Status::NoContent);
+        assert_eq!(put_chunk(&client, &auth, &id, 0, &CONTENT[..8]).await, Status::NoContent);
+        assert_eq!(status(&client, &auth, &id).await.received, vec![0, 1, 2]);
+        assert_eq!(finalize().dispatch().await.status(), Status::NoContent);
+
+        let file = mongo
+            .database
+            .collection::<File>("files")
+            .find_one(doc! { "_id": ObjectId::parse_str(&id).unwrap() })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.size, CONTENT.len() as u64);
+        assert_eq!(file.sha256, format!("{:x}", Sha256::digest(CONTENT)));
+        assert!(!redis.exists(session_key(&id)).await);
+        assert!(!session_dir(&id, &config).exists());
+        mongo.database.drop().await.unwrap();
+    }
+}
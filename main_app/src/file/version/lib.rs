@@ -0,0 +1,218 @@
+//文件历史版本，更新的时候旧内容不release，blob的引用转给版本记录
+//相同内容的版本自然就共用一个blob
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileVersion, User, VersionPolicy};
+use crate::file::storage_backend::blob_storage::{self, BLOB_STORAGE_TYPE};
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::libs::ApiError;
+use crate::MyConfig;
+use chrono::Utc;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::Collection;
+use rocket::futures::TryStreamExt;
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn db_error<E>(_: E) -> ApiError {
+    ApiError::InternalServerError("Database error".to_string().into())
+}
+
+pub fn version_collection(mongo: &MongoDb) -> Collection<FileVersion> {
+    mongo.database.collection::<FileVersion>("file_versions")
+}
+
+pub fn default_policy(config: &MyConfig) -> VersionPolicy {
+    VersionPolicy {
+        max_count: config.version_max_count,
+        max_age_days: config.version_max_age_days,
+    }
+}
+
+pub async fn user_policy(user_id: &ObjectId, mongo: &MongoDb, default: &VersionPolicy) -> VersionPolicy {
+    match mongo.database.collection::<User>("users").find_one(doc! { "_id": user_id }).await {
+        Ok(Some(User { version_policy: Some(policy), .. })) => policy,
+        _ => default.clone(),
+    }
+}
+
+//old是被替换掉的那一版，它持有的blob引用直接交给版本记录
+pub async fn keep_version(mongo: &MongoDb, old: &File) -> Result<(), ApiError> {
+    let version = FileVersion {
+        _id: ObjectId::new(),
+        file: old._id,
+        owner: old.owner,
+        size: old.size,
+        sha256: old.sha256.clone(),
+        storage_type: old.storage_type.clone(),
+        path: old.path.clone(),
+        device: old.extra_metadata.as_ref().and_then(|m| m.device),
+        extra_metadata: old.extra_metadata.clone(),
+        created_at: old.updated_at,
+        replaced_at: Utc::now().timestamp(),
+    };
+    version_collection(mongo).insert_one(&version).await.map_err(db_error)?;
+    Ok(())
+}
+
+//版本记录当成File用，下载、恢复都走这里
+pub fn version_as_file(version: &FileVersion, current: &File) -> File {
+    File {
+        size: version.size,
+        sha256: version.sha256.clone(),
+        storage_type: version.storage_type.clone(),
+        path: version.path.clone(),
+        extra_metadata: version.extra_metadata.clone(),
+        updated_at: version.created_at,
+        ..current.clone()
+    }
+}
+
+pub async fn list_versions(file: &ObjectId, mongo: &MongoDb) -> Result<Vec<FileVersion>, ApiError> {
+    version_collection(mongo)
+        .find(doc! { "file": file })
+        .sort(doc! { "replaced_at": -1 })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)
+}
+
+async fn release_version(mongo: &MongoDb, factory: &StorageFactory, version: &FileVersion) -> Result<(), ApiError> {
+    version_collection(mongo)
+        .delete_one(doc! { "_id": version._id })
+        .await
+        .map_err(db_error)?;
+    crate::quota::lib::charge(mongo, &version.owner, -(version.size as i64)).await;
+    if version.storage_type == BLOB_STORAGE_TYPE {
+        blob_storage::release(mongo, factory, &version.sha256).await?;
+    }
+    Ok(())
+}
+
+//超过个数的从最旧的开始删，超过时间的全删
+pub async fn prune_versions(
+    file: &ObjectId,
+    policy: &VersionPolicy,
+    mongo: &MongoDb,
+    factory: &StorageFactory,
+) -> Result<(), ApiError> {
+    let deadline = Utc::now().timestamp() - (policy.max_age_days * 24 * 60 * 60) as i64;
+    let versions = list_versions(file, mongo).await?;
+    for (index, version) in versions.iter().enumerate() {
+        let too_many = policy.max_count > 0 && index as u64 >= policy.max_count;
+        let too_old = policy.max_age_days > 0 && version.replaced_at < deadline;
+        if too_many || too_old {
+            release_version(mongo, factory, version).await?;
+        }
+    }
+    Ok(())
+}
+
+//文件彻底删掉的时候版本也一起删
+pub async fn delete_versions(file: &ObjectId, mongo: &MongoDb, factory: &StorageFactory) -> Result<(), ApiError> {
+    for version in list_versions(file, mongo).await? {
+        release_version(mongo, factory, &version).await?;
+    }
+    Ok(())
+}
+
+//按时间过期的版本没人更新也要清，后台每小时跑一次
+pub async fn prune_loop(mongo: MongoDb, storage_factory: Arc<Mutex<StorageFactory>>, default: VersionPolicy) {
+    loop {
+        let files: Result<Vec<ObjectId>, ApiError> = async {
+            let files = version_collection(&mongo)
+                .distinct("file", doc! {})
+                .await
+                .map_err(db_error)?;
+            Ok(files.into_iter().filter_map(|f| f.as_object_id()).collect())
+        }
+        .await;
+        match files {
+            Ok(files) => {
+                for file in files {
+                    let owner = match version_collection(&mongo).find_one(doc! { "file": file }).await {
+                        Ok(Some(version)) => version.owner,
+                        _ => continue,
+                    };
+                    let policy = user_policy(&owner, &mongo, &default).await;
+                    let factory = storage_factory.lock().await.clone();
+                    if let Err(e) = prune_versions(&file, &policy, &mongo, &factory).await {
+                        warn!("清理文件{}的历史版本失败: {:?}", file, e);
+                    }
+                }
+            }
+            Err(e) => error!("清理历史版本失败: {:?}", e),
+        }
+        rocket::tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    //非blob的版本，删的时候不用碰存储
+    async fn version(mongo: &MongoDb, file: &File, days_ago: i64) -> FileVersion {
+        let replaced_at = Utc::now().timestamp() - days_ago * DAY;
+        let version = FileVersion {
+            _id: ObjectId::new(),
+            file: file._id,
+            owner: file.owner,
+            size: 1,
+            sha256: String::new(),
+            storage_type: "FLAT".to_string(),
+            path: String::new(),
+            device: None,
+            extra_metadata: None,
+            created_at: replaced_at - DAY,
+            replaced_at,
+        };
+        version_collection(mongo).insert_one(&version).await.unwrap();
+        version
+    }
+
+    async fn remaining(mongo: &MongoDb, file: &File) -> Vec<ObjectId> {
+        list_versions(&file._id, mongo).await.unwrap().iter().map(|v| v._id).collect()
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev service from compose.yaml"]
+    async fn prunes_by_count_and_age() {
+        let mongo = test_support::mongo().await;
+        let factory = StorageFactory::new(&MyConfig::from_temp(ObjectId::new(), &Default::default()));
+        let user = test_support::user(&mongo).await;
+        let file = test_support::file(&mongo, "a.txt", &user.root_id, &user.uuid, 1).await;
+        let mut versions = vec![];
+        for days_ago in [1, 2, 3, 10, 40] {
+            versions.push(version(&mongo, &file, days_ago).await);
+        }
+
+        //只留最新的4个
+        let policy = VersionPolicy { max_count: 4, max_age_days: 0 };
+        prune_versions(&file._id, &policy, &mongo, &factory).await.unwrap();
+        let ids: Vec<ObjectId> = versions[..4].iter().map(|v| v._id).collect();
+        assert_eq!(remaining(&mongo, &file).await, ids);
+
+        //再把超过7天的删掉
+        let policy = VersionPolicy { max_count: 0, max_age_days: 7 };
+        prune_versions(&file._id, &policy, &mongo, &factory).await.unwrap();
+        let ids: Vec<ObjectId> = versions[..3].iter().map(|v| v._id).collect();
+        assert_eq!(remaining(&mongo, &file).await, ids);
+
+        //都是0就是不限
+        let policy = VersionPolicy { max_count: 0, max_age_days: 0 };
+        prune_versions(&file._id, &policy, &mongo, &factory).await.unwrap();
+        assert_eq!(remaining(&mongo, &file).await.len(), 3);
+
+        let policy = VersionPolicy { max_count: 1, max_age_days: 2 };
+        prune_versions(&file._id, &policy, &mongo, &factory).await.unwrap();
+        assert_eq!(remaining(&mongo, &file).await, vec![versions[0]._id]);
+        mongo.database.drop().await.unwrap();
+    }
+}
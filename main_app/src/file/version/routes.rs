@@ -0,0 +1,111 @@
+use super::lib::{default_policy, list_versions, prune_versions, user_policy, version_as_file, version_collection};
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileType, FileVersion, User, VersionPolicy};
+use crate::file::lib::{stamp_device, ConditionalHeaders, CustomFileResponse};
+use crate::file::storage_backend::blob_storage::{acquire_existing, replace_content};
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::libs::{check_file_permission, mongo_error_check, parse_object_id, ApiError};
+use crate::MyConfig;
+use crate::quota::lib::check_quota;
+use mongodb::bson::doc;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+
+async fn load_file(uuid: &str, user: &AuthenticatedUser, mongo: &MongoDb) -> Result<File, ApiError> {
+    let file = mongo
+        .database
+        .collection::<File>("files")
+        .find_one(doc! { "_id": parse_object_id(uuid)? })
+        .await;
+    let file = mongo_error_check(file, Some("File"))?;
+    check_file_permission(user, &file, mongo).await?;
+    if file.type_ != FileType::File {
+        return Err(ApiError::BadRequest("Target is not a file".to_string().into()));
+    }
+    Ok(file)
+}
+
+async fn load_version(file: &File, version: &str, mongo: &MongoDb) -> Result<FileVersion, ApiError> {
+    let version = version_collection(mongo)
+        .find_one(doc! { "_id": parse_object_id(version)?, "file": file._id })
+        .await;
+    mongo_error_check(version, Some("Version"))
+}
+
+#[get("/policy")]
+pub async fn get_policy(
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Json<VersionPolicy> {
+    Json(user_policy(&user.uuid, mongo, &default_policy(config)).await)
+}
+
+#[put("/policy", data = "<policy>")]
+pub async fn set_policy(
+    policy: Json<VersionPolicy>,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<status::NoContent, ApiError> {
+    let policy = mongodb::bson::to_bson(&policy.into_inner()).unwrap();
+    match mongo
+        .database
+        .collection::<User>("users")
+        .update_one(doc! { "_id": user.uuid }, doc! { "$set": { "version_policy": policy } })
+        .await
+    {
+        Ok(_) => Ok(status::NoContent),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+#[get("/<uuid>")]
+pub async fn get_versions(
+    uuid: &str,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<Vec<FileVersion>>, ApiError> {
+    let file = load_file(uuid, &user, mongo).await?;
+    Ok(Json(list_versions(&file._id, mongo).await?))
+}
+
+#[get("/<uuid>/<version>")]
+pub async fn get_version_file(
+    uuid: &str,
+    version: &str,
+    user: AuthenticatedUser,
+    conditional: ConditionalHeaders,
+    mongo: &rocket::State<MongoDb>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<CustomFileResponse, ApiError> {
+    let file = load_file(uuid, &user, mongo).await?;
+    let version = load_version(&file, version, mongo).await?;
+    CustomFileResponse::new(version_as_file(&version, &file), &conditional, storage_factory, mongo).await
+}
+
+//当前内容变成新的历史版本，选中的那一版本身也留着
+#[post("/<uuid>/<version>/restore")]
+pub async fn restore_version(
+    uuid: &str,
+    version: &str,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<status::NoContent, ApiError> {
+    let file = load_file(uuid, &user, mongo).await?;
+    let version = load_version(&file, version, mongo).await?;
+    check_quota(mongo, &user.uuid, version.size, config.default_quota).await?;
+    if acquire_existing(mongo, &version.sha256).await?.is_none() {
+        return Err(ApiError::NotFound("Blob not found".to_string().into()));
+    }
+    let restored = stamp_device(version_as_file(&version, &file), &user, mongo).await;
+    let factory = storage_factory.lock().await.clone();
+    replace_content(mongo, &factory, &file, &restored, config.default_quota).await?;
+    let policy = user_policy(&user.uuid, mongo, &default_policy(config)).await;
+    prune_versions(&file._id, &policy, mongo, &factory).await?;
+    Ok(status::NoContent)
+}
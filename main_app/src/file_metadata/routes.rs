@@ -3,8 +3,9 @@ use std::str::FromStr;
 use crate::auth::guard::AuthenticatedUser;
 use crate::db::connect::{MongoDb, Redis};
 use crate::db::models::{File, FileType};
-use crate::file::storage_backend::lib::StorageFactory;
-use crate::libs::{mongo_error_check, ApiError};
+use crate::file::storage_backend::lib::{pick_storage_type, StorageFactory};
+use crate::libs::{check_file_permission, mongo_error_check, ApiError};
+use crate::MyConfig;
 use chrono::Utc;
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
@@ -13,7 +14,8 @@ use rocket::serde::json::Json;
 use rocket::tokio::sync::Mutex;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use crate::file::storage_backend::ref_storage;
+use crate::file::storage_backend::blob_storage;
+use crate::quota::lib::check_quota;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MetaDataCreateRequest {
     pub name: String,
@@ -21,6 +23,7 @@ pub struct MetaDataCreateRequest {
     pub father: String,
     pub size: u64,
     pub sha256: String,
+    #[serde(default)]
     pub storage_type: String,
 }
 
@@ -31,13 +34,13 @@ pub struct MetaDataCreateResponse {
 }
 
 impl MetaDataCreateResponse {
-    fn normal(id: String) -> Self {
+    pub fn normal(id: String) -> Self {
         MetaDataCreateResponse {
             id,
             status: "success".to_string(),
         }
     }
-    fn ref_file(id: String) -> Self {
+    pub fn ref_file(id: String) -> Self {
         MetaDataCreateResponse {
             id,
             status: "ref".to_string(),
@@ -53,12 +56,25 @@ pub async fn add_metadata(
     user: AuthenticatedUser,
     redis: &rocket::State<Redis>,
     mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
     storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
 ) -> Result<Json<MetaDataCreateResponse>, ApiError> {
+    Ok(Json(stage_metadata(metadata.into_inner(), &user, redis, mongo, config, storage_factory).await?))
+}
+
+//tus那边也要走这一套，所以单独拿出来
+pub async fn stage_metadata(
+    metadata: MetaDataCreateRequest,
+    user: &AuthenticatedUser,
+    redis: &Redis,
+    mongo: &MongoDb,
+    config: &MyConfig,
+    storage_factory: &Mutex<StorageFactory>,
+) -> Result<MetaDataCreateResponse, ApiError> {
     let id = ObjectId::new();
-    let metadata = metadata.into_inner();
-    let factory = storage_factory.lock().await;
-    match factory.get_backend(&metadata.storage_type) {
+    let storage_type = pick_storage_type(&metadata.storage_type, config);
+    let factory = storage_factory.lock().await.clone();
+    match factory.get_backend(&storage_type) {
         Some(_) => {}
         None => {
             return Err(ApiError::BadRequest(
@@ -78,7 +94,7 @@ pub async fn add_metadata(
         updated_at: Utc::now().timestamp(),
         children: vec![],
         path: id.to_hex(),
-        storage_type: metadata.storage_type,
+        storage_type,
         extra_metadata: None,
     };
     let father = mongo
@@ -87,7 +103,7 @@ pub async fn add_metadata(
         .find_one(doc! { "_id": metadata.father })
         .await;
     match father {
-        Ok(Some(_)) => {}
+        Ok(Some(father)) => check_file_permission(user, &father, mongo).await?,
         Ok(None) => {
             return Err(ApiError::NotFound(
                 "Father folder not found".to_string().into(),
@@ -116,15 +132,17 @@ pub async fn add_metadata(
                     doc! { "$set": { "children": t } },
                 )
                 .await;
-            Ok(Json(MetaDataCreateResponse::normal(id.to_string())))
+            Ok(MetaDataCreateResponse::normal(id.to_string()))
         }
         FileType::File => {
-            let collection = mongo.database.collection::<File>("files");
-            //这里是在处理ref的情况，理由和file.GET那里一样
-            if let Some(exist) = ref_storage::find_and_add_ref(
-                &collection,
-                &metadata,).await? {
-                return Ok(Json(MetaDataCreateResponse::ref_file(exist._id.to_hex())));
+            //超了配额的话在传之前就拒掉
+            check_quota(mongo, &user.uuid, metadata.size, config.default_quota).await?;
+            //已经有一样内容的blob就直接建文件，不用再传
+            //sha256为空的是上传完才知道hash的（比如tus），没法秒传
+            if let Some(deduped) = blob_storage::try_dedup(mongo, &metadata).await? {
+                let deduped = crate::file::lib::stamp_device(deduped, user, mongo).await;
+                crate::file::lib::commit_uploaded_file(mongo, storage_factory, &deduped, config.default_quota).await?;
+                return Ok(MetaDataCreateResponse::ref_file(id.to_string()));
             }
             let _: () = redis
                 .set(
@@ -133,7 +151,7 @@ pub async fn add_metadata(
                 )
                 .await;
             let _: () = redis.expire(id.to_string().as_str(), 24 * 60 * 60).await;
-            Ok(Json(MetaDataCreateResponse::normal(id.to_string())))
+            Ok(MetaDataCreateResponse::normal(id.to_string()))
             // TODO delete this
             /*             match db
                 .find_one(doc! { "sha256": &metadata.sha256 , "storage_type": doc! {"$ne": "ref"}})
@@ -226,12 +244,7 @@ pub enum Response {
     FileTree(FileTree),
 }
 
-fn check_permission(user: &AuthenticatedUser, file: &File) -> Result<(), ApiError> {
-    if file.owner != user.uuid {
-        return Err(ApiError::Forbidden("Permission denied".to_string().into()));
-    };
-    Ok(())
-}
+
 
 #[get("/<uuid>?<tree>")]
 pub async fn get_metadata(
@@ -246,7 +259,7 @@ pub async fn get_metadata(
         .find_one(doc! {"_id": ObjectId::from_str(uuid).unwrap()})
         .await;
     let file = mongo_error_check(file, Some("File"))?;
-    check_permission(&user, &file)?;
+    check_file_permission(&user, &file, mongo).await?;
     if tree {
         let tree = get_tree(&ObjectId::from_str(uuid).unwrap(), mongo).await;
         match tree {
@@ -280,14 +293,17 @@ pub async fn update_metadata(
     let file = mongo_error_check(file, Some("File"))?;
     //其实只有father,name可以更新
     //sha256和size是在文件更新的时候改的
-    //path,type不能更新
+    //path,type,storage_type不能更新，不然blob的引用计数就乱了
     //created_at和updated_at是由系统指定的
     //owner肯定不能动
+    //children只能由系统维护，不然可以把别人的文件挂到自己目录下
     //查一下
     if new_metadata.sha256 != file.sha256
+        || new_metadata.children != file.children
         || new_metadata.size != file.size
         || new_metadata.type_ != file.type_
         || new_metadata.path != file.path
+        || new_metadata.storage_type != file.storage_type
         || new_metadata.owner != file.owner
         || new_metadata.created_at != file.created_at
     {
@@ -300,7 +316,7 @@ pub async fn update_metadata(
         updated_at: Utc::now().timestamp(),
         ..new_metadata
     };
-    check_permission(&user, &file)?;
+    check_file_permission(&user, &file, mongo).await?;
     let old_father = match db.find_one(doc! {"_id": file.father}).await {
         Ok(Some(file)) => file,
         Ok(None) => {
@@ -327,6 +343,28 @@ pub async fn update_metadata(
             ))
         }
     };
+    check_file_permission(&user, &new_father, mongo).await?;
+    //不能挪到自己或者自己的子文件夹下面，不然树里就有环了
+    let mut current = new_father.clone();
+    loop {
+        if current._id == file._id {
+            return Err(ApiError::BadRequest(
+                "Cannot move a folder into itself".to_string().into(),
+            ));
+        }
+        if current.father == current._id || current.type_ == FileType::Root {
+            break;
+        }
+        current = match db.find_one(doc! {"_id": current.father}).await {
+            Ok(Some(father)) => father,
+            Ok(None) => break,
+            Err(_) => {
+                return Err(ApiError::InternalServerError(
+                    "Database error".to_string().into(),
+                ))
+            }
+        };
+    }
     let mut old_father_children = old_father.children;
     let mut new_father_children = new_father.children;
     old_father_children.retain(|x| *x != file._id);
@@ -334,15 +372,13 @@ pub async fn update_metadata(
     let _ = db
         .update_one(
             doc! {"_id": old_father._id},
-            doc! { "$set": doc! { "children": old_father_children },
-            "$set": doc! { "updated_at": Utc::now().timestamp() } },
+            doc! { "$set": doc! { "children": old_father_children, "updated_at": Utc::now().timestamp() } },
         )
         .await;
     let _ = db
         .update_one(
             doc! {"_id": new_father._id},
-            doc! { "$set": doc! { "children": new_father_children },
-            "$set": doc! { "updated_at": Utc::now().timestamp() } },
+            doc! { "$set": doc! { "children": new_father_children, "updated_at": Utc::now().timestamp() } },
         )
         .await;
     let _ = db
@@ -360,14 +396,15 @@ pub async fn delete_metadata(
     user: AuthenticatedUser,
     mongo: &rocket::State<MongoDb>,
     redis: &rocket::State<Redis>,
-    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+    config: &rocket::State<MyConfig>,
 ) -> Result<status::NoContent, ApiError> {
     if redis.exists(uuid).await {
-        let _: () = redis.delete(uuid).await;
+        crate::file::lib::load_staged_metadata(uuid, &user, redis, mongo).await?;
+        //顺便清掉没传完的分片上传
+        crate::file::lib::discard_staged_upload(uuid, redis, config).await;
         return Ok(status::NoContent);
     }
-    let db = mongo.database.collection::<File>("files");
-    match delete_file_l(uuid, &user, &db, storage_factory).await {
+    match delete_file_l(uuid, &user, mongo).await {
         Ok(_) => {}
         Err(e) => {
             return Err(e);
@@ -376,73 +413,21 @@ pub async fn delete_metadata(
     Ok(status::NoContent)
 }
 
-async fn delete_file_l(
+//不是真删，整个挪进回收站，blob要等回收站清掉才release
+pub async fn delete_file_l(
     uuid: &str,
     user: &AuthenticatedUser,
-    db: &mongodb::Collection<File>,
-    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+    mongo: &MongoDb,
 ) -> Result<(), ApiError> {
+    let db = &mongo.database.collection::<File>("files");
     let file = db
         .find_one(doc! {"_id": ObjectId::from_str(uuid).unwrap()})
         .await;
     let file = mongo_error_check(file, Some("File"))?;
-    check_permission(user, &file)?;
-
-    //删除父文件夹里的children里的这个文件
-    let father = db.find_one(doc! {"_id": file.father}).await;
-    let father = mongo_error_check(father, Some("File"))?;
-    let mut father_children = father.children;
-    father_children.retain(|x| x != &file._id);
-    let _ = db
-        .update_one(
-            doc! { "_id": father._id },
-            doc! { "$set": { "children": father_children } },
-        )
-        .await
-        .unwrap();
-
-    //这里是前处理
-    //如果是文件夹，还要把children都删了
-    //如果是文件，检查是不是ref,如果是ref子要删除母的file_references，如果是母要重新选一个母
-    match file.type_ {
-        FileType::Folder => {
-            for child in &file.children {
-                Box::pin(delete_file_l(&child.to_hex(), user, db, storage_factory)).await?;
-            }
-        }
-        FileType::File => {
-            if file.storage_type == "ref" {
-                ref_storage::remove_ref(db, &file).await?;
-                let _ = db
-                    .delete_one(doc! {"_id": ObjectId::from_str(uuid).unwrap()})
-                    .await
-                    .unwrap();
-                return Ok(());
-            }
-            if let Some(ext) = &file.extra_metadata {
-                if ext.file_references.len() != 0 {
-                    ref_storage::change_mother(db, &file).await?;
-                }
-                let _ = db
-                    .delete_one(doc! {"_id": ObjectId::from_str(uuid).unwrap()})
-                    .await
-                    .unwrap();
-                return Ok(());
-            }
-        }
-        FileType::Root => {
-            return Err(ApiError::Forbidden("Permission denied".to_string().into()));
-        }
+    check_file_permission(user, &file, mongo).await?;
+    //home文件夹也不能删
+    if file.type_ == FileType::Root || file._id == user.root_id {
+        return Err(ApiError::Forbidden("Permission denied".to_string().into()));
     }
-
-    //删除文件metadata
-    let _ = db
-        .delete_one(doc! {"_id": ObjectId::from_str(uuid).unwrap()})
-        .await
-        .unwrap();
-
-    //删除文件
-    let factory = storage_factory.lock().await;
-    let _ = factory.delete_file(&file).await;
-    Ok(())
+    crate::trash::lib::move_to_trash(file, mongo).await
 }
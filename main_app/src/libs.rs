@@ -14,6 +14,7 @@ pub enum ApiError {
     Unauthorized(Option<String>),
     Forbidden(Option<String>),
     BadRequest(Option<String>),
+    TooManyRequests(u64), //多少秒之后再试，放进Retry-After
 }
 
 impl ApiError {
@@ -49,6 +50,12 @@ impl ApiError {
                     error: message.clone().unwrap_or_else(|| "Bad Request".to_string()),
                 }),
             ),
+            ApiError::TooManyRequests(retry_after) => status::Custom(
+                Status::TooManyRequests,
+                Json(ErrorResponse {
+                    error: format!("Too many requests, retry after {} seconds", retry_after),
+                }),
+            ),
         }
     }
     pub fn _change_message(&self, message: String) -> Self {
@@ -58,6 +65,7 @@ impl ApiError {
             ApiError::Unauthorized(_) => ApiError::Unauthorized(Some(message)),
             ApiError::Forbidden(_) => ApiError::Forbidden(Some(message)),
             ApiError::BadRequest(_) => ApiError::BadRequest(Some(message)),
+            ApiError::TooManyRequests(retry_after) => ApiError::TooManyRequests(*retry_after),
         }
     }
     pub fn _to_string(&self) -> String {
@@ -67,6 +75,7 @@ impl ApiError {
             ApiError::Unauthorized(message) => message.clone().unwrap_or_default(),
             ApiError::Forbidden(message) => message.clone().unwrap_or_default(),
             ApiError::BadRequest(message) => message.clone().unwrap_or_default(),
+            ApiError::TooManyRequests(retry_after) => format!("Too many requests, retry after {} seconds", retry_after),
         }
     }
 }
@@ -76,7 +85,11 @@ use rocket::response::{self, Responder};
 
 impl <'r> Responder<'r, 'static> for ApiError {
     fn respond_to(self, req: &Request<'_>) -> response::Result<'static> {
-        self.to_response().respond_to(req)
+        let mut response = self.to_response().respond_to(req)?;
+        if let ApiError::TooManyRequests(retry_after) = self {
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+        Ok(response)
     }
 }
 
@@ -93,13 +106,39 @@ pub fn mongo_error_check<T>(result: Result<Option<T>,mongodb::error::Error>,docu
 }
 
 use crate::auth::guard::AuthenticatedUser;
-use crate::db::models::File;
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileType};
+use mongodb::bson::doc;
 
-pub fn check_file_permission(user: &AuthenticatedUser, file: &File) -> Result<(), ApiError> {
+//限定了文件夹的access key还要往上找，得在那个文件夹下面才行
+pub async fn check_file_permission(user: &AuthenticatedUser, file: &File, mongo: &MongoDb) -> Result<(), ApiError> {
     if file.owner != user.uuid {
         return Err(
             ApiError::Forbidden("Permission denied".to_string().into()),
         )
     };
-    Ok(())
+    let folder = match user.folder {
+        Some(folder) => folder,
+        None => return Ok(()),
+    };
+    let collection = mongo.database.collection::<File>("files");
+    let mut current = file.clone();
+    loop {
+        if current._id == folder {
+            return Ok(());
+        }
+        if current.father == current._id || current.type_ == FileType::Root {
+            return Err(ApiError::Forbidden("Permission denied".to_string().into()));
+        }
+        current = match collection.find_one(doc! { "_id": current.father }).await {
+            Ok(Some(father)) => father,
+            Ok(None) => return Err(ApiError::Forbidden("Permission denied".to_string().into())),
+            Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+        };
+    }
+}
+
+pub fn parse_object_id(id: &str) -> Result<mongodb::bson::oid::ObjectId, ApiError> {
+    use std::str::FromStr;
+    mongodb::bson::oid::ObjectId::from_str(id).map_err(|_| ApiError::BadRequest("Invalid id".to_string().into()))
 }
@@ -11,6 +11,13 @@ mod auth;
 mod db;
 mod file;
 mod file_metadata;
+mod webdav;
+mod trash;
+mod quota;
+mod admin;
+mod rate_limit;
+#[cfg(test)]
+mod test_support;
 
 use rocket::data::{Limits, ToByteUnit};
 
@@ -22,7 +29,45 @@ struct TempConfig {
     redis_uri: String,
     flat_storage_path: String,
     cache_storage_path: String,
+    s3_endpoint: String,
+    s3_region: String,
+    s3_bucket: String,
+    s3_access_key: String,
+    s3_secret_key: String,
+    s3_part_size: u64,
+    encryption_keys: String,
+    encryption_rotate: bool,
+    compression_level: i32,
+    default_storage_type: String,
+    trash_retention_days: u64,
+    version_max_count: u64,
+    version_max_age_days: u64,
+    default_quota: u64,
+    login_max_failures: u64,
+    login_ip_max_failures: u64,
+    lockout_base_secs: u64,
+    lockout_max_secs: u64,
+    api_rate_limit: u64,
+    api_rate_window_secs: u64,
+    trusted_ip_header: String,
+    auth_providers: String,
+    ldap_url: String,
+    ldap_bind_dn: String,
+    ldap_bind_password: String,
+    ldap_base_dn: String,
+    ldap_user_attr: String,
+    ldap_admin_group: String,
+    ldap_group_member_attr: String,
+    oidc_issuer: String,
+    oidc_client_id: String,
+    oidc_client_secret: String,
+    oidc_redirect_uri: String,
+    oidc_scopes: String,
+    oidc_claim: String,
+    oidc_algorithms: String,
+    oidc_auto_provision: bool,
     port: u16,
+    webdav_port: u16,
     address: IpAddr,
     limits: Limits
 }
@@ -38,7 +83,63 @@ impl Default for TempConfig {
             flat_storage_path: "./main_app/storage/flat".to_string(),
             //cache_storage_path: "./storage/cache".to_string(),
             cache_storage_path: "./main_app/storage/cache".to_string(),
+            //bucket留空就不启用S3
+            s3_endpoint: "".to_string(),
+            s3_region: "us-east-1".to_string(),
+            s3_bucket: "".to_string(),
+            s3_access_key: "".to_string(),
+            s3_secret_key: "".to_string(),
+            s3_part_size: 16 * 1024 * 1024,
+            //"id:base64密钥,id:base64密钥"，第一个用来加密，留空就不启用加密存储
+            encryption_keys: "".to_string(),
+            encryption_rotate: false,
+            compression_level: 3,
+            //客户端没指定或者传FLAT时用的存储，开了加密想全部加密就设成FLAT_ENC
+            default_storage_type: "FLAT".to_string(),
+            //0表示回收站不自动清
+            trash_retention_days: 30,
+            //用户没单独设置的时候用这个，0表示不限
+            version_max_count: 20,
+            version_max_age_days: 90,
+            //字节，0表示不限
+            default_quota: 0,
+            //同一个用户名（分享链接）连续输错这么多次开始锁，同一个IP的门槛高一点，免得NAT后面的人一起被锁
+            login_max_failures: 5,
+            login_ip_max_failures: 20,
+            //第一次锁这么久，之后每多错一次翻倍，最多锁到max
+            lockout_base_secs: 30,
+            lockout_max_secs: 60 * 60,
+            //每个token每个窗口最多多少个请求，0表示不限
+            api_rate_limit: 0,
+            api_rate_window_secs: 60,
+            //反代传客户端IP用的header，比如"X-Real-IP"，留空就只认连接的对端地址
+            trusted_ip_header: "".to_string(),
+            //按顺序试，"local,ldap"就是本地账号优先
+            auth_providers: "local".to_string(),
+            //url留空就不启用LDAP，ldap://或者ldaps://
+            ldap_url: "".to_string(),
+            //搜用户用的服务账号，都留空就是匿名
+            ldap_bind_dn: "".to_string(),
+            ldap_bind_password: "".to_string(),
+            ldap_base_dn: "".to_string(),
+            ldap_user_attr: "uid".to_string(),
+            //这个组里的人是管理员，留空就不按组同步角色
+            ldap_admin_group: "".to_string(),
+            ldap_group_member_attr: "member".to_string(),
+            //issuer留空就不启用OIDC登录
+            oidc_issuer: "".to_string(),
+            oidc_client_id: "".to_string(),
+            oidc_client_secret: "".to_string(),
+            oidc_redirect_uri: "".to_string(),
+            oidc_scopes: "openid email profile".to_string(),
+            //用哪个claim对应到用户，"sub"或者"email"
+            oidc_claim: "sub".to_string(),
+            //id_token允许的签名算法，逗号分隔，不能信token头里自己写的
+            oidc_algorithms: "RS256".to_string(),
+            //第一次登录的时候自动建用户
+            oidc_auto_provision: true,
             port: 8000,
+            webdav_port: 8001,
             address: "0.0.0.0".parse().unwrap(),
             limits: Limits::default().limit("file", 4.gibibytes())
         }
@@ -56,6 +157,7 @@ impl TempConfig {
     }
 }
 
+#[derive(Clone)]
 pub struct MyConfig {
     pub jwt_secret: String,
     pub mongodb_uri: String,
@@ -63,7 +165,45 @@ pub struct MyConfig {
     pub redis_uri: String,
     pub flat_storage_path: String,
     pub cache_storage_path: String,
+    pub s3_endpoint: String,
+    pub s3_region: String,
+    pub s3_bucket: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    pub s3_part_size: u64,
+    pub encryption_keys: String,
+    pub encryption_rotate: bool,
+    pub compression_level: i32,
+    pub default_storage_type: String,
+    pub trash_retention_days: u64,
+    pub version_max_count: u64,
+    pub version_max_age_days: u64,
+    pub default_quota: u64,
+    pub login_max_failures: u64,
+    pub login_ip_max_failures: u64,
+    pub lockout_base_secs: u64,
+    pub lockout_max_secs: u64,
+    pub api_rate_limit: u64,
+    pub api_rate_window_secs: u64,
+    pub trusted_ip_header: String,
+    pub auth_providers: String,
+    pub ldap_url: String,
+    pub ldap_bind_dn: String,
+    pub ldap_bind_password: String,
+    pub ldap_base_dn: String,
+    pub ldap_user_attr: String,
+    pub ldap_admin_group: String,
+    pub ldap_group_member_attr: String,
+    pub oidc_issuer: String,
+    pub oidc_client_id: String,
+    pub oidc_client_secret: String,
+    pub oidc_redirect_uri: String,
+    pub oidc_scopes: String,
+    pub oidc_claim: String,
+    pub oidc_algorithms: String,
+    pub oidc_auto_provision: bool,
     pub port: u16,
+    pub webdav_port: u16,
     pub system_root_id: ObjectId,
     pub address: IpAddr,
     pub limits: Limits
@@ -78,7 +218,45 @@ impl MyConfig {
             redis_uri: old.redis_uri.clone(),
             flat_storage_path: old.flat_storage_path.clone(),
             cache_storage_path: old.cache_storage_path.clone(),
+            s3_endpoint: old.s3_endpoint.clone(),
+            s3_region: old.s3_region.clone(),
+            s3_bucket: old.s3_bucket.clone(),
+            s3_access_key: old.s3_access_key.clone(),
+            s3_secret_key: old.s3_secret_key.clone(),
+            s3_part_size: old.s3_part_size,
+            encryption_keys: old.encryption_keys.clone(),
+            encryption_rotate: old.encryption_rotate,
+            compression_level: old.compression_level,
+            default_storage_type: old.default_storage_type.clone(),
+            trash_retention_days: old.trash_retention_days,
+            version_max_count: old.version_max_count,
+            version_max_age_days: old.version_max_age_days,
+            default_quota: old.default_quota,
+            login_max_failures: old.login_max_failures,
+            login_ip_max_failures: old.login_ip_max_failures,
+            lockout_base_secs: old.lockout_base_secs,
+            lockout_max_secs: old.lockout_max_secs,
+            api_rate_limit: old.api_rate_limit,
+            api_rate_window_secs: old.api_rate_window_secs,
+            trusted_ip_header: old.trusted_ip_header.clone(),
+            auth_providers: old.auth_providers.clone(),
+            ldap_url: old.ldap_url.clone(),
+            ldap_bind_dn: old.ldap_bind_dn.clone(),
+            ldap_bind_password: old.ldap_bind_password.clone(),
+            ldap_base_dn: old.ldap_base_dn.clone(),
+            ldap_user_attr: old.ldap_user_attr.clone(),
+            ldap_admin_group: old.ldap_admin_group.clone(),
+            ldap_group_member_attr: old.ldap_group_member_attr.clone(),
+            oidc_issuer: old.oidc_issuer.clone(),
+            oidc_client_id: old.oidc_client_id.clone(),
+            oidc_client_secret: old.oidc_client_secret.clone(),
+            oidc_redirect_uri: old.oidc_redirect_uri.clone(),
+            oidc_scopes: old.oidc_scopes.clone(),
+            oidc_claim: old.oidc_claim.clone(),
+            oidc_algorithms: old.oidc_algorithms.clone(),
+            oidc_auto_provision: old.oidc_auto_provision,
             port: old.port,
+            webdav_port: old.webdav_port,
             system_root_id: root_id,
             address: old.address,
             limits: old.limits.clone()
@@ -145,21 +323,95 @@ use crate::db::FirstInit;
 async fn rocket() -> _ {
     let config = TempConfig::from_env();
     let mut mongodb = MongoDb::init(&config.mongodb_uri, &config.mongodb_name).await;
-    let _ = mongodb.first_init().await.unwrap();
+    mongodb.first_init().await.unwrap();
     let root_id = mongodb.get_root_id().await.unwrap();
     let redis = Redis::init(&config.redis_uri).await;
 
     let config = MyConfig::from_temp(root_id, &config);
     let app_config = AppConfig::from_config(&config);
+    //先建rocket，日志要在这里初始化，下面启动时的报错才打得出来
+    let rocket = rocket::custom(app_config.to_figment());
 
     let mut storage_factory = file::storage_backend::lib::StorageFactory::new(&config);
     storage_factory.register_backend("FLAT", Box::new(file::storage_backend::flat::LocalFlatStorageBackend::new(storage_factory.get_config())));
+    storage_factory.register_backend("FLAT_ZSTD", Box::new(file::storage_backend::compressed::CompressedStorageBackend::new(storage_factory.get_config())));
+    if !config.s3_bucket.is_empty() {
+        storage_factory.register_backend("S3", Box::new(file::storage_backend::s3::S3StorageBackend::new(storage_factory.get_config())));
+        let storage_config = storage_factory.get_config().clone();
+        storage_factory.register_backend("S3_ZSTD", Box::new(file::storage_backend::compressed::CompressedStorageBackend::wrap(Box::new(file::storage_backend::s3::S3StorageBackend::new(&storage_config)), &storage_config)));
+    }
+    if !config.encryption_keys.is_empty() {
+        use file::storage_backend::compressed::CompressedStorageBackend;
+        use file::storage_backend::encrypted::EncryptedStorageBackend;
+        use file::storage_backend::lib::StorageBackend;
+        let storage_config = storage_factory.get_config().clone();
+        //密钥配错了直接不起，不然加密的文件全都读不出来
+        let encrypted = |inner: Box<dyn StorageBackend>| -> Box<dyn StorageBackend> {
+            match EncryptedStorageBackend::wrap(inner, &storage_config) {
+                Ok(backend) => Box::new(backend),
+                Err(e) => {
+                    error!("加密存储配置有误: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        };
+        storage_factory.register_backend("FLAT_ENC", encrypted(Box::new(file::storage_backend::flat::LocalFlatStorageBackend::new(&storage_config))));
+        //先压缩再加密，密文压不动
+        storage_factory.register_backend("FLAT_ZSTD_ENC", Box::new(CompressedStorageBackend::wrap(encrypted(Box::new(file::storage_backend::flat::LocalFlatStorageBackend::new(&storage_config))), &storage_config)));
+        if !config.s3_bucket.is_empty() {
+            storage_factory.register_backend("S3_ENC", encrypted(Box::new(file::storage_backend::s3::S3StorageBackend::new(&storage_config))));
+            storage_factory.register_backend("S3_ZSTD_ENC", Box::new(CompressedStorageBackend::wrap(encrypted(Box::new(file::storage_backend::s3::S3StorageBackend::new(&storage_config))), &storage_config)));
+        }
+    }
+    if storage_factory.get_backend(&config.default_storage_type).is_none() {
+        error!("默认存储{}没有启用，检查default_storage_type和对应的S3、加密配置", config.default_storage_type);
+        std::process::exit(1);
+    }
+    //迁移每一步都能重跑，失败了下次启动接着迁，不要因为这个起不来
+    if let Err(e) = file::storage_backend::blob_storage::migrate_legacy_files(&mongodb, &storage_factory).await {
+        error!("迁移旧文件到blob存储失败: {:?}", e);
+    }
+    quota::lib::init_usage(&mongodb).await.unwrap();
+    auth::lib::ensure_admin(&mongodb).await.unwrap();
+    //登录和access key在redis里的key加了前缀，按库里的登录设备重建一遍
+    if let Err(e) = redis.recover_from_db(&mongodb).await {
+        error!("恢复登录状态失败: {}", e);
+    }
+    file::share::lib::init_share_links(&mongodb, &redis).await.unwrap();
+    //换了主密钥之后开一次，旧密钥要等这里跑完才能从配置里删
+    if config.encryption_rotate {
+        file::storage_backend::blob_storage::rotate_keys(&mongodb, &storage_factory).await.unwrap();
+    }
+    let storage_factory = Arc::new(Mutex::new(storage_factory));
+
+    let webdav_state = Arc::new(webdav::server::WebDavState::new(
+        mongodb.clone(),
+        redis.clone(),
+        storage_factory.clone(),
+        config.clone(),
+    ));
 
-    rocket::custom(app_config.to_figment())
+    let purge_state = (mongodb.clone(), storage_factory.clone(), config.trash_retention_days);
+    let prune_state = (mongodb.clone(), storage_factory.clone(), file::version::lib::default_policy(&config));
+
+    rocket
         .manage(config)
         .manage(mongodb)
         .manage(redis)
-        .manage(Arc::new(Mutex::new(storage_factory)))
+        .manage(storage_factory)
+        .manage(auth::oidc::OidcCache::default())
+        .attach(rocket::fairing::AdHoc::on_liftoff("WebDAV", |_| Box::pin(async move {
+            rocket::tokio::spawn(webdav::server::serve(webdav_state));
+        })))
+        .attach(rocket::fairing::AdHoc::on_liftoff("Trash purge", move |_| Box::pin(async move {
+            let (mongodb, storage_factory, retention_days) = purge_state;
+            rocket::tokio::spawn(trash::lib::purge_loop(mongodb, storage_factory, retention_days));
+        })))
+        .attach(rocket::fairing::AdHoc::on_liftoff("Version prune", move |_| Box::pin(async move {
+            let (mongodb, storage_factory, policy) = prune_state;
+            rocket::tokio::spawn(file::version::lib::prune_loop(mongodb, storage_factory, policy));
+        })))
+        .register("/", catchers![rate_limit::guard::too_many_requests])
         .mount("/", routes![index])
         .mount("/auth", routes![
             auth::routes::login,
@@ -167,6 +419,16 @@ async fn rocket() -> _ {
             auth::routes::create_access_key,
             auth::routes::delete_access_key,
             auth::routes::list_devices,
+            auth::routes::change_password,
+            auth::routes::update_settings,
+            auth::routes::login_totp,
+            auth::routes::refresh,
+            auth::routes::oidc_login,
+            auth::routes::oidc_callback,
+            auth::routes::totp_enroll,
+            auth::routes::totp_confirm,
+            auth::routes::totp_recovery_codes,
+            auth::routes::totp_disable,
         ])
         .mount("/metadata", routes![
             file_metadata::routes::add_metadata,
@@ -179,8 +441,57 @@ async fn rocket() -> _ {
             file::routes::update_file,
             file::routes::upload_file,
         ])
+        .mount("/file/session", routes![
+            file::upload_session::routes::open_session,
+            file::upload_session::routes::get_session,
+            file::upload_session::routes::upload_chunk,
+            file::upload_session::routes::finalize_session,
+            file::upload_session::routes::abort_session,
+        ])
+        .mount("/tus", routes![
+            file::tus::routes::tus_options,
+            file::tus::routes::create_upload,
+            file::tus::routes::upload_offset,
+            file::tus::routes::append_upload,
+            file::tus::routes::terminate_upload,
+        ])
+        .mount("/file/version", routes![
+            file::version::routes::get_policy,
+            file::version::routes::set_policy,
+            file::version::routes::get_versions,
+            file::version::routes::get_version_file,
+            file::version::routes::restore_version,
+        ])
+        .mount("/quota", routes![
+            quota::routes::get_usage,
+        ])
+        .mount("/trash", routes![
+            trash::routes::list_trash,
+            trash::routes::restore_item,
+            trash::routes::delete_item,
+            trash::routes::empty_trash,
+        ])
+        .mount("/admin", routes![
+            admin::routes::list_users,
+            admin::routes::add_user,
+            admin::routes::set_disabled,
+            admin::routes::reset_password,
+            admin::routes::force_logout,
+            admin::routes::reset_totp,
+            admin::routes::set_quota,
+            admin::routes::remove_user,
+        ])
         .mount("/file/share", routes![
             file::share::routes::crate_share_link,
             file::share::routes::get_share_file,
+            file::share::routes::list_share_folder,
+            file::share::routes::open_share_session,
+            file::share::routes::crate_file_request,
+            file::share::routes::request_add_metadata,
+            file::share::routes::request_upload_file,
+            file::share::routes::list_share_links,
+            file::share::routes::get_share_link,
+            file::share::routes::update_share_link,
+            file::share::routes::revoke_share_link,
         ])
 }
\ No newline at end of file
@@ -0,0 +1,173 @@
+//每个用户的空间配额，used_bytes随上传、更新、清理增减
+//删到回收站不算释放，回收站清掉或者历史版本过期才减
+use crate::db::connect::MongoDb;
+use crate::db::models::User;
+use crate::libs::ApiError;
+use mongodb::bson::{doc, Document};
+use mongodb::bson::oid::ObjectId;
+use rocket::futures::TryStreamExt;
+
+pub const QUOTA_EXCEEDED: &str = "Quota exceeded";
+
+fn users(mongo: &MongoDb) -> mongodb::Collection<User> {
+    mongo.database.collection::<User>("users")
+}
+
+//0表示不限
+pub fn effective_quota(user: &User, default_quota: u64) -> u64 {
+    user.quota.unwrap_or(default_quota)
+}
+
+pub async fn charge(mongo: &MongoDb, owner: &ObjectId, delta: i64) {
+    if delta == 0 {
+        return;
+    }
+    let _ = users(mongo)
+        .update_one(doc! { "_id": owner }, doc! { "$inc": { "used_bytes": delta } })
+        .await;
+}
+
+//按配额原子地占上bytes，占不上就是超了，几个上传同时提交也不会一起超过配额
+pub async fn reserve(mongo: &MongoDb, owner: &ObjectId, bytes: u64, default_quota: u64) -> Result<(), ApiError> {
+    let user = match users(mongo).find_one(doc! { "_id": owner }).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(ApiError::NotFound("User not found".to_string().into())),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    let quota = effective_quota(&user, default_quota);
+    let filter = if quota == 0 {
+        doc! { "_id": owner }
+    } else if bytes > quota {
+        return Err(ApiError::Forbidden(QUOTA_EXCEEDED.to_string().into()));
+    } else {
+        doc! { "_id": owner, "used_bytes": { "$lte": (quota - bytes) as i64 } }
+    };
+    match users(mongo).update_one(filter, doc! { "$inc": { "used_bytes": bytes as i64 } }).await {
+        Ok(result) if result.matched_count == 1 => Ok(()),
+        Ok(_) => Err(ApiError::Forbidden(QUOTA_EXCEEDED.to_string().into())),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+//再放extra字节进来会不会超，只是传之前先拒掉明显超了的，真正占配额在reserve
+pub async fn check_quota(mongo: &MongoDb, owner: &ObjectId, extra: u64, default_quota: u64) -> Result<(), ApiError> {
+    let user = match users(mongo).find_one(doc! { "_id": owner }).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(ApiError::NotFound("User not found".to_string().into())),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    let quota = effective_quota(&user, default_quota);
+    if quota > 0 && user.used_bytes.max(0) as u64 + extra > quota {
+        return Err(ApiError::Forbidden(QUOTA_EXCEEDED.to_string().into()));
+    }
+    Ok(())
+}
+
+async fn sum_size(collection: &str, filter: Document, mongo: &MongoDb) -> Result<i64, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$group": { "_id": null, "total": { "$sum": "$size" } } },
+    ];
+    let result: Vec<Document> = mongo
+        .database
+        .collection::<Document>(collection)
+        .aggregate(pipeline)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(result
+        .first()
+        .and_then(|d| d.get("total"))
+        .and_then(|t| t.as_i64().or_else(|| t.as_i32().map(i64::from)))
+        .unwrap_or(0))
+}
+
+//按(文件, 回收站, 历史版本)分别算
+pub async fn usage_by_kind(mongo: &MongoDb, owner: &ObjectId) -> Result<(i64, i64, i64), ApiError> {
+    let db_error = |_| ApiError::InternalServerError("Database error".to_string().into());
+    let files = sum_size("files", doc! { "owner": owner, "type": "File" }, mongo).await.map_err(db_error)?;
+    let trash = sum_size("trashed_files", doc! { "owner": owner, "type": "File" }, mongo).await.map_err(db_error)?;
+    let versions = sum_size("file_versions", doc! { "owner": owner }, mongo).await.map_err(db_error)?;
+    Ok((files, trash, versions))
+}
+
+//以前没有配额记账的用户，启动时按现有文件算一遍
+pub async fn init_usage(mongo: &MongoDb) -> Result<(), ApiError> {
+    let pending: Vec<User> = match users(mongo).find(doc! { "used_bytes": { "$exists": false } }).await {
+        Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    for user in pending {
+        let (files, trash, versions) = usage_by_kind(mongo, &user._id).await?;
+        let _ = users(mongo)
+            .update_one(doc! { "_id": user._id }, doc! { "$set": { "used_bytes": files + trash + versions } })
+            .await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::UserRole;
+    use crate::test_support;
+
+    fn account(quota: Option<u64>) -> User {
+        User {
+            _id: ObjectId::new(),
+            username: String::new(),
+            nickname: String::new(),
+            password: String::new(),
+            root_id: ObjectId::new(),
+            role: UserRole::Normal,
+            disabled: false,
+            provider: None,
+            oidc_subject: None,
+            totp: None,
+            version_policy: None,
+            quota,
+            used_bytes: 0,
+        }
+    }
+
+    fn assert_exceeded(result: Result<(), ApiError>) {
+        match result {
+            Err(ApiError::Forbidden(Some(message))) => assert_eq!(message, QUOTA_EXCEEDED),
+            other => panic!("expected quota rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn user_quota_overrides_default() {
+        assert_eq!(effective_quota(&account(None), 100), 100);
+        assert_eq!(effective_quota(&account(Some(0)), 100), 0);
+        assert_eq!(effective_quota(&account(Some(50)), 100), 50);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev service from compose.yaml"]
+    async fn rejects_uploads_past_quota() {
+        let mongo = test_support::mongo().await;
+        let user = account(Some(100));
+        users(&mongo).insert_one(&user).await.unwrap();
+
+        reserve(&mongo, &user._id, 60, 0).await.unwrap();
+        assert_exceeded(reserve(&mongo, &user._id, 41, 0).await);
+        assert_exceeded(reserve(&mongo, &user._id, 101, 0).await);
+        check_quota(&mongo, &user._id, 40, 0).await.unwrap();
+        assert_exceeded(check_quota(&mongo, &user._id, 41, 0).await);
+        reserve(&mongo, &user._id, 40, 0).await.unwrap();
+        //清掉一些之后又能传了
+        charge(&mongo, &user._id, -50).await;
+        reserve(&mongo, &user._id, 50, 0).await.unwrap();
+        let stored = users(&mongo).find_one(doc! { "_id": user._id }).await.unwrap().unwrap();
+        assert_eq!(stored.used_bytes, 100);
+
+        //没单独设配额的按默认值，0是不限
+        let unlimited = account(None);
+        users(&mongo).insert_one(&unlimited).await.unwrap();
+        reserve(&mongo, &unlimited._id, 1 << 40, 0).await.unwrap();
+        assert_exceeded(reserve(&mongo, &unlimited._id, 1, 10).await);
+        mongo.database.drop().await.unwrap();
+    }
+}
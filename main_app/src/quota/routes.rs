@@ -0,0 +1,136 @@
+use super::lib::{effective_quota, usage_by_kind};
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileType, User};
+use crate::libs::{check_file_permission, mongo_error_check, parse_object_id, ApiError};
+use crate::MyConfig;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use rocket::futures::TryStreamExt;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderUsage {
+    pub _id: ObjectId,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: FileType,
+    pub size: u64, //文件夹是下面所有文件加起来
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsageResponse {
+    pub quota: u64, //0表示不限
+    pub used: i64,
+    pub files: i64,
+    pub trash: i64,
+    pub versions: i64,
+    pub folder: ObjectId,
+    pub children: Vec<FolderUsage>,
+}
+
+//按father往下找，children可能是旧的；visited防止数据坏了成环
+fn subtree_size(root: &File, by_father: &HashMap<ObjectId, Vec<&File>>) -> u64 {
+    let mut size = 0;
+    let mut pending = vec![root];
+    let mut visited = HashSet::new();
+    while let Some(file) = pending.pop() {
+        if !visited.insert(file._id) {
+            continue;
+        }
+        if file.type_ == FileType::File {
+            size += file.size;
+        } else if let Some(children) = by_father.get(&file._id) {
+            pending.extend(children);
+        }
+    }
+    size
+}
+
+//folder不填就是home，children是folder下面每一项各占多少
+#[get("/usage?<folder>")]
+pub async fn get_usage(
+    folder: Option<&str>,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    config: &rocket::State<MyConfig>,
+) -> Result<Json<UsageResponse>, ApiError> {
+    let folder = match folder {
+        Some(folder) => parse_object_id(folder)?,
+        None => user.root_id,
+    };
+    let account = mongo_error_check(
+        mongo.database.collection::<User>("users").find_one(doc! { "_id": user.uuid }).await,
+        Some("User"),
+    )?;
+    let files: Vec<File> = match mongo
+        .database
+        .collection::<File>("files")
+        .find(doc! { "owner": user.uuid })
+        .await
+    {
+        Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    let files: HashMap<ObjectId, File> = files.into_iter().map(|f| (f._id, f)).collect();
+    let target = match files.get(&folder) {
+        Some(target) => target,
+        None => return Err(ApiError::NotFound("Folder not found".to_string().into())),
+    };
+    check_file_permission(&user, target, mongo).await?;
+    //father指向自己的不能算成自己的孩子
+    let mut by_father: HashMap<ObjectId, Vec<&File>> = HashMap::new();
+    for file in files.values().filter(|f| f.father != f._id) {
+        by_father.entry(file.father).or_default().push(file);
+    }
+    let mut children: Vec<FolderUsage> = by_father
+        .get(&folder)
+        .map(|children| children.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|child| FolderUsage {
+            _id: child._id,
+            name: child.name.clone(),
+            type_: child.type_.clone(),
+            size: subtree_size(child, &by_father),
+        })
+        .collect();
+    children.sort_by_key(|c| std::cmp::Reverse(c.size));
+    let (live, trash, versions) = usage_by_kind(mongo, &user.uuid).await?;
+    Ok(Json(UsageResponse {
+        quota: effective_quota(&account, config.default_quota),
+        used: account.used_bytes,
+        files: live,
+        trash,
+        versions,
+        folder,
+        children,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, father: &ObjectId, owner: &ObjectId, size: u64) -> File {
+        File { type_: FileType::File, size, ..File::new_folder(name, father, owner, None) }
+    }
+
+    #[test]
+    fn subtree_size_follows_father_not_children() {
+        let owner = ObjectId::new();
+        let home = ObjectId::new();
+        //移进来的文件还没写进children
+        let folder = File::new_folder("a", &home, &owner, None);
+        let inner = File::new_folder("b", &folder._id, &owner, None);
+        let files = [file("x", &folder._id, &owner, 3), file("y", &inner._id, &owner, 4), inner];
+        let mut by_father: HashMap<ObjectId, Vec<&File>> = HashMap::new();
+        for file in &files {
+            by_father.entry(file.father).or_default().push(file);
+        }
+        assert!(folder.children.is_empty());
+        assert_eq!(subtree_size(&folder, &by_father), 7);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::libs::ApiError;
+use crate::MyConfig;
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+use std::net::IpAddr;
+
+//guard里没法直接带header，超限的时候把秒数放进request的local cache，由429的catcher取出来
+pub struct RetryAfter(pub u64);
+
+#[catch(429)]
+pub fn too_many_requests(request: &Request) -> ApiError {
+    ApiError::TooManyRequests(request.local_cache(|| RetryAfter(1)).0)
+}
+
+//按IP计数用的客户端地址，默认只认socket的对端地址
+//放在反代后面的时候在配置里写上反代会设置的header，才去读header，不然谁都能伪造X-Real-IP绕开限制
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let remote = request.remote().map(|addr| addr.ip());
+        let header = match request.rocket().state::<MyConfig>() {
+            Some(config) if !config.trusted_ip_header.is_empty() => config.trusted_ip_header.as_str(),
+            _ => return Outcome::Success(ClientIp(remote)),
+        };
+        //X-Forwarded-For这种一串的取最后一个，是离我们最近的反代加上去的
+        let ip = request
+            .headers()
+            .get_one(header)
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|value| value.trim().parse().ok());
+        Outcome::Success(ClientIp(ip.or(remote)))
+    }
+}
@@ -0,0 +1,164 @@
+//登录、分享密码的防爆破，以及每个token的请求频率限制，计数都放redis
+use crate::db::connect::Redis;
+use crate::libs::ApiError;
+use crate::MyConfig;
+use chrono::Utc;
+use std::net::IpAddr;
+
+//这段时间里没再错就清零，锁定时间比它长的话按锁定时间算，不然翻倍就断了
+const FAILURE_WINDOW: i64 = 15 * 60;
+
+pub struct Counter {
+    pub key: String,
+    pub max_failures: u64,
+    pub per_ip: bool, //按IP的不因为登录成功清零，不然拿自己的号就能给IP解锁
+}
+
+//kind是"login"、"totp"、"share"这种，subject是用户名、分享链接之类的
+pub fn counters(kind: &str, ip: Option<IpAddr>, subject: &str, config: &MyConfig) -> Vec<Counter> {
+    let mut counters = vec![Counter {
+        key: format!("rl:{}:subject:{}", kind, subject),
+        max_failures: config.login_max_failures,
+        per_ip: false,
+    }];
+    if let Some(ip) = ip {
+        counters.push(Counter {
+            key: format!("rl:{}:ip:{}", kind, ip),
+            max_failures: config.login_ip_max_failures,
+            per_ip: true,
+        });
+    }
+    counters
+}
+
+fn lock_key(counter: &Counter) -> String {
+    format!("{}:lock", counter.key)
+}
+
+//锁着的话返回还要等多久
+pub async fn check_locked(redis: &Redis, counters: &[Counter]) -> Result<(), ApiError> {
+    let mut wait = 0;
+    for counter in counters {
+        wait = wait.max(redis.ttl(lock_key(counter)).await);
+    }
+    if wait > 0 {
+        return Err(ApiError::TooManyRequests(wait as u64));
+    }
+    Ok(())
+}
+
+//超过次数之后每多错一次锁的时间翻倍
+pub async fn record_failure(redis: &Redis, config: &MyConfig, counters: &[Counter]) {
+    for counter in counters {
+        if counter.max_failures == 0 {
+            continue;
+        }
+        let failures = redis.incr(&counter.key).await as u64;
+        redis.expire(&counter.key, FAILURE_WINDOW.max(config.lockout_max_secs as i64)).await;
+        if failures >= counter.max_failures {
+            let exponent = (failures - counter.max_failures).min(32) as u32;
+            let seconds = config.lockout_base_secs.saturating_mul(2u64.saturating_pow(exponent)).min(config.lockout_max_secs);
+            if seconds > 0 {
+                redis.set(lock_key(counter), 1).await;
+                redis.expire(lock_key(counter), seconds as i64).await;
+            }
+        }
+    }
+}
+
+pub async fn record_success(redis: &Redis, counters: &[Counter]) {
+    for counter in counters.iter().filter(|c| !c.per_ip) {
+        redis.delete(&counter.key).await;
+    }
+}
+
+//固定窗口计数，超了返回窗口还剩几秒
+pub async fn check_api_rate(redis: &Redis, config: &MyConfig, token: &str) -> Result<(), u64> {
+    if config.api_rate_limit == 0 || config.api_rate_window_secs == 0 {
+        return Ok(());
+    }
+    let window = config.api_rate_window_secs as i64;
+    let now = Utc::now().timestamp();
+    let key = format!("rl:api:{}:{}", token, now / window);
+    let count = redis.incr(&key).await;
+    if count == 1 {
+        redis.expire(&key, window).await;
+    }
+    if count as u64 > config.api_rate_limit {
+        return Err((window - now % window) as u64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use mongodb::bson::oid::ObjectId;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use rocket::response::Responder;
+
+    fn test_config(temp: crate::TempConfig) -> MyConfig {
+        MyConfig::from_temp(ObjectId::new(), &temp)
+    }
+
+    #[rocket::async_test]
+    async fn too_many_requests_sets_retry_after() {
+        let client = Client::untracked(rocket::build()).await.unwrap();
+        let request = client.get("/");
+        let response = ApiError::TooManyRequests(42).respond_to(&request).unwrap();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("42"));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the redis_dev service from compose.yaml"]
+    async fn api_rate_rejects_past_limit_until_window_ends() {
+        let redis = test_support::redis().await;
+        let config = test_config(crate::TempConfig {
+            api_rate_limit: 2,
+            api_rate_window_secs: 3600,
+            ..Default::default()
+        });
+        let token = ObjectId::new().to_hex();
+        assert!(check_api_rate(&redis, &config, &token).await.is_ok());
+        assert!(check_api_rate(&redis, &config, &token).await.is_ok());
+        let retry_after = check_api_rate(&redis, &config, &token).await.unwrap_err();
+        assert!(retry_after > 0 && retry_after <= 3600);
+        //别的token不受影响
+        assert!(check_api_rate(&redis, &config, &ObjectId::new().to_hex()).await.is_ok());
+        let key = format!("rl:api:{}:{}", token, Utc::now().timestamp() / 3600);
+        assert!(redis.ttl(&key).await <= 3600);
+        redis.delete(&key).await;
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the redis_dev service from compose.yaml"]
+    async fn lockout_doubles_and_success_clears_subject() {
+        let redis = test_support::redis().await;
+        let config = test_config(crate::TempConfig {
+            login_max_failures: 2,
+            login_ip_max_failures: 0,
+            lockout_base_secs: 30,
+            lockout_max_secs: 100,
+            ..Default::default()
+        });
+        let counters = counters("login", None, &ObjectId::new().to_hex(), &config);
+        record_failure(&redis, &config, &counters).await;
+        assert!(check_locked(&redis, &counters).await.is_ok());
+        record_failure(&redis, &config, &counters).await;
+        match check_locked(&redis, &counters).await {
+            Err(ApiError::TooManyRequests(wait)) => assert!(wait > 0 && wait <= 30),
+            other => panic!("expected lockout, got {:?}", other),
+        }
+        record_failure(&redis, &config, &counters).await;
+        assert!(redis.ttl(lock_key(&counters[0])).await > 30);
+        //翻倍之后也不超过上限
+        record_failure(&redis, &config, &counters).await;
+        assert!(redis.ttl(lock_key(&counters[0])).await <= 100);
+        record_success(&redis, &counters).await;
+        assert!(!redis.exists(&counters[0].key).await);
+        redis.delete(lock_key(&counters[0])).await;
+    }
+}
@@ -0,0 +1,112 @@
+//要连mongo、redis的测试用，先起compose.yaml里的mongodb_dev和redis_dev，然后cargo test -- --ignored
+//地址默认就是compose里的，可以用RC_TEST_MONGO_URI、RC_TEST_REDIS_URI覆盖
+//每次用一个新库，跑完不用清
+use crate::auth::guard::AuthenticatedUser;
+use crate::auth::lib::{create_user, generate_jwt};
+use crate::db::connect::{MongoDb, Redis};
+use crate::db::models::{File, FileType, LoginedDeviceType, User, UserRole};
+use crate::file::storage_backend::flat::LocalFlatStorageBackend;
+use crate::file::storage_backend::lib::{StorageBackend, StorageFactory};
+use crate::{MyConfig, TempConfig};
+use mongodb::bson::oid::ObjectId;
+use rocket::local::asynchronous::Client;
+use rocket::tokio::sync::Mutex;
+use rocket::Route;
+use std::sync::Arc;
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+pub async fn mongo() -> MongoDb {
+    let name = format!("rc_test_{}", ObjectId::new().to_hex());
+    MongoDb::init(&env_or("RC_TEST_MONGO_URI", "mongodb://localhost:27017"), &name).await
+}
+
+pub async fn redis() -> Redis {
+    Redis::init(&env_or("RC_TEST_REDIS_URI", "redis://localhost:6379")).await
+}
+
+//只有home文件夹的新用户，不写users表
+pub async fn user(mongo: &MongoDb) -> AuthenticatedUser {
+    let uuid = ObjectId::new();
+    let root = File {
+        type_: FileType::Root,
+        ..File::new_folder("home", &uuid, &uuid, None)
+    };
+    mongo.database.collection::<File>("files").insert_one(&root).await.unwrap();
+    AuthenticatedUser {
+        uuid,
+        username: uuid.to_hex(),
+        nickname: uuid.to_hex(),
+        token: None,
+        root_id: root._id,
+        role: UserRole::Normal,
+        scopes: None,
+        folder: None,
+    }
+}
+
+pub async fn folder(mongo: &MongoDb, name: &str, father: &ObjectId, owner: &ObjectId) -> File {
+    let folder = File::new_folder(name, father, owner, None);
+    mongo.database.collection::<File>("files").insert_one(&folder).await.unwrap();
+    folder
+}
+
+pub async fn file(mongo: &MongoDb, name: &str, father: &ObjectId, owner: &ObjectId, size: u64) -> File {
+    let file = File {
+        type_: FileType::File,
+        size,
+        ..File::new_folder(name, father, owner, None)
+    };
+    mongo.database.collection::<File>("files").insert_one(&file).await.unwrap();
+    file
+}
+
+//存储放在临时目录里的配置，只注册FLAT
+pub fn storage() -> (MyConfig, StorageFactory) {
+    let dir = std::env::temp_dir().join(format!("rc_test_{}", ObjectId::new().to_hex()));
+    let temp = TempConfig {
+        flat_storage_path: dir.join("flat").to_string_lossy().to_string(),
+        cache_storage_path: dir.join("cache").to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    std::fs::create_dir_all(&temp.flat_storage_path).unwrap();
+    std::fs::create_dir_all(&temp.cache_storage_path).unwrap();
+    let config = MyConfig::from_temp(ObjectId::new(), &temp);
+    let mut factory = StorageFactory::new(&config);
+    factory.register_backend("FLAT", Box::new(LocalFlatStorageBackend::new(factory.get_config())));
+    (config, factory)
+}
+
+//只挂了给出的这些路由的rocket
+pub async fn client(
+    mongo: &MongoDb,
+    redis: &Redis,
+    config: &MyConfig,
+    factory: StorageFactory,
+    mounts: Vec<(&str, Vec<Route>)>,
+) -> Client {
+    let mut rocket = rocket::build()
+        .manage(config.clone())
+        .manage(mongo.clone())
+        .manage(redis.clone())
+        .manage(Arc::new(Mutex::new(factory)));
+    for (base, routes) in mounts {
+        rocket = rocket.mount(base, routes);
+    }
+    Client::untracked(rocket).await.unwrap()
+}
+
+//users表里真正建一个用户，返回Authorization头的值
+pub async fn login(mongo: &MongoDb, redis: &Redis, config: &MyConfig) -> (User, String) {
+    let name = ObjectId::new().to_hex();
+    let user = create_user(&name, "password", &name, UserRole::Normal, mongo, &config.system_root_id)
+        .await
+        .unwrap();
+    let (token, jti) = generate_jwt(&user._id, &config.jwt_secret);
+    let key = LoginedDeviceType::Normal.redis_key(&jti);
+    redis.set(&key, user._id.to_string()).await;
+    redis.expire(&key, 60 * 60).await;
+    (user, format!("Bearer {}", token))
+}
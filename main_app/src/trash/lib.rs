@@ -0,0 +1,310 @@
+//删掉的文件先整棵挪到trashed_files里，blob的引用还留着，彻底删除的时候才release
+//files里就找不到了，所以下载、分享、webdav都不用额外判断
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileType, TrashItem};
+use crate::file::storage_backend::blob_storage;
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::libs::ApiError;
+use chrono::Utc;
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::Collection;
+use rocket::futures::TryStreamExt;
+use rocket::tokio::sync::Mutex;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn db_error<E>(_: E) -> ApiError {
+    ApiError::InternalServerError("Database error".to_string().into())
+}
+
+fn files(mongo: &MongoDb) -> Collection<File> {
+    mongo.database.collection::<File>("files")
+}
+
+fn trashed_files(mongo: &MongoDb) -> Collection<File> {
+    mongo.database.collection::<File>("trashed_files")
+}
+
+pub fn trash_collection(mongo: &MongoDb) -> Collection<TrashItem> {
+    mongo.database.collection::<TrashItem>("trash")
+}
+
+//root和它下面所有的文件，按father+owner一层层找，children字段不可信
+//结果是一层一层排的，父文件夹一定在子文件前面
+async fn collect_subtree(collection: &Collection<File>, root: File) -> Result<Vec<File>, ApiError> {
+    let mut visited = HashSet::from([root._id]);
+    let mut pending = vec![root._id];
+    let owner = root.owner;
+    let mut result = vec![root];
+    while !pending.is_empty() {
+        let children: Vec<File> = collection
+            .find(doc! { "father": { "$in": &pending }, "owner": owner })
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+        //数据坏了出现环的时候不要死循环
+        let children: Vec<File> = children.into_iter().filter(|c| visited.insert(c._id)).collect();
+        pending = children.iter().filter(|c| c.type_ != FileType::File).map(|c| c._id).collect();
+        result.extend(children);
+    }
+    Ok(result)
+}
+
+pub async fn move_to_trash(file: File, mongo: &MongoDb) -> Result<(), ApiError> {
+    let subtree = collect_subtree(&files(mongo), file.clone()).await?;
+    let ids: Vec<ObjectId> = subtree.iter().map(|f| f._id).collect();
+    let size = subtree
+        .iter()
+        .filter(|f| f.type_ == FileType::File)
+        .map(|f| f.size)
+        .sum();
+    //先复制过去再删，中途失败了顶多多一份，不会丢
+    trashed_files(mongo).insert_many(&subtree).await.map_err(db_error)?;
+    let item = TrashItem {
+        _id: file._id,
+        owner: file.owner,
+        father: file.father,
+        name: file.name.clone(),
+        type_: file.type_.clone(),
+        size,
+        deleted_at: Utc::now().timestamp(),
+    };
+    trash_collection(mongo).insert_one(&item).await.map_err(db_error)?;
+    files(mongo)
+        .delete_many(doc! { "_id": { "$in": &ids } })
+        .await
+        .map_err(db_error)?;
+    files(mongo)
+        .update_one(
+            doc! { "_id": file.father },
+            doc! { "$pull": { "children": file._id }, "$set": { "updated_at": Utc::now().timestamp() } },
+        )
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+pub async fn get_trash_item(id: &ObjectId, user: &AuthenticatedUser, mongo: &MongoDb) -> Result<TrashItem, ApiError> {
+    match trash_collection(mongo).find_one(doc! { "_id": id }).await {
+        Ok(Some(item)) if item.owner == user.uuid => Ok(item),
+        Ok(Some(_)) => Err(ApiError::Forbidden("Permission denied".to_string().into())),
+        Ok(None) => Err(ApiError::NotFound("Trash item not found".to_string().into())),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+//同一个文件夹里已经有同名的就在后面加编号
+async fn available_name(father: &File, name: &str, mongo: &MongoDb) -> Result<String, ApiError> {
+    let siblings: Vec<File> = files(mongo)
+        .find(doc! { "father": father._id, "owner": father.owner })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    let taken = |candidate: &str| siblings.iter().any(|s| s.name == candidate);
+    if !taken(name) {
+        return Ok(name.to_string());
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut n = 1;
+    loop {
+        let candidate = format!("{} ({}){}", stem, n, ext);
+        if !taken(&candidate) {
+            return Ok(candidate);
+        }
+        n += 1;
+    }
+}
+
+//恢复到原来的文件夹，原文件夹没了就放到指定的father，都没有就放回home
+pub async fn restore(
+    item: TrashItem,
+    father: Option<ObjectId>,
+    user: &AuthenticatedUser,
+    mongo: &MongoDb,
+) -> Result<File, ApiError> {
+    let find_folder = |id: ObjectId| async move {
+        match files(mongo).find_one(doc! { "_id": id }).await {
+            Ok(Some(folder)) if folder.owner == user.uuid && folder.type_ != FileType::File => Ok(Some(folder)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(db_error(e)),
+        }
+    };
+    let target = match father {
+        Some(father) => match find_folder(father).await? {
+            Some(folder) => folder,
+            None => return Err(ApiError::NotFound("Father folder not found".to_string().into())),
+        },
+        None => match find_folder(item.father).await? {
+            Some(folder) => folder,
+            None => match find_folder(user.root_id).await? {
+                Some(folder) => folder,
+                None => return Err(ApiError::NotFound("Father folder not found".to_string().into())),
+            },
+        },
+    };
+    let root = match trashed_files(mongo).find_one(doc! { "_id": item._id }).await {
+        Ok(Some(root)) => root,
+        Ok(None) => return Err(ApiError::NotFound("Trash item not found".to_string().into())),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    let mut subtree = collect_subtree(&trashed_files(mongo), root).await?;
+    let ids: Vec<ObjectId> = subtree.iter().map(|f| f._id).collect();
+    subtree[0].father = target._id;
+    subtree[0].name = available_name(&target, &item.name, mongo).await?;
+    subtree[0].updated_at = Utc::now().timestamp();
+    let restored = subtree[0].clone();
+    files(mongo).insert_many(&subtree).await.map_err(db_error)?;
+    files(mongo)
+        .update_one(
+            doc! { "_id": target._id },
+            doc! { "$push": { "children": restored._id }, "$set": { "updated_at": Utc::now().timestamp() } },
+        )
+        .await
+        .map_err(db_error)?;
+    trashed_files(mongo)
+        .delete_many(doc! { "_id": { "$in": &ids } })
+        .await
+        .map_err(db_error)?;
+    trash_collection(mongo)
+        .delete_one(doc! { "_id": item._id })
+        .await
+        .map_err(db_error)?;
+    Ok(restored)
+}
+
+//彻底删除，文件的blob和历史版本都少一次引用
+pub async fn purge(item: &TrashItem, mongo: &MongoDb, factory: &StorageFactory) -> Result<(), ApiError> {
+    let root = trashed_files(mongo)
+        .find_one(doc! { "_id": item._id })
+        .await
+        .map_err(db_error)?;
+    if let Some(root) = root {
+        let subtree = collect_subtree(&trashed_files(mongo), root).await?;
+        //先放掉引用再删记录，中途失败了剩下的记录还在，下次接着清
+        //从叶子往上删，父文件夹的记录没了下次就找不到下面的了
+        for file in subtree.into_iter().rev() {
+            if file.type_ == FileType::File && file.storage_type == blob_storage::BLOB_STORAGE_TYPE {
+                blob_storage::release(mongo, factory, &file.sha256).await?;
+            }
+            if file.type_ == FileType::File {
+                crate::quota::lib::charge(mongo, &file.owner, -(file.size as i64)).await;
+                crate::file::version::lib::delete_versions(&file._id, mongo, factory).await?;
+            }
+            trashed_files(mongo)
+                .delete_one(doc! { "_id": file._id })
+                .await
+                .map_err(db_error)?;
+        }
+    }
+    trash_collection(mongo)
+        .delete_one(doc! { "_id": item._id })
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+pub async fn purge_expired(mongo: &MongoDb, storage_factory: &Mutex<StorageFactory>, retention_days: u64) -> Result<(), ApiError> {
+    let deadline = Utc::now().timestamp() - (retention_days * 24 * 60 * 60) as i64;
+    let expired: Vec<TrashItem> = trash_collection(mongo)
+        .find(doc! { "deleted_at": { "$lt": deadline } })
+        .await
+        .map_err(db_error)?
+        .try_collect()
+        .await
+        .map_err(db_error)?;
+    let factory = storage_factory.lock().await.clone();
+    for item in expired {
+        purge(&item, mongo, &factory).await?;
+    }
+    Ok(())
+}
+
+//后台每小时清一次过期的，retention_days为0就不自动清
+pub async fn purge_loop(mongo: MongoDb, storage_factory: Arc<Mutex<StorageFactory>>, retention_days: u64) {
+    if retention_days == 0 {
+        return;
+    }
+    loop {
+        if let Err(e) = purge_expired(&mongo, &storage_factory, retention_days).await {
+            error!("清理回收站失败: {:?}", e);
+        }
+        rocket::tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    async fn exists(collection: &Collection<File>, id: &ObjectId) -> bool {
+        collection.find_one(doc! { "_id": id }).await.unwrap().is_some()
+    }
+
+    //只改了father，旧文件夹的children里还留着它，新文件夹的children里没有
+    async fn move_file(mongo: &MongoDb, file: &File, to: &File) {
+        files(mongo)
+            .update_one(doc! { "_id": file._id }, doc! { "$set": { "father": to._id } })
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev service from compose.yaml"]
+    async fn subtree_follows_father_after_move() {
+        let mongo = test_support::mongo().await;
+        let user = test_support::user(&mongo).await;
+        let old = test_support::folder(&mongo, "old", &user.root_id, &user.uuid).await;
+        let new = test_support::folder(&mongo, "new", &user.root_id, &user.uuid).await;
+        let mut moved = test_support::file(&mongo, "a.txt", &old._id, &user.uuid, 10).await;
+        files(&mongo)
+            .update_one(doc! { "_id": old._id }, doc! { "$push": { "children": moved._id } })
+            .await
+            .unwrap();
+        let old = files(&mongo).find_one(doc! { "_id": old._id }).await.unwrap().unwrap();
+        move_file(&mongo, &moved, &new).await;
+        moved.father = new._id;
+        //别人的文件就算father指过来也不能带走
+        let other = test_support::file(&mongo, "other.txt", &new._id, &ObjectId::new(), 1).await;
+
+        move_to_trash(old, &mongo).await.unwrap();
+        assert!(exists(&files(&mongo), &moved._id).await);
+
+        move_to_trash(new.clone(), &mongo).await.unwrap();
+        assert!(!exists(&files(&mongo), &moved._id).await);
+        assert!(exists(&trashed_files(&mongo), &moved._id).await);
+        assert!(exists(&files(&mongo), &other._id).await);
+        let item = trash_collection(&mongo).find_one(doc! { "_id": new._id }).await.unwrap().unwrap();
+        assert_eq!(item.size, 10);
+
+        let restored = restore(item, None, &user, &mongo).await.unwrap();
+        assert_eq!(restored.father, user.root_id);
+        assert!(exists(&files(&mongo), &moved._id).await);
+        assert!(!exists(&trashed_files(&mongo), &moved._id).await);
+        mongo.database.drop().await.unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the mongodb_dev service from compose.yaml"]
+    async fn restore_renames_on_conflict() {
+        let mongo = test_support::mongo().await;
+        let user = test_support::user(&mongo).await;
+        let file = test_support::file(&mongo, "a.txt", &user.root_id, &user.uuid, 1).await;
+        move_to_trash(file, &mongo).await.unwrap();
+        test_support::file(&mongo, "a.txt", &user.root_id, &user.uuid, 1).await;
+        let item = trash_collection(&mongo).find_one(doc! {}).await.unwrap().unwrap();
+        let restored = restore(item, None, &user, &mongo).await.unwrap();
+        assert_eq!(restored.name, "a (1).txt");
+        mongo.database.drop().await.unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use super::lib::{get_trash_item, purge, restore, trash_collection};
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, TrashItem};
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::libs::{parse_object_id, ApiError};
+use mongodb::bson::doc;
+use rocket::futures::TryStreamExt;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Mutex;
+use std::sync::Arc;
+
+#[get("/")]
+pub async fn list_trash(
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<Vec<TrashItem>>, ApiError> {
+    let items = trash_collection(mongo)
+        .find(doc! { "owner": user.uuid })
+        .sort(doc! { "deleted_at": -1 })
+        .await;
+    let items = match items {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match items {
+        Ok(items) => Ok(Json(items)),
+        Err(_) => Err(ApiError::InternalServerError("Database error".to_string().into())),
+    }
+}
+
+//father可以不填，原来的文件夹还在就放回去，不在了就放回home
+#[post("/<uuid>/restore?<father>")]
+pub async fn restore_item(
+    uuid: &str,
+    father: Option<&str>,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+) -> Result<Json<File>, ApiError> {
+    let item = get_trash_item(&parse_object_id(uuid)?, &user, mongo).await?;
+    let father = match father {
+        Some(father) => Some(parse_object_id(father)?),
+        None => None,
+    };
+    Ok(Json(restore(item, father, &user, mongo).await?))
+}
+
+#[delete("/<uuid>")]
+pub async fn delete_item(
+    uuid: &str,
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<status::NoContent, ApiError> {
+    let item = get_trash_item(&parse_object_id(uuid)?, &user, mongo).await?;
+    let factory = storage_factory.lock().await.clone();
+    purge(&item, mongo, &factory).await?;
+    Ok(status::NoContent)
+}
+
+#[delete("/")]
+pub async fn empty_trash(
+    user: AuthenticatedUser,
+    mongo: &rocket::State<MongoDb>,
+    storage_factory: &rocket::State<Arc<Mutex<StorageFactory>>>,
+) -> Result<status::NoContent, ApiError> {
+    let items: Vec<TrashItem> = match trash_collection(mongo).find(doc! { "owner": user.uuid }).await {
+        Ok(cursor) => cursor.try_collect().await.unwrap_or_default(),
+        Err(_) => return Err(ApiError::InternalServerError("Database error".to_string().into())),
+    };
+    let factory = storage_factory.lock().await.clone();
+    for item in items {
+        purge(&item, mongo, &factory).await?;
+    }
+    Ok(status::NoContent)
+}
@@ -0,0 +1,650 @@
+//把files集合映射成dav_server的文件系统，根目录是用户的root_id
+use crate::auth::guard::AuthenticatedUser;
+use crate::db::connect::MongoDb;
+use crate::db::models::{File, FileExtraMetadata, FileType, VersionPolicy};
+use crate::file::lib::{commit_uploaded_file, get_local_file_type, stamp_device};
+use crate::file::version::lib::prune_versions;
+use crate::quota::lib::{check_quota, QUOTA_EXCEEDED};
+use crate::file::storage_backend::lib::{FileStream, StorageFactory};
+use crate::file::storage_backend::blob_storage::{
+    acquire_existing, replace_content, resolve_blob, store_local_file,
+};
+use crate::file_metadata::routes::delete_file_l;
+use crate::libs::ApiError;
+use bytes::{Buf, Bytes};
+use chrono::Utc;
+use dav_server::davpath::DavPath;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+    OpenOptions, ReadDirMeta,
+};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use rocket::futures::{stream, FutureExt, TryStreamExt};
+use rocket::tokio::fs;
+use rocket::tokio::fs::File as AsyncFile;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+impl From<ApiError> for FsError {
+    fn from(err: ApiError) -> Self {
+        match err {
+            ApiError::NotFound(_) => FsError::NotFound,
+            ApiError::Forbidden(Some(message)) if message == QUOTA_EXCEEDED => FsError::InsufficientStorage,
+            ApiError::Forbidden(_) | ApiError::Unauthorized(_) => FsError::Forbidden,
+            _ => FsError::GeneralFailure,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MongoDavFs {
+    pub user: AuthenticatedUser,
+    pub mongo: MongoDb,
+    pub storage_factory: Arc<Mutex<StorageFactory>>,
+    pub cache_storage_path: String,
+    pub version_policy: VersionPolicy,
+    pub default_quota: u64,
+    pub default_storage_type: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DavMeta {
+    size: u64,
+    updated_at: i64,
+    created_at: i64,
+    is_dir: bool,
+    sha256: String,
+}
+
+impl DavMeta {
+    fn from_file(file: &File) -> Self {
+        Self {
+            size: file.size,
+            updated_at: file.updated_at,
+            created_at: file.created_at,
+            is_dir: file.type_ != FileType::File,
+            sha256: file.sha256.clone(),
+        }
+    }
+}
+
+fn to_system_time(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+impl DavMetaData for DavMeta {
+    fn len(&self) -> u64 {
+        self.size
+    }
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(to_system_time(self.updated_at))
+    }
+    fn created(&self) -> FsResult<SystemTime> {
+        Ok(to_system_time(self.created_at))
+    }
+    fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+    fn etag(&self) -> Option<String> {
+        if self.is_dir {
+            Some(format!("{:x}", self.updated_at))
+        } else {
+            Some(self.sha256.clone())
+        }
+    }
+}
+
+struct DavEntry {
+    name: String,
+    meta: DavMeta,
+}
+
+impl DavDirEntry for DavEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta: Box<dyn DavMetaData> = Box::new(self.meta.clone());
+        Box::pin(async move { Ok(meta) })
+    }
+}
+
+fn path_names(path: &DavPath) -> Vec<String> {
+    path.as_rel_ospath()
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+impl MongoDavFs {
+    fn collection(&self) -> mongodb::Collection<File> {
+        self.mongo.database.collection::<File>("files")
+    }
+
+    async fn find_child(&self, folder: &File, name: &str) -> FsResult<Option<File>> {
+        if folder.type_ == FileType::File {
+            return Err(FsError::NotFound);
+        }
+        //按father找，children字段不可信，REST那边挪过的文件不会及时更新
+        self.collection()
+            .find_one(doc! { "father": folder._id, "_id": { "$ne": folder._id }, "owner": folder.owner, "name": name })
+            .await
+            .map_err(|_| FsError::GeneralFailure)
+    }
+
+    async fn resolve_names(&self, names: &[String]) -> FsResult<File> {
+        let mut current = match self.collection().find_one(doc! { "_id": self.user.root_id }).await {
+            Ok(Some(root)) => root,
+            _ => return Err(FsError::NotFound),
+        };
+        for name in names {
+            current = match self.find_child(&current, name).await? {
+                Some(child) => child,
+                None => return Err(FsError::NotFound),
+            };
+        }
+        if current.owner != self.user.uuid {
+            return Err(FsError::Forbidden);
+        }
+        Ok(current)
+    }
+
+    async fn resolve(&self, path: &DavPath) -> FsResult<File> {
+        self.resolve_names(&path_names(path)).await
+    }
+
+    //返回父文件夹和最后一段名字
+    async fn resolve_parent(&self, path: &DavPath) -> FsResult<(File, String)> {
+        let mut names = path_names(path);
+        let name = match names.pop() {
+            Some(name) => name,
+            None => return Err(FsError::Forbidden),
+        };
+        let parent = self.resolve_names(&names).await?;
+        if parent.type_ == FileType::File {
+            return Err(FsError::NotFound);
+        }
+        Ok((parent, name))
+    }
+
+    async fn detach(&self, file: &File) -> FsResult<()> {
+        self.collection()
+            .update_one(
+                doc! { "_id": file.father },
+                doc! { "$pull": { "children": file._id }, "$set": { "updated_at": Utc::now().timestamp() } },
+            )
+            .await
+            .map_err(|_| FsError::GeneralFailure)?;
+        Ok(())
+    }
+
+    async fn attach(&self, file_id: ObjectId, parent: &File) -> FsResult<()> {
+        self.collection()
+            .update_one(
+                doc! { "_id": parent._id },
+                doc! { "$push": { "children": file_id }, "$set": { "updated_at": Utc::now().timestamp() } },
+            )
+            .await
+            .map_err(|_| FsError::GeneralFailure)?;
+        Ok(())
+    }
+
+    //MOVE/COPY带Overwrite时dav_server只帮忙删目录，目标是文件的要自己覆盖
+    async fn clear_target(&self, source: &File, parent: &File, name: &str) -> FsResult<()> {
+        match self.find_child(parent, name).await? {
+            Some(target) if target._id == source._id => Err(FsError::Forbidden),
+            Some(target) if target.type_ == FileType::File => {
+                delete_file_l(&target._id.to_hex(), &self.user, &self.mongo).await?;
+                Ok(())
+            }
+            Some(_) => Err(FsError::Exists),
+            None => Ok(()),
+        }
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/webdav/{}",
+            self.cache_storage_path,
+            uuid::Uuid::new_v4()
+        ))
+    }
+}
+
+impl DavFileSystem for MongoDavFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            if !options.write {
+                let file = self.resolve(path).await?;
+                if file.type_ != FileType::File {
+                    return Err(FsError::Forbidden);
+                }
+                let blob = resolve_blob(file.clone(), &self.mongo).await?;
+                let dav_file: Box<dyn DavFile> = Box::new(DavReadFile {
+                    meta: DavMeta::from_file(&file),
+                    blob,
+                    storage_factory: self.storage_factory.clone(),
+                    position: 0,
+                    stream: None,
+                });
+                return Ok(dav_file);
+            }
+
+            let (parent, name) = self.resolve_parent(path).await?;
+            let existing = self.find_child(&parent, &name).await?;
+            match &existing {
+                Some(_) if options.create_new => return Err(FsError::Exists),
+                Some(file) if file.type_ != FileType::File => return Err(FsError::Forbidden),
+                None if !options.create => return Err(FsError::NotFound),
+                _ => {}
+            }
+            let temp_path = self.temp_path();
+            let _ = fs::create_dir_all(temp_path.parent().unwrap()).await;
+            let temp = AsyncFile::create(&temp_path).await.map_err(|_| FsError::GeneralFailure)?;
+            let dav_file: Box<dyn DavFile> = Box::new(DavWriteFile {
+                fs: self.clone(),
+                parent,
+                name,
+                existing,
+                temp,
+                temp_path,
+                hasher: Sha256::new(),
+                size: 0,
+                expected_size: options.size,
+                committed: false,
+            });
+            Ok(dav_file)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            let folder = self.resolve(path).await?;
+            if folder.type_ == FileType::File {
+                return Err(FsError::Forbidden);
+            }
+            let children: Vec<File> = self
+                .collection()
+                .find(doc! { "father": folder._id, "_id": { "$ne": folder._id }, "owner": folder.owner })
+                .await
+                .map_err(|_| FsError::GeneralFailure)?
+                .try_collect()
+                .await
+                .map_err(|_| FsError::GeneralFailure)?;
+            let entries = children
+                .into_iter()
+                .map(|child| {
+                    let entry: Box<dyn DavDirEntry> = Box::new(DavEntry {
+                        meta: DavMeta::from_file(&child),
+                        name: child.name,
+                    });
+                    Ok(entry)
+                })
+                .collect::<Vec<_>>();
+            let entries: FsStream<Box<dyn DavDirEntry>> = Box::pin(stream::iter(entries));
+            Ok(entries)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let file = self.resolve(path).await?;
+            let meta: Box<dyn DavMetaData> = Box::new(DavMeta::from_file(&file));
+            Ok(meta)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (parent, name) = self.resolve_parent(path).await?;
+            if self.find_child(&parent, &name).await?.is_some() {
+                return Err(FsError::Exists);
+            }
+            let folder = File::new_folder(&name, &parent._id, &self.user.uuid, None);
+            self.collection()
+                .insert_one(&folder)
+                .await
+                .map_err(|_| FsError::GeneralFailure)?;
+            self.attach(folder._id, &parent).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let folder = self.resolve(path).await?;
+            if folder.type_ != FileType::Folder || folder._id == self.user.root_id {
+                return Err(FsError::Forbidden);
+            }
+            delete_file_l(&folder._id.to_hex(), &self.user, &self.mongo).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let file = self.resolve(path).await?;
+            if file.type_ != FileType::File {
+                return Err(FsError::Forbidden);
+            }
+            delete_file_l(&file._id.to_hex(), &self.user, &self.mongo).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let file = self.resolve(from).await?;
+            if file._id == self.user.root_id {
+                return Err(FsError::Forbidden);
+            }
+            let (parent, name) = self.resolve_parent(to).await?;
+            self.clear_target(&file, &parent, &name).await?;
+            //不能把文件夹移到自己下面
+            let mut ancestor = parent.clone();
+            loop {
+                if ancestor._id == file._id {
+                    return Err(FsError::Forbidden);
+                }
+                if ancestor._id == self.user.root_id || ancestor.father == ancestor._id {
+                    break;
+                }
+                ancestor = match self.collection().find_one(doc! { "_id": ancestor.father }).await {
+                    Ok(Some(father)) => father,
+                    _ => break,
+                };
+            }
+            self.detach(&file).await?;
+            self.collection()
+                .update_one(
+                    doc! { "_id": file._id },
+                    doc! { "$set": { "name": &name, "father": parent._id, "updated_at": Utc::now().timestamp() } },
+                )
+                .await
+                .map_err(|_| FsError::GeneralFailure)?;
+            self.attach(file._id, &parent).await
+        }
+        .boxed()
+    }
+
+    //文件夹的copy由dav_server自己递归create_dir，这里只处理文件
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let file = self.resolve(from).await?;
+            if file.type_ != FileType::File {
+                return Err(FsError::Forbidden);
+            }
+            let (parent, name) = self.resolve_parent(to).await?;
+            check_quota(&self.mongo, &self.user.uuid, file.size, self.default_quota).await?;
+            self.clear_target(&file, &parent, &name).await?;
+            //同一个blob多一次引用就行，不用真的复制数据
+            if acquire_existing(&self.mongo, &file.sha256).await?.is_none() {
+                return Err(FsError::NotFound);
+            }
+            let copied = File {
+                _id: ObjectId::new(),
+                name,
+                father: parent._id,
+                children: vec![],
+                owner: self.user.uuid,
+                created_at: Utc::now().timestamp(),
+                updated_at: Utc::now().timestamp(),
+                ..file
+            };
+            commit_uploaded_file(&self.mongo, &self.storage_factory, &copied, self.default_quota).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+pub struct DavReadFile {
+    meta: DavMeta,
+    blob: File,
+    storage_factory: Arc<Mutex<StorageFactory>>,
+    position: u64,
+    //FileStream不是Sync的，套一层Mutex，用的时候get_mut不用真的加锁
+    stream: Option<std::sync::Mutex<FileStream>>,
+}
+
+impl std::fmt::Debug for DavReadFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DavReadFile")
+            .field("id", &self.blob._id)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl DavFile for DavReadFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta: Box<dyn DavMetaData> = Box::new(self.meta.clone());
+        Box::pin(async move { Ok(meta) })
+    }
+
+    fn write_buf(&mut self, _buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::Forbidden) })
+    }
+
+    fn write_bytes(&mut self, _buf: Bytes) -> FsFuture<'_, ()> {
+        Box::pin(async { Err(FsError::Forbidden) })
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        async move {
+            if self.stream.is_none() {
+                let remaining = self.blob.size.saturating_sub(self.position);
+                let factory = self.storage_factory.lock().await.clone();
+                let stream = factory.get_file_range(&self.blob, self.position, remaining).await?;
+                self.stream = Some(std::sync::Mutex::new(stream));
+            }
+            let stream = self.stream.as_mut().unwrap().get_mut().unwrap();
+            let mut buffer = vec![0; count];
+            let mut filled = 0;
+            while filled < count {
+                let n = stream.read(&mut buffer[filled..]).await.map_err(|_| FsError::GeneralFailure)?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            buffer.truncate(filled);
+            self.position += filled as u64;
+            Ok(Bytes::from(buffer))
+        }
+        .boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        async move {
+            let position = match pos {
+                SeekFrom::Start(offset) => offset as i64,
+                SeekFrom::Current(offset) => self.position as i64 + offset,
+                SeekFrom::End(offset) => self.blob.size as i64 + offset,
+            };
+            if position < 0 {
+                return Err(FsError::GeneralFailure);
+            }
+            if position as u64 != self.position {
+                self.position = position as u64;
+                self.stream = None;
+            }
+            Ok(self.position)
+        }
+        .boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+//PUT的时候先写到cache里，flush的时候再算好sha256存进storage
+pub struct DavWriteFile {
+    fs: MongoDavFs,
+    parent: File,
+    name: String,
+    existing: Option<File>,
+    temp: AsyncFile,
+    temp_path: PathBuf,
+    hasher: Sha256,
+    size: u64,
+    expected_size: Option<u64>,
+    committed: bool,
+}
+
+impl std::fmt::Debug for DavWriteFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DavWriteFile")
+            .field("name", &self.name)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl Drop for DavWriteFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl DavWriteFile {
+    async fn commit(&mut self) -> Result<(), ApiError> {
+        let sha256 = format!("{:x}", self.hasher.clone().finalize());
+        let extra_metadata = get_local_file_type(&self.temp_path).await.map(|t| FileExtraMetadata {
+            detected_mime_type: Some(t.mime_type().to_string()),
+            ..Default::default()
+        });
+        check_quota(&self.fs.mongo, &self.fs.user.uuid, self.size, self.fs.default_quota).await?;
+        let now = Utc::now().timestamp();
+        match self.existing.clone() {
+            None => {
+                let id = ObjectId::new();
+                let metadata = File {
+                    _id: id,
+                    name: self.name.clone(),
+                    type_: FileType::File,
+                    father: self.parent._id,
+                    children: vec![],
+                    owner: self.fs.user.uuid,
+                    created_at: now,
+                    updated_at: now,
+                    size: self.size,
+                    sha256,
+                    path: id.to_hex(),
+                    storage_type: self.fs.default_storage_type.clone(),
+                    extra_metadata,
+                };
+                let factory = self.fs.storage_factory.lock().await.clone();
+                let metadata = store_local_file(&self.fs.mongo, &factory, metadata, &self.temp_path).await?;
+                let metadata = stamp_device(metadata, &self.fs.user, &self.fs.mongo).await;
+                commit_uploaded_file(&self.fs.mongo, &self.fs.storage_factory, &metadata, self.fs.default_quota).await?;
+                self.existing = Some(metadata);
+            }
+            Some(existing) => {
+                //新内容存到原来那个backend
+                let target = File {
+                    sha256,
+                    storage_type: resolve_blob(existing.clone(), &self.fs.mongo).await?.storage_type,
+                    extra_metadata,
+                    ..existing.clone()
+                };
+                let factory = self.fs.storage_factory.lock().await.clone();
+                let metadata = store_local_file(&self.fs.mongo, &factory, target, &self.temp_path).await?;
+                let metadata = stamp_device(metadata, &self.fs.user, &self.fs.mongo).await;
+                replace_content(&self.fs.mongo, &factory, &existing, &metadata, self.fs.default_quota).await?;
+                prune_versions(&existing._id, &self.fs.version_policy, &self.fs.mongo, &factory).await?;
+                self.existing = Some(metadata);
+            }
+        }
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl DavFile for DavWriteFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let now = Utc::now().timestamp();
+        let meta: Box<dyn DavMetaData> = Box::new(DavMeta {
+            size: self.size,
+            updated_at: now,
+            created_at: self.existing.as_ref().map(|f| f.created_at).unwrap_or(now),
+            is_dir: false,
+            sha256: format!("{:x}", self.hasher.clone().finalize()),
+        });
+        Box::pin(async move { Ok(meta) })
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        async move {
+            while buf.has_remaining() {
+                let chunk = Bytes::copy_from_slice(buf.chunk());
+                buf.advance(chunk.len());
+                self.write_bytes(chunk).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        async move {
+            if self.committed {
+                return Err(FsError::GeneralFailure);
+            }
+            self.hasher.update(&buf);
+            self.size += buf.len() as u64;
+            self.temp.write_all(&buf).await.map_err(|_| FsError::GeneralFailure)
+        }
+        .boxed()
+    }
+
+    fn read_bytes(&mut self, _count: usize) -> FsFuture<'_, Bytes> {
+        Box::pin(async { Err(FsError::Forbidden) })
+    }
+
+    //只支持顺序写，带Content-Range的PUT不支持
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        let size = self.size;
+        Box::pin(async move {
+            match pos {
+                SeekFrom::Start(offset) if offset == size => Ok(size),
+                SeekFrom::Current(0) | SeekFrom::End(0) => Ok(size),
+                _ => Err(FsError::NotImplemented),
+            }
+        })
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move {
+            if self.committed {
+                return Ok(());
+            }
+            //dav_server在检查Content-Length之前就flush了，长度不对的不能存
+            if self.expected_size.is_some_and(|size| size != self.size) {
+                return Err(FsError::GeneralFailure);
+            }
+            self.temp.flush().await.map_err(|_| FsError::GeneralFailure)?;
+            self.commit().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
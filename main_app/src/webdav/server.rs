@@ -0,0 +1,198 @@
+//rocket没法路由PROPFIND这些方法，webdav单独开一个端口用hyper跑
+use super::fs::MongoDavFs;
+use crate::auth::guard::AuthenticatedUser;
+use crate::auth::lib::{authenticate, authenticate_token, scope_for_method};
+use crate::auth::totp::totp_enabled;
+use crate::db::connect::{MongoDb, Redis};
+use crate::file::storage_backend::lib::StorageFactory;
+use crate::file::version::lib::{default_policy, user_policy};
+use crate::libs::ApiError;
+use crate::rate_limit::lib as rate_limit;
+use crate::MyConfig;
+use base64::Engine;
+use dav_server::body::Body;
+use dav_server::memls::MemLs;
+use dav_server::{DavConfig, DavHandler};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use mongodb::bson::oid::ObjectId;
+use rocket::tokio;
+use rocket::tokio::net::TcpListener;
+use rocket::tokio::sync::Mutex;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+//一天没来过的用户锁就不留了，客户端刷新锁的间隔远比这个短
+const LOCK_SYSTEM_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct WebDavState {
+    pub mongo: MongoDb,
+    pub redis: Redis,
+    pub storage_factory: Arc<Mutex<StorageFactory>>,
+    pub config: MyConfig,
+    pub handler: DavHandler,
+    //每个用户的路径都是从自己的home算的，锁也要分开
+    //记一下最后用的时间，太久没用的清掉，不然用户越多越大
+    pub lock_systems: std::sync::Mutex<HashMap<ObjectId, (Box<MemLs>, Instant)>>,
+}
+
+//Basic里的用户名，Bearer的没有
+fn basic_username(req: &Request<Incoming>) -> Option<String> {
+    let auth_header = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = auth_header.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    decoded.split_once(':').map(|(name, _)| name.to_string())
+}
+
+impl WebDavState {
+    pub fn new(
+        mongo: MongoDb,
+        redis: Redis,
+        storage_factory: Arc<Mutex<StorageFactory>>,
+        config: MyConfig,
+    ) -> Self {
+        Self {
+            mongo,
+            redis,
+            storage_factory,
+            config,
+            handler: DavHandler::new(),
+            lock_systems: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock_system(&self, user: &ObjectId) -> Box<MemLs> {
+        let mut lock_systems = self.lock_systems.lock().unwrap();
+        let now = Instant::now();
+        lock_systems.retain(|_, (_, last_used)| now.duration_since(*last_used) < LOCK_SYSTEM_IDLE);
+        let (lock_system, last_used) = lock_systems.entry(*user).or_insert_with(|| (MemLs::new(), now));
+        *last_used = now;
+        lock_system.clone()
+    }
+
+    //Bearer跟AuthenticatedUser一样，Basic的密码可以是账号密码也可以是access key
+    //开了两步验证的账号只能用access key
+    async fn authenticate_basic(&self, req: &Request<Incoming>) -> Option<AuthenticatedUser> {
+        let auth_header = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+        if let Some(token) = auth_header.strip_prefix("Bearer ") {
+            return authenticate_token(token, &self.config.jwt_secret, &self.mongo, &self.redis)
+                .await
+                .ok();
+        }
+        let encoded = auth_header.strip_prefix("Basic ")?;
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        if let Ok(user) = authenticate(username, password, &self.mongo, &self.config).await {
+            if !totp_enabled(&user.uuid, &self.mongo).await {
+                return Some(user);
+            }
+        }
+        match authenticate_token(password, &self.config.jwt_secret, &self.mongo, &self.redis).await {
+            Ok(user) if user.username == username => Some(user),
+            _ => None,
+        }
+    }
+
+    //Basic带的用户名和登录接口共用一套防爆破计数
+    async fn authenticate(&self, req: &Request<Incoming>, remote: IpAddr) -> Result<AuthenticatedUser, ApiError> {
+        let username = basic_username(req);
+        let counters = username.as_deref().map(|name| rate_limit::counters("login", Some(remote), name, &self.config));
+        if let Some(counters) = &counters {
+            rate_limit::check_locked(&self.redis, counters).await?;
+        }
+        let user = match self.authenticate_basic(req).await {
+            Some(user) => user,
+            None => {
+                if let Some(counters) = &counters {
+                    rate_limit::record_failure(&self.redis, &self.config, counters).await;
+                }
+                return Err(ApiError::Unauthorized(None));
+            }
+        };
+        if let Some(counters) = &counters {
+            rate_limit::record_success(&self.redis, counters).await;
+        }
+        let rate_key = user.token.clone().unwrap_or_else(|| user.uuid.to_hex());
+        if let Err(retry_after) = rate_limit::check_api_rate(&self.redis, &self.config, &rate_key).await {
+            return Err(ApiError::TooManyRequests(retry_after));
+        }
+        Ok(user)
+    }
+
+    pub async fn handle(&self, req: Request<Incoming>, remote: IpAddr) -> Response<Body> {
+        let user = match self.authenticate(&req, remote).await {
+            Ok(user) => user,
+            Err(ApiError::TooManyRequests(retry_after)) => {
+                return Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::RETRY_AFTER, retry_after)
+                    .body(Body::from("Too Many Requests"))
+                    .unwrap();
+            }
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, "Basic realm=\"rustcloud\"")
+                    .body(Body::from("Unauthorized"))
+                    .unwrap();
+            }
+        };
+        //有限制的access key按方法查权限，文件夹限制靠root_id
+        if !user.has_scope(scope_for_method(req.method().as_str())) {
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Forbidden"))
+                .unwrap();
+        }
+        let fs = MongoDavFs {
+            user: user.clone(),
+            mongo: self.mongo.clone(),
+            storage_factory: self.storage_factory.clone(),
+            cache_storage_path: self.config.cache_storage_path.clone(),
+            version_policy: user_policy(&user.uuid, &self.mongo, &default_policy(&self.config)).await,
+            default_quota: self.config.default_quota,
+            default_storage_type: self.config.default_storage_type.clone(),
+        };
+        let config = DavConfig::new()
+            .filesystem(Box::new(fs))
+            .locksystem(self.lock_system(&user.uuid))
+            .principal(user.username);
+        self.handler.handle_with(config, req).await
+    }
+}
+
+pub async fn serve(state: Arc<WebDavState>) {
+    let addr = SocketAddr::new(state.config.address, state.config.webdav_port);
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("WebDAV监听{}失败: {}", addr, e);
+            return;
+        }
+    };
+    info!("WebDAV listening on {}", addr);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok((stream, remote)) => (stream, remote.ip()),
+            Err(_) => continue,
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(state.handle(req, remote).await) }
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
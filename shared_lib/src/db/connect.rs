@@ -6,6 +6,7 @@ use redis::AsyncCommands;
 
 use super::models::{File,LoginedDevice};
 
+#[derive(Clone)]
 pub struct MongoDb {
     pub _client: Client,
     pub database: Database,
@@ -32,6 +33,7 @@ impl MongoDb {
     }
 }
 
+#[derive(Clone)]
 pub struct Redis {
     pub client: redis::Client,
     pub connection_manager: redis::aio::ConnectionManager,
@@ -52,15 +54,17 @@ impl Redis {
                 .unwrap(),
         }
     }
-    pub async fn recover_from_db(&self, mongodb: &MongoDb) {
-        //恢复登录
+    pub async fn recover_from_db(&self, mongodb: &MongoDb) -> Result<(), mongodb::error::Error> {
+        //恢复登录，以前没加前缀的key顺便删掉
         let collection = mongodb.database.collection::<LoginedDevice>("logined_devices");
-        let mut cursor = collection.find(doc! {}).await.unwrap();
-        while cursor.advance().await.unwrap() {
-            let device = cursor.deserialize_current().unwrap();
-            self.set(&device.uuid, &device.user_uuid.to_hex()).await;
-            let _ = self.expire_at(&device.uuid, device.expire_at).await;
-            }
+        let mut cursor = collection.find(doc! {}).await?;
+        while cursor.advance().await? {
+            let device = cursor.deserialize_current()?;
+            self.delete(&device.uuid).await;
+            self.set(device.redis_key(), &device.user_uuid.to_hex()).await;
+            let _ = self.expire_at(device.redis_key(), device.expire_at).await;
+        }
+        Ok(())
     }
     pub async fn get_connection(&self) -> redis::aio::ConnectionManager {
         self.connection_manager.clone()
@@ -141,6 +145,34 @@ impl Redis {
         let _: () = con.expire(key, seconds).await.unwrap();
         Ok(())
     }
+    //SET NX EX，key不存在时才写并带上过期时间，写成功返回true
+    pub async fn set_nx_ex<'a, K, V>(&self, key: K, value: V, seconds: u64) -> bool
+    where
+        K: redis::ToRedisArgs + Send + Sync + 'a,
+        V: redis::ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut con = self.get_connection().await;
+        let options = redis::SetOptions::default()
+            .conditional_set(redis::ExistenceCheck::NX)
+            .with_expiration(redis::SetExpiry::EX(seconds));
+        let result: Option<String> = con.set_options(key, value, options).await.unwrap();
+        result.is_some()
+    }
+    pub async fn incr<'a, K>(&self, key: K) -> i64
+    where
+        K: redis::ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut con = self.get_connection().await;
+        con.incr(key, 1).await.unwrap()
+    }
+    //秒，不存在是-2，没设过期是-1
+    pub async fn ttl<'a, K>(&self, key: K) -> i64
+    where
+        K: redis::ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut con = self.get_connection().await;
+        con.ttl(key).await.unwrap()
+    }
     pub async fn decr<'a, K>(&self, key: K)
     where
         K: redis::ToRedisArgs + Send + Sync + 'a,
@@ -10,16 +10,65 @@ pub struct User {
     pub nickname: String,
     pub password: String,
     pub root_id: ObjectId,
+    #[serde(default)]
+    pub role: UserRole,
+    #[serde(default)]
+    pub disabled: bool, //被管理员停用的账号登不上，已有的登录也会被踢掉
+    #[serde(default)]
+    pub provider: Option<String>, //外部登录建的用户是"ldap"、"oidc"，None是本地密码
+    #[serde(default)]
+    pub oidc_subject: Option<String>, //OIDC登录对应的claim值，用户名不一定和它一样
+    #[serde(default)]
+    pub totp: Option<TotpSettings>, //没开两步验证就是None
+    #[serde(default)]
+    pub version_policy: Option<VersionPolicy>, //不设就用配置里的默认值
+    #[serde(default)]
+    pub quota: Option<u64>, //字节，不设就用配置里的默认值
+    #[serde(default)]
+    pub used_bytes: i64, //文件、回收站、历史版本都算，秒传的也按文件大小算
 }
 
-use mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+//enabled为false表示还在绑定，确认过验证码之后才生效
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TotpSettings {
+    pub secret: String, //base32
+    pub enabled: bool,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>, //argon2 hash，用一个删一个
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub enum UserRole {
+    Admin,
+    #[default]
+    Normal,
+}
 
+//历史版本最多留几个、留多久，0表示不限
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VersionPolicy {
+    pub max_count: u64,
+    pub max_age_days: u64,
+}
+
+use mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum LoginedDeviceType {
     Normal,
     ApiKey,
 }
 
+impl LoginedDeviceType {
+    //jwt登录的会话和access key在redis里分开放，jti不能直接拿来当access key用
+    pub fn redis_key(&self, uuid: &str) -> String {
+        match self {
+            LoginedDeviceType::Normal => format!("session:{}", uuid),
+            LoginedDeviceType::ApiKey => format!("access_key:{}", uuid),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoginedDevice {
     pub _id: ObjectId,
@@ -31,6 +80,55 @@ pub struct LoginedDevice {
     pub uuid: String, //jti or apikey
     pub user_uuid: ObjectId,
     pub type_: LoginedDeviceType,
+    #[serde(default)]
+    pub refresh_token: Option<String>, //当前有效的refresh token的sha256，access key没有
+    #[serde(default)]
+    pub previous_refresh_token: Option<String>, //上一个refresh token的sha256，再拿它来换就是被偷用了
+    #[serde(default)]
+    pub scopes: Option<Vec<AccessScope>>, //access key能做的事，None是不限
+    #[serde(default)]
+    pub folder: Option<ObjectId>, //access key只能动这个文件夹下面的东西
+}
+
+impl LoginedDevice {
+    pub fn redis_key(&self) -> String {
+        self.type_.redis_key(&self.uuid)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessScope {
+    Read,
+    Write,
+    Delete,
+    Share,
+}
+
+//分享链接，过期了靠expire_at上的TTL索引删，redis里只是缓存
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShareLink {
+    pub _id: ObjectId,
+    pub uuid: String, //链接里的那一段
+    pub owner: ObjectId,
+    pub target: ObjectId,
+    pub created_at: i64,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub expire_at: chrono::DateTime<chrono::Utc>,
+    pub download_limit: i64, //建的时候给的次数，-1是不限
+    pub remaining_downloads: i64,
+    pub password: Option<String>, //argon2 hash，和用户密码一样
+    #[serde(default)]
+    pub file_request: Option<FileRequest>, //有的话是只能往里传文件的链接，不能下载
+}
+
+//文件请求的限制和已经收到的量，限制是0表示不限
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FileRequest {
+    pub max_files: u64,
+    pub max_bytes: u64,
+    pub received_files: u64,
+    pub received_bytes: u64,
 }
 
 
@@ -53,21 +151,16 @@ pub struct ThumbnailDetail {
     pub file: ObjectId,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct FileExtraMetadata {
     pub detected_mime_type: Option<String>,
     pub thumbnail: Option<ObjectId>,
-    pub file_references: Vec<ObjectId>, 
-}
-
-impl Default for FileExtraMetadata {
-    fn default() -> Self {
-        Self {
-            detected_mime_type: None,
-            thumbnail: None,
-            file_references: vec![],
-        }
-    }
+    #[serde(default)]
+    pub device: Option<ObjectId>, //上传这一版的登录设备
+    #[serde(default)]
+    pub share_link: Option<String>, //通过文件请求链接传上来的，记链接的uuid
+    #[serde(default)]
+    pub stored_size: Option<u64>, //压缩、加密之后实际占的空间，size是下载下来的大小
 }
 
 
@@ -90,7 +183,7 @@ pub struct File {
     pub updated_at: i64,
     pub size: u64,//floder时可以随便填
     pub sha256: String,//floder时可以随便填
-    pub path: String,//floder时可以随便填, storage_type为ref为原文件ObjectId的Hex,为flat时为存储的文件id
+    pub path: String,//floder时可以随便填, storage_type为blob时为blob的sha256
     pub storage_type: String,
     pub extra_metadata: Option<FileExtraMetadata>,
 }
@@ -113,4 +206,46 @@ impl File {
             extra_metadata: None,
         }
     }
-}
\ No newline at end of file
+}
+
+//按sha256存的实际文件内容，File只记sha256，多个File共用一个blob
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Blob {
+    pub _id: String, //sha256
+    pub storage_type: String,
+    pub path: String, //在storage backend里的位置
+    pub size: u64,
+    #[serde(default)]
+    pub stored_size: u64, //压缩、加密后实际占的空间
+    pub ref_count: i64,
+    pub created_at: i64,
+}
+
+//回收站里的一项，只记用户删的那一层，文件夹下面的文件跟着一起挪到trashed_files里
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashItem {
+    pub _id: ObjectId, //就是被删的File的_id
+    pub owner: ObjectId,
+    pub father: ObjectId, //删之前所在的文件夹，恢复的时候用
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: FileType,
+    pub size: u64, //文件夹是下面所有文件加起来
+    pub deleted_at: i64,
+}
+
+//文件被更新之前的一版，blob的引用由这条记录持有
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileVersion {
+    pub _id: ObjectId,
+    pub file: ObjectId,
+    pub owner: ObjectId,
+    pub size: u64,
+    pub sha256: String,
+    pub storage_type: String,
+    pub path: String,
+    pub device: Option<ObjectId>,
+    pub extra_metadata: Option<FileExtraMetadata>,
+    pub created_at: i64, //这一版成为当前版本的时间
+    pub replaced_at: i64,
+}